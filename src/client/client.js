@@ -2,6 +2,7 @@ $(document).ready(function() {
 
     var ws;
     var macAddrMap;
+    var infraMap = {};
 
     function mkForce(nodes, links, width, height) {
         return d3.layout.force()
@@ -91,9 +92,357 @@ $(document).ready(function() {
     var types = {
         'ip4': mkConns('ip4', true),
         'ip6': mkConns('ip6', false),
-        'mac': mkConns('mac', false)
+        'mac': mkConns('mac', false),
+        'ble': mkConns('ble', false)
     };
 
+    function mkTable(tabId, title, columns) {
+        var table = mkTab(tabId, title, false)
+            .append("table").attr("class", "table table-condensed");
+        table.append("thead").append("tr").selectAll("th")
+            .data(columns)
+            .enter().append("th")
+            .text(function(c) { return c.title; });
+        table.append("tbody");
+        return { table: table, columns: columns, rows: {} };
+    }
+
+    function updateRow(t, key, row) {
+        t.rows[key] = row;
+        var data = d3.values(t.rows);
+        if(t.sort) {
+            data.sort(t.sort);
+        }
+        var rows = t.table.select("tbody").selectAll("tr").data(data);
+        rows.enter().append("tr");
+        rows.exit().remove();
+        var cells = rows.selectAll("td")
+            .data(function(r) {
+                return t.columns.map(function(c) { return c.value(r); });
+            });
+        cells.enter().append("td");
+        cells.text(function(v) { return v; });
+    }
+
+    function macName(addr) {
+        var alias = macAddrMap[addr];
+        return alias ? alias : addr;
+    }
+
+    function orDash(v) {
+        return (v === null || v === undefined) ? "-" : v;
+    }
+
+    var authTable = mkTable("tab_auth", "auth", [
+        {title: "station", value: function(a) { return macName(a.station); }},
+        {title: "authenticator", value: function(a) {
+            return a.authenticator ? macName(a.authenticator) : "?";
+        }},
+        {title: "status", value: function(a) { return a.status; }},
+        {title: "method", value: function(a) { return orDash(a.eap_method); }},
+        {title: "identity", value: function(a) { return orDash(a.identity); }},
+        {title: "eap", value: function(a) {
+            return a.eap_success === null ? "-" : (a.eap_success ? "success" : "failure");
+        }},
+        {title: "4-way", value: function(a) {
+            return a.handshake.map(function(seen, i) { return seen ? i+1 : "-"; }).join("");
+        }},
+        {title: "attempts", value: function(a) { return a.attempts; }},
+        {title: "completed", value: function(a) { return a.completed; }},
+        {title: "failures", value: function(a) { return a.failures; }},
+        {title: "timeouts", value: function(a) { return a.timeouts; }}
+    ]);
+    authTable.sort = function(a, b) { return b.last_update - a.last_update; };
+
+    function rateLabel(r) {
+        var label = r.mbps.toFixed(1) + " Mbps";
+        if(r.mcs !== null) {
+            label += " (" + r.phy + " mcs" + r.mcs + " " + r.nss + "ss";
+            if(r.bandwidth !== null) {
+                label += " " + r.bandwidth + "MHz";
+            }
+            label += ")";
+        }
+        return label;
+    }
+
+    var wifiTable = mkTable("tab_wifi", "wifi", [
+        {title: "station", value: function(w) { return macName(w.station); }},
+        {title: "channel", value: function(w) { return orDash(w.channel); }},
+        {title: "signal", value: function(w) { return orDash(w.signal); }},
+        {title: "frames", value: function(w) { return w.rates.total; }},
+        {title: "rates", value: function(w) {
+            var rates = w.rates.rates.slice();
+            rates.sort(function(a, b) { return b.count - a.count; });
+            return rates.slice(0, 3).map(function(rc) {
+                return rateLabel(rc.rate) + " " +
+                    Math.round(100 * rc.count / w.rates.total) + "%";
+            }).join(", ");
+        }}
+    ]);
+    wifiTable.sort = function(a, b) { return b.rates.total - a.rates.total; };
+
+    var clientTable = mkTable("tab_clients", "clients", [
+        {title: "station", value: function(c) {
+            return macName(c.mac) + (c.randomized ? " (random)" : "");
+        }},
+        {title: "ssids", value: function(c) { return c.ssids.join(", "); }},
+        {title: "wildcard", value: function(c) { return c.wildcard_probes; }},
+        {title: "probes", value: function(c) { return c.probes; }},
+        {title: "rates", value: function(c) { return c.rates.join(","); }},
+        {title: "first seen", value: function(c) {
+            return new Date(c.first_seen * 1000).toLocaleTimeString();
+        }},
+        {title: "last seen", value: function(c) {
+            return new Date(c.last_seen * 1000).toLocaleTimeString();
+        }},
+        {title: "signal", value: function(c) {
+            return c.signals.map(function(s) { return s.dbm; }).join(" ");
+        }}
+    ]);
+    clientTable.sort = function(a, b) { return b.last_seen - a.last_seen; };
+
+    //The same probing clients, with random addresses gathered up into the
+    //devices that probably used them.
+    var deviceTable = mkTable("tab_devices", "devices", [
+        {title: "device", value: function(d) { return d.id; }},
+        {title: "addresses", value: function(d) { return d.macs.map(macName).join(", "); }},
+        {title: "linked by", value: function(d) { return orDash(d.linked_by); }},
+        {title: "ssids", value: function(d) { return d.ssids.join(", "); }},
+        {title: "probes", value: function(d) { return d.probes; }},
+        {title: "last seen", value: function(d) {
+            return new Date(d.last_seen * 1000).toLocaleTimeString();
+        }}
+    ]);
+    deviceTable.sort = function(a, b) { return b.last_seen - a.last_seen; };
+
+    var bleTable = mkTable("tab_ble_devices", "ble devices", [
+        {title: "address", value: function(d) { return macName(d.addr) + " (" + d.addr_type + ")"; }},
+        {title: "name", value: function(d) { return orDash(d.name); }},
+        {title: "manufacturer", value: function(d) { return orDash(d.manufacturer); }},
+        {title: "services", value: function(d) { return d.services.join(", "); }},
+        {title: "adverts", value: function(d) { return d.adverts; }},
+        {title: "connects", value: function(d) { return d.connects; }},
+        {title: "peers", value: function(d) { return d.peers.map(macName).join(", "); }},
+        {title: "rssi", value: function(d) { return orDash(d.rssi); }},
+        {title: "last seen", value: function(d) {
+            return new Date(d.last_seen * 1000).toLocaleTimeString();
+        }}
+    ]);
+    bleTable.sort = function(a, b) { return b.last_seen - a.last_seen; };
+
+    var assocTable = mkTable("tab_assoc", "assoc", [
+        {title: "station", value: function(a) { return macName(a.station); }},
+        {title: "bssid", value: function(a) { return macName(a.bssid); }},
+        {title: "status", value: function(a) { return a.status; }},
+        {title: "auths", value: function(a) { return a.auths; }},
+        {title: "assoc reqs", value: function(a) { return a.assoc_reqs; }},
+        {title: "assocs", value: function(a) { return a.assocs; }},
+        {title: "rejections", value: function(a) { return a.rejections; }},
+        {title: "deauths", value: function(a) { return a.deauths; }},
+        {title: "disassocs", value: function(a) { return a.disassocs; }},
+        {title: "last code", value: function(a) {
+            return a.last_code === null ? "-" : a.last_code + " (" + a.last_code_name + ")";
+        }}
+    ]);
+    assocTable.sort = function(a, b) { return b.last_update - a.last_update; };
+
+    function pct(f) {
+        return (100 * f).toFixed(1) + "%";
+    }
+
+    var airtimeTable = mkTable("tab_airtime", "airtime", [
+        {title: "channel", value: function(a) { return a.channel.mhz + " MHz"; }},
+        {title: "utilization", value: function(a) { return pct(a.channel.utilization); }},
+        {title: "reserved", value: function(a) { return pct(a.channel.reserved); }},
+        {title: "frames", value: function(a) { return a.channel.frames; }},
+        {title: "top stations", value: function(a) {
+            return a.stations.slice(0, 5).map(function(st) {
+                return macName(st.mac) + " " + pct(st.airtime_us / a.channel.airtime_us);
+            }).join(", ");
+        }}
+    ]);
+    airtimeTable.sort = function(a, b) { return a.channel.mhz - b.channel.mhz; };
+
+    var distanceTable = mkTable("tab_distance", "distance", [
+        {title: "station", value: function(d) {
+            return macName(d.mac) + (d.calibrated ? " (calibrated)" : "");
+        }},
+        {title: "distance", value: function(d) { return d.estimate.meters.toFixed(1) + "m"; }},
+        {title: "range", value: function(d) {
+            return d.estimate.low.toFixed(1) + "-" + d.estimate.high.toFixed(1) + "m";
+        }},
+        {title: "signal", value: function(d) { return d.estimate.signal.toFixed(1) + "dBm"; }},
+        {title: "samples", value: function(d) { return d.estimate.samples; }},
+        {title: "model", value: function(d) { return d.model + ", " + d.smoothing; }}
+    ]);
+    distanceTable.sort = function(a, b) { return a.estimate.meters - b.estimate.meters; };
+
+    //Trilaterated stations, drawn to scale with the sensors that placed them.
+    var floorPlan = (function() {
+        var chart = mkConnsTab("tab_position", "position", false);
+        return {
+            chart: chart,
+            width: +chart.attr("width"),
+            height: +chart.attr("height"),
+            sensors: [],
+            positions: {}
+        };
+    })();
+
+    function drawFloorPlan(fp) {
+        var stations = d3.entries(fp.positions);
+        var xs = fp.sensors.map(function(s) { return s.x; })
+            .concat(stations.map(function(e) { return e.value.x; }));
+        var ys = fp.sensors.map(function(s) { return s.y; })
+            .concat(stations.map(function(e) { return e.value.y; }));
+        var margin = 40;
+        var span = Math.max(d3.max(xs) - d3.min(xs), d3.max(ys) - d3.min(ys), 1);
+        var scale = Math.min(fp.width, fp.height) - 2 * margin;
+        var x = function(v) { return margin + scale * (v - d3.min(xs)) / span; };
+        var y = function(v) { return margin + scale * (v - d3.min(ys)) / span; };
+
+        var sensors = fp.chart.selectAll(".sensor").data(fp.sensors);
+        var newSensors = sensors.enter().append("g").attr("class", "sensor");
+        newSensors.append("rect").attr("x", -5).attr("y", -5).attr("width", 10).attr("height", 10);
+        newSensors.append("text").attr("dx", 8).attr("dy", ".35em");
+        sensors.attr("transform", function(s) { return "translate(" + x(s.x) + "," + y(s.y) + ")"; });
+        sensors.select("text").text(function(s) { return s.name; });
+
+        var pos = fp.chart.selectAll(".position").data(stations, function(e) { return e.key; });
+        var newPos = pos.enter().append("g").attr("class", "position");
+        newPos.append("circle").attr("class", "error");
+        newPos.append("circle").attr("class", "station").attr("r", 4);
+        newPos.append("text").attr("dx", 8).attr("dy", ".35em");
+        pos.attr("transform", function(e) {
+            return "translate(" + x(e.value.x) + "," + y(e.value.y) + ")";
+        });
+        pos.select(".error").attr("r", function(e) { return scale * e.value.error / span; });
+        pos.select("text").text(function(e) { return macName(e.key); });
+    }
+
+    var linkTable = mkTable("tab_link", "link", [
+        {title: "addr", value: function(l) { return macName(l.mac); }},
+        {title: "kind", value: function(l) { return l.kind; }},
+        {title: "frames", value: function(l) { return l.stats.frames; }},
+        {title: "retries", value: function(l) { return pct(l.retry_rate); }},
+        {title: "fcs failures", value: function(l) { return pct(l.fcs_rate); }},
+        {title: "bad version", value: function(l) { return pct(l.bad_version_rate); }},
+        {title: "captured", value: function(l) {
+            return pct(l.completeness) + " (" + l.stats.missed + " missed)";
+        }}
+    ]);
+    linkTable.sort = function(a, b) { return b.retry_rate - a.retry_rate; };
+
+    //Recent signal per station, one character per interval, like the cli.
+    var sparkLevels = "_.-~=+*#";
+    function sparkline(buckets) {
+        var avgs = buckets.map(function(b) { return b.avg; });
+        var lo = d3.min(avgs), hi = d3.max(avgs);
+        return avgs.map(function(a) {
+            var i = hi - lo < 1 ? sparkLevels.length >> 1
+                : Math.round((a - lo) / (hi - lo) * (sparkLevels.length - 1));
+            return sparkLevels[i];
+        }).join("");
+    }
+
+    var signalTable = mkTable("tab_signal", "signal", [
+        {title: "station", value: function(s) { return macName(s.mac); }},
+        {title: "sensor", value: function(s) { return s.sensor; }},
+        {title: "min", value: function(s) { return s.bucket.min + "dBm"; }},
+        {title: "avg", value: function(s) { return s.bucket.avg.toFixed(1) + "dBm"; }},
+        {title: "max", value: function(s) { return s.bucket.max + "dBm"; }},
+        {title: "noise", value: function(s) {
+            return s.bucket.noise === null ? "-" : s.bucket.noise.toFixed(1) + "dBm";
+        }},
+        {title: "history", value: function(s) { return sparkline(s.history); }}
+    ]);
+    signalTable.sort = function(a, b) { return b.bucket.avg - a.bucket.avg; };
+    var signalHistoryLen = 60;
+
+    //Wi-Fi topology: APs, the clients in each BSS, and probing-only devices.
+    //Each message is the whole graph; nodes keep their place between them.
+    var wifiTopo = (function() {
+        var chart = mkConnsTab("tab_topology", "wifi topology", false);
+        var nodes = [], links = [];
+        var force = mkForce(nodes, links, +chart.attr("width"), +chart.attr("height"));
+        force.on("tick", function() {
+            chart.selectAll(".topolink")
+                .attr("x1", function(d) { return d.source.x; })
+                .attr("y1", function(d) { return d.source.y; })
+                .attr("x2", function(d) { return d.target.x; })
+                .attr("y2", function(d) { return d.target.y; });
+            chart.selectAll(".toponode")
+                .attr("transform", function(d) { return "translate(" + d.x + "," + d.y + ")"; });
+        });
+        return { chart: chart, nodes: nodes, links: links, nodeMap: {}, force: force };
+    })();
+
+    var topoSizes = { 'ap': 10, 'client': 5, 'probing': 3 };
+
+    function topoLabel(n) {
+        var label = macName(n.addr);
+        if(n.kind === "ap") {
+            label += " \"" + orDash(n.ssid) + "\" ch " + orDash(n.channel);
+        }
+        return label;
+    }
+
+    function loadTopology(msg) {
+        var t = wifiTopo;
+        var seen = {};
+        msg.nodes.forEach(function(n) {
+            var old = t.nodeMap[n.addr];
+            if(old) {
+                n.x = old.x; n.y = old.y; n.px = old.px; n.py = old.py;
+            }
+            n.displaySize = topoSizes[n.kind];
+            seen[n.addr] = n;
+        });
+        t.nodeMap = seen;
+        t.nodes.length = 0;
+        msg.nodes.forEach(function(n) { t.nodes.push(n); });
+        t.links.length = 0;
+        msg.links.forEach(function(l) {
+            if(seen[l.source] && seen[l.target]) {
+                t.links.push({source: seen[l.source], target: seen[l.target], kind: l.kind});
+            }
+        });
+
+        var lines = t.chart.selectAll(".topolink").data(t.links);
+        lines.enter().insert("line", "g");
+        lines.exit().remove();
+        lines.attr("class", function(l) { return "topolink " + l.kind; });
+
+        var gs = t.chart.selectAll(".toponode").data(t.nodes, function(n) { return n.addr; });
+        var newGs = gs.enter().append("g").call(t.force.drag);
+        newGs.append("circle");
+        newGs.append("text").attr("dx", 12).attr("dy", ".35em");
+        gs.exit().remove();
+        gs.attr("class", function(n) { return "toponode " + n.kind; });
+        gs.select("circle").attr("r", function(n) { return n.displaySize; });
+        gs.select("text").text(topoLabel);
+
+        t.force.start();
+    }
+
+    var presenceCount = 0;
+    var presenceTable = mkTable("tab_presence", "presence", [
+        {title: "time", value: function(e) { return new Date(e.time * 1000).toLocaleString(); }},
+        {title: "device", value: function(e) { return e.name; }},
+        {title: "event", value: function(e) { return e.kind; }},
+        {title: "via", value: function(e) { return e.source; }}
+    ]);
+    presenceTable.sort = function(a, b) { return b.time - a.time; };
+
+    var alertCount = 0;
+    var alertTable = mkTable("tab_alerts", "alerts", [
+        {title: "time", value: function(a) { return new Date(a.time * 1000).toLocaleTimeString(); }},
+        {title: "kind", value: function(a) { return a.kind; }},
+        {title: "message", value: function(a) { return a.message; }}
+    ]);
+    alertTable.sort = function(a, b) { return b.time - a.time; };
+
     var pie = d3.layout.pie()
         .value(function(d) { return d.sz; })
         .sort(null);
@@ -131,31 +480,141 @@ $(document).ready(function() {
         });
 
         newNodes.append("svg:text")
-            .attr("class", function(d) {
-                var alias = macAddrMap[d.addr];
-                return "nodetext" + (alias ? " knownaddr" : "");
-            })
             .attr("dx", 12)
-            .attr("dy", ".35em")
-            .text(function(d) {
-                var alias = macAddrMap[d.addr];
-                return alias ? alias : d.addr;
-            });
+            .attr("dy", ".35em");
 
+        //labels can change after a node is created, e.g. when a switch
+        //announces itself, so refresh all of them.
+        nodes.select("text")
+            .attr("class", nodeClass)
+            .text(nodeLabel);
     };
 
-    function updateNode(c, from, to) {
+    function nodeLabel(d) {
+        var alias = macAddrMap[d.addr];
+        var infra = infraMap[d.addr];
+        var label = alias ? alias : d.addr;
+        if(!alias && infra) {
+            label = infra.name + " (" + infra.port + ")";
+        }
+        return d.tag ? label + " [" + d.tag + "]" : label;
+    }
+
+    function nodeClass(d) {
+        var cls = "nodetext";
+        if(macAddrMap[d.addr] || infraMap[d.addr]) {
+            cls += " knownaddr";
+        }
+        if(infraMap[d.addr]) {
+            cls += " infra";
+        }
+        return cls;
+    }
+
+    function loadNeighbor(msg) {
+        var n = msg.neighbor;
+        infraMap[n.mac] = {
+            name: n.system_name ? n.system_name : n.chassis_id,
+            port: n.port_id
+        };
+        update(types.mac);
+    }
+
+    function loadAuth(msg) {
+        updateRow(authTable, msg.auth.station, msg.auth);
+    }
+
+    function loadWifi(msg) {
+        updateRow(wifiTable, msg.station, msg);
+    }
+
+    function loadClient(msg) {
+        updateRow(clientTable, msg.mac, msg);
+    }
+
+    function loadDevice(msg) {
+        updateRow(deviceTable, msg.device.id, msg.device);
+    }
+
+    function loadBle(msg) {
+        updateRow(bleTable, msg.device.addr, msg.device);
+    }
+
+    function loadAssoc(msg) {
+        updateRow(assocTable, msg.assoc.station + "_" + msg.assoc.bssid, msg.assoc);
+    }
+
+    function loadAirtime(msg) {
+        updateRow(airtimeTable, msg.channel.mhz, msg);
+    }
+
+    function loadDistance(msg) {
+        updateRow(distanceTable, msg.mac, msg);
+    }
+
+    function loadPosition(msg) {
+        floorPlan.sensors = msg.sensors;
+        floorPlan.positions[msg.mac] = msg.position;
+        drawFloorPlan(floorPlan);
+    }
+
+    function loadLink(msg) {
+        updateRow(linkTable, msg.kind + "_" + msg.mac, msg);
+    }
+
+    function loadSignal(msg) {
+        var key = msg.sensor + "_" + msg.mac;
+        var prev = signalTable.rows[key];
+        msg.history = (prev ? prev.history : []).concat([msg.bucket]).slice(-signalHistoryLen);
+        updateRow(signalTable, key, msg);
+    }
+
+    function loadPresence(msg) {
+        updateRow(presenceTable, presenceCount++, msg.event);
+    }
+
+    function loadAlert(msg) {
+        console.log("alert: " + msg.alert.message);
+        updateRow(alertTable, alertCount++, msg.alert);
+    }
+
+    var handlers = {
+        'neighbor': loadNeighbor,
+        'auth': loadAuth,
+        'wifi': loadWifi,
+        'client': loadClient,
+        'assoc': loadAssoc,
+        'alert': loadAlert,
+        'airtime': loadAirtime,
+        'link': loadLink,
+        'distance': loadDistance,
+        'position': loadPosition,
+        'signal': loadSignal,
+        'topology': loadTopology,
+        'presence': loadPresence,
+        'device': loadDevice,
+        'ble_device': loadBle
+    };
+
+    //the same address inside two tunnels is two hosts
+    function nodeKey(addr, tag) {
+        return tag ? addr + "@" + tag : addr;
+    }
+
+    function updateNode(c, from, to, tag) {
         var updateLinks = false;
-        var index = c.nodeMap[from.addr];
+        var key = nodeKey(from.addr, tag);
+        var index = c.nodeMap[key];
         if(index === undefined) {
             index = c.nodes.length;
             c.nodes.push({addr: from.addr,
+                          tag: tag,
                           countFrom: from.sent.count,
                           sizeFrom: from.sent.size,
                           countTo: to.sent.count,
                           sizeTo: to.sent.size,
                           displaySize: displaySize(from.sent.size+to.sent.size)});
-            c.nodeMap[from.addr] = index;
+            c.nodeMap[key] = index;
             updateLinks = true;
         } else {
             var node = c.nodes[index];
@@ -176,16 +635,18 @@ $(document).ready(function() {
 
         var route = msg.route;
 
-        var linkKey = route.a.addr+"_"+route.b.addr;
+        var aKey = nodeKey(route.a.addr, route.tag);
+        var bKey = nodeKey(route.b.addr, route.tag);
+        var linkKey = aKey+"_"+bKey;
         var oldLinkNode = c.linkNodes[linkKey];
         if(oldLinkNode) {
-            var oldA = c.nodes[c.nodeMap[oldLinkNode.a.addr]];
+            var oldA = c.nodes[c.nodeMap[aKey]];
             oldA.countFrom -= oldLinkNode.a.sent.count;
             oldA.sizeFrom -= oldLinkNode.a.sent.size;
             oldA.countTo -= oldLinkNode.b.sent.count;
             oldA.sizeTo -= oldLinkNode.b.sent.size;
 
-            var oldB = c.nodes[c.nodeMap[oldLinkNode.b.addr]];
+            var oldB = c.nodes[c.nodeMap[bKey]];
             oldB.countFrom -= oldLinkNode.b.sent.count;
             oldB.sizeFrom -= oldLinkNode.b.sent.size;
             oldB.countTo -= oldLinkNode.a.sent.count;
@@ -194,11 +655,12 @@ $(document).ready(function() {
         c.linkNodes[linkKey] = route;
 
         //bitwise-or to avoid short-circuit
-        var updateLinks = updateNode(c, route.a, route.b) | updateNode(c, route.b, route.a);
+        var updateLinks = updateNode(c, route.a, route.b, route.tag) |
+                          updateNode(c, route.b, route.a, route.tag);
 
         if(updateLinks) {
-            c.links.push({source: c.nodeMap[route.a.addr],
-                          target: c.nodeMap[route.b.addr]});
+            c.links.push({source: c.nodeMap[aKey],
+                          target: c.nodeMap[bKey]});
         }
 
         update(c);
@@ -225,6 +687,8 @@ $(document).ready(function() {
             //console.log(msg);
             if(msg.typ === undefined) {
                 macAddrMap = msg;
+            } else if(handlers[msg.typ]) {
+                handlers[msg.typ](msg);
             } else {
                 loadUpdate(msg);
             }
@@ -0,0 +1,235 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+use std::num::Float;
+
+use multicast::Multicast;
+
+use ether::MacAddr;
+use dot11::{self, FrameType};
+use tap::{self, RadioFields};
+
+// How much of the medium each channel and station is using, estimated from
+// each frame's length and rate plus the time the Duration/ID field reserves
+// after it.  We only see what our radio hears, so this is a lower bound.
+
+/// Microseconds on the air for a frame of len bytes (FCS included), or None
+/// if the driver didn't tell us the rate.
+pub fn tx_time_us(radio: &RadioFields, len: u32) -> Option<u32> {
+    let rate = match radio.phy_rate {
+        Some(r) if r.mbps > 0.0 => r,
+        _ => return None
+    };
+    let preamble = match rate.phy {
+        "legacy" => {
+            let dsss = radio.channel.map(|c| c.flags.contains(tap::CCK)).unwrap_or(false)
+                || [1.0, 2.0, 5.5, 11.0].contains(&rate.mbps);
+            match (dsss, radio.has_flag(tap::SHORT_PREAMBLE)) {
+                (true, true) => 96,
+                (true, false) => 192,
+                (false, _) => 20
+            }
+        }
+        //legacy preamble, then the format's own signal, training and LTFs
+        "ht" => 32 + 4 * rate.nss as u32,
+        "vht" => 36 + 4 * rate.nss as u32,
+        "he" => 36 + 8 * rate.nss as u32,
+        _ => 20
+    };
+    Some(preamble + ((len * 8) as f32 / rate.mbps).ceil() as u32)
+}
+
+pub struct AirtimeSample {
+    pub mhz: Option<u16>,
+    pub ta: Option<MacAddr>,
+    pub frame_ty: FrameType,
+    pub ctrl_subtype: Option<u8>,
+    pub airtime_us: u32,
+    pub nav_us: u32,
+    /// Capture time.
+    pub time: i64,
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct ChannelAirtime {
+    pub mhz: u16,
+    pub frames: u64,
+    pub airtime_us: u64,
+    pub nav_us: u64,
+    /// Fraction of the last full second spent transmitting, and reserved
+    /// by the Duration/ID field.
+    pub utilization: f32,
+    pub reserved: f32,
+    pub mgmt: u64,
+    pub data: u64,
+    pub rts: u64,
+    pub cts: u64,
+    pub ack: u64,
+    pub block_ack: u64,
+    pub other_ctrl: u64,
+    bucket_sec: i64,
+    bucket_us: u64,
+    bucket_nav_us: u64,
+}
+
+impl ChannelAirtime {
+    fn new(mhz: u16, now: i64) -> ChannelAirtime {
+        ChannelAirtime {
+            mhz: mhz,
+            frames: 0,
+            airtime_us: 0,
+            nav_us: 0,
+            utilization: 0.0,
+            reserved: 0.0,
+            mgmt: 0,
+            data: 0,
+            rts: 0,
+            cts: 0,
+            ack: 0,
+            block_ack: 0,
+            other_ctrl: 0,
+            bucket_sec: now,
+            bucket_us: 0,
+            bucket_nav_us: 0
+        }
+    }
+
+    /// Returns true when a second's worth of samples has been rolled up.
+    /// Seconds are capture time, so a file's channels are as busy as they
+    /// were when it was recorded, not as fast as it's read.
+    fn update(&mut self, s: &AirtimeSample, now: i64) -> bool {
+        let rolled = now > self.bucket_sec;
+        if rolled {
+            self.utilization = (self.bucket_us as f32 / 1e6).min(1.0);
+            self.reserved = (self.bucket_nav_us as f32 / 1e6).min(1.0);
+            self.bucket_sec = now;
+            self.bucket_us = 0;
+            self.bucket_nav_us = 0;
+        }
+        self.frames += 1;
+        self.airtime_us += s.airtime_us as u64;
+        self.nav_us += s.nav_us as u64;
+        //late, from a sensor that's behind; its second's already gone
+        if now >= self.bucket_sec {
+            self.bucket_us += s.airtime_us as u64;
+            self.bucket_nav_us += s.nav_us as u64;
+        }
+        match (s.frame_ty, s.ctrl_subtype) {
+            (FrameType::Management, _) => self.mgmt += 1,
+            (FrameType::Data, _) => self.data += 1,
+            (FrameType::Control, Some(dot11::CTRL_RTS)) => self.rts += 1,
+            (FrameType::Control, Some(dot11::CTRL_CTS)) => self.cts += 1,
+            (FrameType::Control, Some(dot11::CTRL_ACK)) => self.ack += 1,
+            (FrameType::Control, Some(dot11::CTRL_BA)) => self.block_ack += 1,
+            (FrameType::Control, _) => self.other_ctrl += 1,
+            _ => {}
+        }
+        rolled
+    }
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct StationAirtime {
+    pub mac: MacAddr,
+    pub mhz: Option<u16>,
+    pub frames: u64,
+    pub airtime_us: u64,
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct AirtimeMsg {
+    typ: &'static str,
+    channel: ChannelAirtime,
+    stations: Vec<StationAirtime>,
+}
+
+const AIRTIME_MSG_STATIONS: usize = 10;
+
+#[derive(Clone)]
+pub struct AirtimeController {
+    pub channels: Arc<RwLock<HashMap<u16, ChannelAirtime>>>,
+    pub stations: Arc<RwLock<HashMap<MacAddr, StationAirtime>>>,
+    airtime_tx: Sender<AirtimeSample>,
+    mcast: Multicast<AirtimeMsg>,
+}
+
+impl AirtimeController {
+    pub fn spawn() -> io::Result<AirtimeController> {
+        let (airtime_tx, airtime_rx) = channel();
+        let out = AirtimeController {
+            channels: Arc::new(RwLock::new(HashMap::new())),
+            stations: Arc::new(RwLock::new(HashMap::new())),
+            airtime_tx: airtime_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("airtime_handler".to_string()).spawn(move || {
+            loop {
+                let res = airtime_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let s: AirtimeSample = res.unwrap();
+                let now = s.time;
+
+                if let Some(ta) = s.ta {
+                    let mut stations = ctl.stations.write().unwrap();
+                    let st = match stations.entry(ta) {
+                        Entry::Occupied(e) => e.into_mut(),
+                        Entry::Vacant(e) => e.insert(StationAirtime {
+                            mac: ta, mhz: s.mhz, frames: 0, airtime_us: 0
+                        })
+                    };
+                    st.mhz = s.mhz.or(st.mhz);
+                    st.frames += 1;
+                    st.airtime_us += s.airtime_us as u64;
+                }
+
+                let mhz = match s.mhz {
+                    Some(m) => m,
+                    None => continue
+                };
+                let rolled = {
+                    let mut channels = ctl.channels.write().unwrap();
+                    let ch = match channels.entry(mhz) {
+                        Entry::Occupied(e) => e.into_mut(),
+                        Entry::Vacant(e) => e.insert(ChannelAirtime::new(mhz, now))
+                    };
+                    if ch.update(&s, now) { Some(ch.clone()) } else { None }
+                };
+                if let Some(ch) = rolled {
+                    ctl.mcast.send(Arc::new(AirtimeMsg {
+                        typ: "airtime",
+                        channel: ch,
+                        stations: ctl.top_stations(mhz, AIRTIME_MSG_STATIONS)
+                    }));
+                }
+            }
+        }));
+
+        Ok(out)
+    }
+
+    /// The stations that have used the most airtime on a channel.
+    pub fn top_stations(&self, mhz: u16, n: usize) -> Vec<StationAirtime> {
+        let stations = self.stations.read().unwrap();
+        let mut list: Vec<_> = stations.values()
+            .filter(|st| st.mhz == Some(mhz))
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| a.airtime_us.cmp(&b.airtime_us).reverse());
+        list.truncate(n);
+        list
+    }
+
+    pub fn sender(&self) -> Sender<AirtimeSample> {
+        self.airtime_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<AirtimeMsg>>) {
+        self.mcast.register(s);
+    }
+}
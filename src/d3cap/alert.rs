@@ -0,0 +1,83 @@
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use time;
+
+use multicast::Multicast;
+use fixed_ring::FixedRingBuffer;
+
+use ether::MacAddr;
+
+// Anything that wants a human to look at it right now: shown in the cli as
+// it happens, kept around for `ls alerts`, and pushed to the ui.
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct Alert {
+    pub kind: &'static str,
+    pub addrs: Vec<MacAddr>,
+    pub message: String,
+    pub time: i64,
+}
+
+impl Alert {
+    pub fn new(kind: &'static str, addrs: Vec<MacAddr>, message: String) -> Alert {
+        Alert {
+            kind: kind,
+            addrs: addrs,
+            message: message,
+            time: time::get_time().sec
+        }
+    }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct AlertMsg {
+    typ: &'static str,
+    alert: Alert,
+}
+
+const ALERT_HISTORY_LEN: usize = 100;
+
+#[derive(Clone)]
+pub struct AlertController {
+    pub recent: Arc<RwLock<FixedRingBuffer<Alert>>>,
+    alert_tx: Sender<Alert>,
+    mcast: Multicast<AlertMsg>,
+}
+
+impl AlertController {
+    pub fn spawn() -> io::Result<AlertController> {
+        let (alert_tx, alert_rx) = channel();
+        let out = AlertController {
+            recent: Arc::new(RwLock::new(FixedRingBuffer::new(ALERT_HISTORY_LEN))),
+            alert_tx: alert_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("alert_handler".to_string()).spawn(move || {
+            loop {
+                let res = alert_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let alert: Alert = res.unwrap();
+                println!("\nALERT [{}]: {}", alert.kind, alert.message);
+                ctl.recent.write().unwrap().push(alert.clone());
+                ctl.mcast.send(Arc::new(AlertMsg { typ: "alert", alert: alert }));
+            }
+        }));
+
+        Ok(out)
+    }
+
+    pub fn sender(&self) -> Sender<Alert> {
+        self.alert_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<AlertMsg>>) {
+        self.mcast.register(s);
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+
+use util::{checked_cast, le_u16};
+use ether::MacAddr;
+use dot11::{self, ManagementFrameHeader};
+use ie::{Ies, Security};
+use alert::Alert;
+use rogue::{self, Network};
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, ParseErr, Radio};
+
+// Access points announce themselves in beacons, and answer probes with the
+// same thing.  Both bodies are: timestamp (8), beacon interval (2),
+// capability info (2), then information elements.  802.11-2012 8.3.3.2
+const BEACON_FIXED_LEN: usize = 12;
+const CAP_PRIVACY: u16 = 1 << 4;
+
+pub struct ApSighting {
+    pub bssid: MacAddr,
+    pub beacon: bool,
+    pub interval: u16,
+    pub privacy: bool,
+    pub ies: Ies,
+    pub radio: Option<Radio>,
+    /// Capture time.
+    pub time: i64,
+}
+
+pub fn parse_beacon(frame: &[u8], beacon: bool, radio: Option<Radio>, time: i64)
+                    -> Option<ApSighting> {
+    let hdr: &ManagementFrameHeader = match checked_cast(frame) {
+        Some(h) => h,
+        None => return None
+    };
+    let off = hdr.body_offset();
+    if frame.len() < off + BEACON_FIXED_LEN {
+        return None;
+    }
+    let body = &frame[off..];
+    Some(ApSighting {
+        bssid: hdr.addr3,
+        beacon: beacon,
+        interval: le_u16(&body[8..]),
+        privacy: le_u16(&body[10..]) & CAP_PRIVACY != 0,
+        ies: Ies::parse(&body[BEACON_FIXED_LEN..]),
+        radio: radio,
+        time: time
+    })
+}
+
+/// Summarize what it takes to join: the strongest scheme offered wins, with
+/// transition modes called out since they're weaker than they look.
+pub fn security_mode(privacy: bool, rsn: &Option<Security>, wpa: &Option<Security>)
+                     -> &'static str {
+    fn has(s: &Option<Security>, akm: &str) -> bool {
+        s.as_ref().map(|s| s.akms.iter().any(|a| a.contains(akm))).unwrap_or(false)
+    }
+    let sae = has(rsn, "SAE");
+    let psk = has(rsn, "PSK") || has(wpa, "PSK");
+    let eap = has(rsn, "802.1X") || has(wpa, "802.1X");
+    match (rsn.is_some(), wpa.is_some()) {
+        (true, _) if sae && psk => "WPA2/WPA3-SAE",
+        (true, _) if sae => "WPA3-SAE",
+        (true, _) if has(rsn, "OWE") => "OWE",
+        (true, true) if eap => "WPA/WPA2-Enterprise",
+        (true, true) => "WPA/WPA2-PSK",
+        (true, false) if eap => "WPA2-Enterprise",
+        (true, false) => "WPA2-PSK",
+        (false, true) if eap => "WPA-Enterprise",
+        (false, true) => "WPA-PSK",
+        (false, false) if privacy => "WEP",
+        (false, false) => "Open"
+    }
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct ApInfo {
+    pub bssid: MacAddr,
+    pub ssid: Option<String>,
+    pub channel: Option<u8>,
+    pub mhz: Option<u16>,
+    pub rates: Vec<f32>,
+    pub ht: bool,
+    pub vht: bool,
+    pub he: bool,
+    pub security: &'static str,
+    pub rsn: Option<Security>,
+    pub wpa: Option<Security>,
+    pub country: Option<String>,
+    pub beacon_interval: u16,
+    pub signal: Option<i8>,
+    pub beacons: u32,
+    pub probe_resps: u32,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// Alert kinds it's currently raising against our configured networks.
+    pub issues: Vec<&'static str>,
+}
+
+impl ApInfo {
+    fn new(s: &ApSighting, now: i64) -> ApInfo {
+        let mut ap = ApInfo {
+            bssid: s.bssid,
+            ssid: None,
+            channel: None,
+            mhz: None,
+            rates: Vec::new(),
+            ht: false,
+            vht: false,
+            he: false,
+            security: "Open",
+            rsn: None,
+            wpa: None,
+            country: None,
+            beacon_interval: 0,
+            signal: None,
+            beacons: 0,
+            probe_resps: 0,
+            first_seen: now,
+            last_seen: now,
+            issues: Vec::new()
+        };
+        ap.update(s, now);
+        ap
+    }
+
+    fn update(&mut self, s: &ApSighting, now: i64) {
+        let ies = &s.ies;
+        //hidden networks give their ssid away in probe responses
+        if ies.ssid.is_some() {
+            self.ssid = ies.ssid.clone();
+        }
+        self.channel = ies.channel.or(self.channel);
+        self.rates = ies.rates.clone();
+        self.ht = ies.ht;
+        self.vht = ies.vht;
+        self.he = ies.he;
+        self.security = security_mode(s.privacy, &ies.rsn, &ies.wpa);
+        self.rsn = ies.rsn.clone();
+        self.wpa = ies.wpa.clone();
+        self.country = ies.country.clone().or(self.country.take());
+        self.beacon_interval = s.interval;
+        if let Some(r) = s.radio {
+            self.mhz = r.mhz.or(self.mhz);
+            self.signal = r.signal.or(self.signal);
+        }
+        if s.beacon {
+            self.beacons += 1;
+        } else {
+            self.probe_resps += 1;
+        }
+        //out of order, from a sensor that's behind
+        if now > self.last_seen {
+            self.last_seen = now;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ApController {
+    pub map: Arc<RwLock<HashMap<MacAddr, ApInfo>>>,
+    ap_tx: Sender<ApSighting>,
+}
+
+impl ApController {
+    pub fn spawn(networks: Vec<Network>, alerts: Sender<Alert>) -> io::Result<ApController> {
+        let (ap_tx, ap_rx) = channel();
+        let out = ApController {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            ap_tx: ap_tx
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("ap_handler".to_string()).spawn(move || {
+            //dropped if nobody's listening for alerts any more
+            let mut alerts = Some(alerts);
+            loop {
+                let res = ap_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let s: ApSighting = res.unwrap();
+                let now = s.time;
+
+                let mut map = ctl.map.write().unwrap();
+                let ap = match map.entry(s.bssid) {
+                    Entry::Occupied(e) => {
+                        let ap = e.into_mut();
+                        ap.update(&s, now);
+                        ap
+                    }
+                    Entry::Vacant(e) => e.insert(ApInfo::new(&s, now))
+                };
+
+                //only alert on what's newly wrong, not every beacon
+                let issues = rogue::check(ap, &networks);
+                for &(kind, ref msg) in issues.iter() {
+                    if ap.issues.contains(&kind) {
+                        continue
+                    }
+                    let alert = Alert::new(kind, vec![ap.bssid], msg.clone());
+                    if alerts.as_ref().map(|a| a.send(alert).is_err()).unwrap_or(false) {
+                        alerts = None;
+                    }
+                }
+                ap.issues = issues.iter().map(|&(kind, _)| kind).collect();
+            }
+        }));
+
+        Ok(out)
+    }
+
+    pub fn sender(&self) -> Sender<ApSighting> {
+        self.ap_tx.clone()
+    }
+}
+
+pub struct ApDissector {
+    beacon: bool,
+    ap_tx: Sender<ApSighting>,
+}
+
+impl Dissector for ApDissector {
+    fn dissect(&self, _: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        match parse_beacon(data, self.beacon, ctx.radio, ctx.time) {
+            Some(s) => Ok(try!(self.ap_tx.send(s))),
+            None => Err(ParseErr::UnknownPacket)
+        }
+    }
+}
+
+pub fn register_dissectors(reg: &mut DissectorRegistry, ap_tx: Sender<ApSighting>) {
+    reg.register(DissectKey::Dot11Mgmt(dot11::MGMT_BEACON),
+                 Box::new(ApDissector { beacon: true, ap_tx: ap_tx.clone() }));
+    reg.register(DissectKey::Dot11Mgmt(dot11::MGMT_PROBE_RESP),
+                 Box::new(ApDissector { beacon: false, ap_tx: ap_tx }));
+}
@@ -0,0 +1,433 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use toml;
+
+use multicast::Multicast;
+
+use util::{checked_cast, le_u16};
+use ether::MacAddr;
+use dot11::{self, ManagementFrameHeader};
+use alert::Alert;
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, ParseErr};
+
+// Joining and leaving a BSS: authentication, (re)association, and the
+// deauthentication and disassociation frames either side can send to end it.
+// Deauths are unprotected on most networks, so a flood of them is the usual
+// sign of somebody kicking clients off.
+
+#[derive(RustcEncodable, Copy, Clone, PartialEq, Debug)]
+pub enum MgmtKind {
+    AssocReq,
+    AssocResp,
+    ReassocReq,
+    ReassocResp,
+    Auth,
+    Deauth,
+    Disassoc,
+}
+
+pub struct AssocEvent {
+    pub station: MacAddr,
+    pub bssid: MacAddr,
+    pub from_station: bool,
+    pub kind: MgmtKind,
+    /// Status code for responses and authentication, reason code for
+    /// deauthentication and disassociation.
+    pub code: Option<u16>,
+    pub auth_alg: Option<u16>,
+    pub auth_seq: Option<u16>,
+    /// Capture time.
+    pub time: i64,
+}
+
+pub fn parse_assoc(kind: MgmtKind, frame: &[u8], time: i64) -> Option<AssocEvent> {
+    let hdr: &ManagementFrameHeader = match checked_cast(frame) {
+        Some(h) => h,
+        None => return None
+    };
+    let off = hdr.body_offset();
+    if frame.len() < off {
+        return None;
+    }
+    let body = &frame[off..];
+    let bssid = hdr.addr3;
+    let from_station = hdr.addr2 != bssid;
+    let mut ev = AssocEvent {
+        station: if from_station { hdr.addr2 } else { hdr.addr1 },
+        bssid: bssid,
+        from_station: from_station,
+        kind: kind,
+        code: None,
+        auth_alg: None,
+        auth_seq: None,
+        time: time
+    };
+    // 802.11-2012 8.3.3
+    match kind {
+        MgmtKind::AssocReq | MgmtKind::ReassocReq => {}
+        MgmtKind::AssocResp | MgmtKind::ReassocResp => {
+            //capability, then status
+            if body.len() < 4 {
+                return None;
+            }
+            ev.code = Some(le_u16(&body[2..]));
+        }
+        MgmtKind::Auth => {
+            //algorithm, transaction sequence, status
+            if body.len() < 6 {
+                return None;
+            }
+            ev.auth_alg = Some(le_u16(body));
+            ev.auth_seq = Some(le_u16(&body[2..]));
+            ev.code = Some(le_u16(&body[4..]));
+        }
+        MgmtKind::Deauth | MgmtKind::Disassoc => {
+            if body.len() < 2 {
+                return None;
+            }
+            ev.code = Some(le_u16(body));
+        }
+    }
+    Some(ev)
+}
+
+// 802.11-2012 Table 8-36
+pub fn reason_name(code: u16) -> &'static str {
+    match code {
+        1 => "unspecified",
+        2 => "previous authentication no longer valid",
+        3 => "station leaving",
+        4 => "inactivity",
+        5 => "AP unable to handle all stations",
+        6 => "class 2 frame from unauthenticated station",
+        7 => "class 3 frame from unassociated station",
+        8 => "station leaving BSS",
+        9 => "not authenticated",
+        10 => "power capability unacceptable",
+        11 => "supported channels unacceptable",
+        13 => "invalid element",
+        14 => "MIC failure",
+        15 => "4-way handshake timeout",
+        16 => "group key handshake timeout",
+        17 => "4-way handshake element mismatch",
+        18 => "invalid group cipher",
+        19 => "invalid pairwise cipher",
+        20 => "invalid AKMP",
+        21 => "unsupported RSN version",
+        22 => "invalid RSN capabilities",
+        23 => "802.1X authentication failed",
+        24 => "cipher suite rejected",
+        34 => "too many unacknowledged frames",
+        _ => "unknown"
+    }
+}
+
+// 802.11-2012 Table 8-37
+pub fn status_name(code: u16) -> &'static str {
+    match code {
+        0 => "success",
+        1 => "unspecified failure",
+        10 => "capabilities unsupported",
+        11 => "reassociation denied",
+        12 => "association denied",
+        13 => "authentication algorithm unsupported",
+        14 => "authentication sequence out of order",
+        15 => "challenge failure",
+        16 => "authentication timeout",
+        17 => "AP full",
+        18 => "basic rates unsupported",
+        30 => "rejected temporarily",
+        31 => "management frame policy violation",
+        37 => "request declined",
+        40 => "invalid element",
+        41 => "invalid group cipher",
+        42 => "invalid pairwise cipher",
+        43 => "invalid AKMP",
+        53 => "invalid PMKID",
+        _ => "unknown"
+    }
+}
+
+#[derive(RustcEncodable, Copy, Clone, PartialEq, Debug)]
+pub enum AssocStatus {
+    Authenticating,
+    Authenticated,
+    Associating,
+    Associated,
+    Rejected,
+    Deauthenticated,
+    Disassociated,
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct AssocState {
+    pub station: MacAddr,
+    pub bssid: MacAddr,
+    pub status: AssocStatus,
+    pub auths: u32,
+    pub assoc_reqs: u32,
+    pub assocs: u32,
+    pub rejections: u32,
+    pub deauths: u32,
+    pub disassocs: u32,
+    /// The last non-success status or reason code, and what it means.
+    pub last_code: Option<u16>,
+    pub last_code_name: Option<&'static str>,
+    pub first_seen: i64,
+    pub last_update: i64,
+}
+
+impl AssocState {
+    fn new(ev: &AssocEvent, now: i64) -> AssocState {
+        AssocState {
+            station: ev.station,
+            bssid: ev.bssid,
+            status: AssocStatus::Authenticating,
+            auths: 0,
+            assoc_reqs: 0,
+            assocs: 0,
+            rejections: 0,
+            deauths: 0,
+            disassocs: 0,
+            last_code: None,
+            last_code_name: None,
+            first_seen: now,
+            last_update: now
+        }
+    }
+
+    fn update(&mut self, ev: &AssocEvent, now: i64) {
+        let ok = ev.code.map(|c| c == 0).unwrap_or(true);
+        match ev.kind {
+            MgmtKind::Auth => {
+                //count exchanges, not frames: the station always starts them
+                if ev.from_station && ev.auth_seq == Some(1) {
+                    self.auths += 1;
+                    self.status = AssocStatus::Authenticating;
+                } else if !ev.from_station && ok {
+                    self.status = AssocStatus::Authenticated;
+                }
+            }
+            MgmtKind::AssocReq | MgmtKind::ReassocReq => {
+                self.assoc_reqs += 1;
+                self.status = AssocStatus::Associating;
+            }
+            MgmtKind::AssocResp | MgmtKind::ReassocResp => {
+                if ok {
+                    self.assocs += 1;
+                    self.status = AssocStatus::Associated;
+                }
+            }
+            MgmtKind::Deauth => {
+                self.deauths += 1;
+                self.status = AssocStatus::Deauthenticated;
+            }
+            MgmtKind::Disassoc => {
+                self.disassocs += 1;
+                self.status = AssocStatus::Disassociated;
+            }
+        }
+        if !ok && ev.kind != MgmtKind::Deauth && ev.kind != MgmtKind::Disassoc {
+            self.rejections += 1;
+            self.status = AssocStatus::Rejected;
+        }
+        if let Some(c) = ev.code {
+            if !ok {
+                self.last_code = Some(c);
+                self.last_code_name = Some(match ev.kind {
+                    MgmtKind::Deauth | MgmtKind::Disassoc => reason_name(c),
+                    _ => status_name(c)
+                });
+            }
+        }
+        self.last_update = now;
+    }
+}
+
+/// How many deauth/disassoc frames for one BSS within how many seconds it
+/// takes to call it a flood.
+#[derive(Copy, Clone, Debug)]
+pub struct FloodConf {
+    pub count: usize,
+    pub secs: i64,
+}
+
+impl FloodConf {
+    pub fn new() -> FloodConf {
+        FloodConf { count: 30, secs: 10 }
+    }
+
+    /// Reads the [wifi] table, e.g.:
+    ///
+    ///     [wifi]
+    ///     deauth-flood-count = 30
+    ///     deauth-flood-secs = 10
+    pub fn from_toml(conf: &toml::Table) -> FloodConf {
+        let mut out = FloodConf::new();
+        if let Some(tbl) = conf.get("wifi").and_then(|t| t.as_table()) {
+            let int = |key: &str| tbl.get(key).and_then(|v| v.as_integer());
+            out.count = int("deauth-flood-count").map(|c| c as usize).unwrap_or(out.count);
+            out.secs = int("deauth-flood-secs").unwrap_or(out.secs);
+        }
+        out
+    }
+}
+
+struct FloodState {
+    times: VecDeque<i64>,
+    alerted: i64,
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct AssocMsg {
+    typ: &'static str,
+    assoc: AssocState,
+}
+
+#[derive(Clone)]
+pub struct AssocController {
+    pub map: Arc<RwLock<HashMap<(MacAddr, MacAddr), AssocState>>>,
+    assoc_tx: Sender<AssocEvent>,
+    mcast: Multicast<AssocMsg>,
+}
+
+impl AssocController {
+    pub fn spawn(flood: FloodConf, alerts: Sender<Alert>) -> io::Result<AssocController> {
+        let (assoc_tx, assoc_rx) = channel();
+        let out = AssocController {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            assoc_tx: assoc_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("assoc_handler".to_string()).spawn(move || {
+            let mut floods: HashMap<MacAddr, FloodState> = HashMap::new();
+            //dropped if nobody's listening for alerts any more
+            let mut alerts = Some(alerts);
+            loop {
+                let res = assoc_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let ev: AssocEvent = res.unwrap();
+                //capture time, so a file's deauths are as far apart as they were
+                let now = ev.time;
+
+                let st = {
+                    let mut map = ctl.map.write().unwrap();
+                    let st = match map.entry((ev.station, ev.bssid)) {
+                        Entry::Occupied(e) => e.into_mut(),
+                        Entry::Vacant(e) => e.insert(AssocState::new(&ev, now))
+                    };
+                    st.update(&ev, now);
+                    st.clone()
+                };
+                ctl.mcast.send(Arc::new(AssocMsg { typ: "assoc", assoc: st }));
+
+                if ev.kind != MgmtKind::Deauth && ev.kind != MgmtKind::Disassoc {
+                    continue;
+                }
+                let fs = match floods.entry(ev.bssid) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(FloodState { times: VecDeque::new(), alerted: 0 })
+                };
+                fs.times.push_back(now);
+                while fs.times.front().map(|&t| now - t >= flood.secs).unwrap_or(false) {
+                    fs.times.pop_front();
+                }
+                //one alert per window, not one per frame past the threshold
+                if fs.times.len() >= flood.count && now - fs.alerted >= flood.secs {
+                    fs.alerted = now;
+                    let reason = ev.code.map(|c| format!(", last reason {} ({})", c, reason_name(c)))
+                        .unwrap_or("".to_string());
+                    let alert = Alert::new(
+                        "deauth-flood",
+                        vec![ev.bssid, ev.station],
+                        format!("{} deauth/disassoc frames for BSS {} in {}s{}",
+                                fs.times.len(), ev.bssid, flood.secs, reason));
+                    if alerts.as_ref().map(|a| a.send(alert).is_err()).unwrap_or(false) {
+                        alerts = None;
+                    }
+                }
+            }
+        }));
+
+        Ok(out)
+    }
+
+    pub fn sender(&self) -> Sender<AssocEvent> {
+        self.assoc_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<AssocMsg>>) {
+        self.mcast.register(s);
+    }
+}
+
+pub struct AssocDissector {
+    kind: MgmtKind,
+    assoc_tx: Sender<AssocEvent>,
+}
+
+impl Dissector for AssocDissector {
+    fn dissect(&self, _: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        match parse_assoc(self.kind, data, ctx.time) {
+            Some(ev) => Ok(try!(self.assoc_tx.send(ev))),
+            None => Err(ParseErr::UnknownPacket)
+        }
+    }
+}
+
+pub fn register_dissectors(reg: &mut DissectorRegistry, assoc_tx: Sender<AssocEvent>) {
+    let kinds = [(dot11::MGMT_ASSOC_REQ, MgmtKind::AssocReq),
+                 (dot11::MGMT_ASSOC_RESP, MgmtKind::AssocResp),
+                 (dot11::MGMT_REASSOC_REQ, MgmtKind::ReassocReq),
+                 (dot11::MGMT_REASSOC_RESP, MgmtKind::ReassocResp),
+                 (dot11::MGMT_AUTH, MgmtKind::Auth),
+                 (dot11::MGMT_DEAUTH, MgmtKind::Deauth),
+                 (dot11::MGMT_DISASSOC, MgmtKind::Disassoc)];
+    for &(subtype, kind) in kinds.iter() {
+        reg.register(DissectKey::Dot11Mgmt(subtype),
+                     Box::new(AssocDissector { kind: kind, assoc_tx: assoc_tx.clone() }));
+    }
+}
+
+#[cfg(test)]
+fn hex(s: &str) -> Vec<u8> {
+    use rustc_serialize::hex::FromHex;
+    s.replace(" ", "").from_hex().unwrap()
+}
+
+// A deauth from an AP, reason 7, as captured with the FCS stripped: just the
+// 24 byte header and the reason code.
+#[test]
+fn test_parse_deauth() {
+    let frame = hex("c000 3a01 001122334455 66778899aabb 66778899aabb 1000 0700");
+    assert_eq!(frame.len(), 26);
+
+    let hdr: &ManagementFrameHeader = checked_cast(&frame).unwrap();
+    assert_eq!(hdr.base.fr_ctrl.frame_subtype(), dot11::MGMT_DEAUTH);
+    assert_eq!(hdr.seq().number(), 1);
+
+    let ev = parse_assoc(MgmtKind::Deauth, &frame, 1000).unwrap();
+    assert_eq!(ev.station, MacAddr::from_string("00:11:22:33:44:55").unwrap());
+    assert_eq!(ev.bssid, MacAddr::from_string("66:77:88:99:aa:bb").unwrap());
+    assert!(!ev.from_station);
+    assert_eq!(ev.code, Some(7));
+    assert_eq!(ev.time, 1000);
+}
+
+// With the Order bit set the body would start after HT Control, which a
+// 26 byte frame doesn't have.
+#[test]
+fn test_parse_deauth_truncated() {
+    let frame = hex("c080 3a01 001122334455 66778899aabb 66778899aabb 1000 0700");
+    assert!(parse_assoc(MgmtKind::Deauth, &frame, 1000).is_none());
+}
@@ -0,0 +1,587 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use rustc_serialize::hex::ToHex;
+
+use multicast::Multicast;
+
+use util::{le_u16, le_u32};
+use ether::MacAddr;
+use distance::SignalReading;
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, GraphSender, ParseErr,
+              SeenFrames};
+use pcap::rustpcap as cap;
+
+// Bluetooth Low Energy link layer, as BLE sniffers capture it.  Devices
+// advertise on three channels, saying who they are and what they offer in a
+// list of AD structures; other devices scan them and connect to them.  Only
+// the advertising channels are decoded: once connected, packets only carry
+// the connection's access address, not the devices'.  Core 5.3 Vol 6 Part B 2.3
+
+/// What LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR puts in front of the packet.
+const PHDR_LEN: usize = 10;
+const PHDR_SIGNAL_VALID: u16 = 0x0002;
+const PHDR_CRC_CHECKED: u16 = 0x0400;
+const PHDR_CRC_VALID: u16 = 0x0800;
+
+const ADV_ACCESS_ADDRESS: u32 = 0x8e89bed6;
+/// Access address and PDU header, before the payload.
+const LL_HDR_LEN: usize = 6;
+const LL_CRC_LEN: usize = 3;
+
+pub const ADV_IND: u8 = 0;
+pub const ADV_DIRECT_IND: u8 = 1;
+pub const ADV_NONCONN_IND: u8 = 2;
+pub const SCAN_REQ: u8 = 3;
+pub const SCAN_RSP: u8 = 4;
+pub const CONNECT_IND: u8 = 5;
+pub const ADV_SCAN_IND: u8 = 6;
+
+/// RF channels are 2MHz apart, from 2402.
+fn channel_mhz(rf_channel: u8) -> Option<u16> {
+    if rf_channel < 40 { Some(2402 + 2 * rf_channel as u16) } else { None }
+}
+
+/// Device addresses go over the air least significant byte first.
+fn ble_addr(b: &[u8]) -> MacAddr {
+    let mut a = [0; 6];
+    for (o, x) in a.iter_mut().zip(b[..6].iter().rev()) {
+        *o = *x;
+    }
+    MacAddr::from_bytes(&a)
+}
+
+/// Random addresses say what kind they are in their top two bits.
+/// Core 5.3 Vol 6 Part B 1.3
+pub fn addr_type(addr: &MacAddr, random: bool) -> &'static str {
+    if !random {
+        return "public";
+    }
+    match addr.as_bytes()[0] >> 6 {
+        0b11 => "static",
+        0b01 => "resolvable",
+        0b00 => "non-resolvable",
+        _ => "random"
+    }
+}
+
+// AD types.  Assigned Numbers 2.3
+const AD_FLAGS: u8 = 0x01;
+const AD_UUID16_INCOMPLETE: u8 = 0x02;
+const AD_UUID16_COMPLETE: u8 = 0x03;
+const AD_UUID32_INCOMPLETE: u8 = 0x04;
+const AD_UUID32_COMPLETE: u8 = 0x05;
+const AD_UUID128_INCOMPLETE: u8 = 0x06;
+const AD_UUID128_COMPLETE: u8 = 0x07;
+const AD_NAME_SHORT: u8 = 0x08;
+const AD_NAME_COMPLETE: u8 = 0x09;
+const AD_TX_POWER: u8 = 0x0a;
+const AD_SERVICE_DATA16: u8 = 0x16;
+const AD_APPEARANCE: u8 = 0x19;
+const AD_MANUFACTURER: u8 = 0xff;
+
+/// A few of the companies you'll see most, by Bluetooth SIG company ID.
+fn company_name(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x0006 => "Microsoft",
+        0x004c => "Apple",
+        0x0059 => "Nordic Semiconductor",
+        0x0075 => "Samsung",
+        0x0087 => "Garmin",
+        0x00e0 => "Google",
+        0x0157 => "Huami",
+        0x0171 => "Amazon",
+        0x038f => "Xiaomi",
+        _ => return None
+    })
+}
+
+/// What a device said about itself in its advertising data.
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct AdData {
+    pub flags: Option<u8>,
+    pub name: Option<String>,
+    pub services: Vec<String>,
+    pub tx_power: Option<i8>,
+    pub appearance: Option<u16>,
+    /// Company ID, and whatever follows it as hex.
+    pub manufacturer: Option<(u16, String)>,
+}
+
+impl AdData {
+    pub fn new() -> AdData {
+        AdData { flags: None, name: None, services: Vec::new(), tx_power: None,
+                 appearance: None, manufacturer: None }
+    }
+
+    /// A list of (length, type, data) structures; a zero length ends it early.
+    pub fn parse(mut data: &[u8]) -> AdData {
+        let mut ad = AdData::new();
+        while data.len() >= 2 && data[0] != 0 {
+            let len = data[0] as usize;
+            if data.len() < 1 + len {
+                break;
+            }
+            let typ = data[1];
+            let v = &data[2..1 + len];
+            match typ {
+                AD_FLAGS if v.len() >= 1 => ad.flags = Some(v[0]),
+                AD_UUID16_INCOMPLETE | AD_UUID16_COMPLETE => {
+                    ad.services.extend(v.chunks(2).filter(|u| u.len() == 2)
+                                       .map(|u| format!("{:04x}", le_u16(u))));
+                }
+                AD_UUID32_INCOMPLETE | AD_UUID32_COMPLETE => {
+                    ad.services.extend(v.chunks(4).filter(|u| u.len() == 4)
+                                       .map(|u| format!("{:08x}", le_u32(u))));
+                }
+                AD_UUID128_INCOMPLETE | AD_UUID128_COMPLETE => {
+                    ad.services.extend(v.chunks(16).filter(|u| u.len() == 16).map(uuid128));
+                }
+                //a complete name beats a shortened one
+                AD_NAME_COMPLETE => ad.name = Some(lossy_str(v)),
+                AD_NAME_SHORT if ad.name.is_none() => ad.name = Some(lossy_str(v)),
+                AD_TX_POWER if v.len() >= 1 => ad.tx_power = Some(v[0] as i8),
+                AD_SERVICE_DATA16 if v.len() >= 2 => {
+                    let u = format!("{:04x}", le_u16(v));
+                    if !ad.services.contains(&u) {
+                        ad.services.push(u);
+                    }
+                }
+                AD_APPEARANCE if v.len() >= 2 => ad.appearance = Some(le_u16(v)),
+                AD_MANUFACTURER if v.len() >= 2 => {
+                    ad.manufacturer = Some((le_u16(v), v[2..].to_hex()));
+                }
+                _ => {}
+            }
+            data = &data[1 + len..];
+        }
+        ad
+    }
+}
+
+fn uuid128(u: &[u8]) -> String {
+    let b: Vec<u8> = u.iter().rev().cloned().collect();
+    format!("{}-{}-{}-{}-{}", b[0..4].to_hex(), b[4..6].to_hex(), b[6..8].to_hex(),
+            b[8..10].to_hex(), b[10..16].to_hex())
+}
+
+fn lossy_str(b: &[u8]) -> String {
+    String::from_utf8_lossy(b).into_owned()
+}
+
+/// A connect request's parameters.  Core 5.3 Vol 6 Part B 2.3.3.1
+#[derive(RustcEncodable, Clone, Copy, Debug)]
+pub struct ConnParams {
+    pub access_address: u32,
+    pub interval_ms: f32,
+    pub latency: u16,
+    pub timeout_ms: u32,
+}
+
+const CONNECT_LL_DATA_LEN: usize = 22;
+
+fn parse_conn_params(d: &[u8]) -> Option<ConnParams> {
+    if d.len() < CONNECT_LL_DATA_LEN {
+        return None;
+    }
+    Some(ConnParams {
+        access_address: le_u32(d),
+        interval_ms: le_u16(&d[10..]) as f32 * 1.25,
+        latency: le_u16(&d[12..]),
+        timeout_ms: le_u16(&d[14..]) as u32 * 10
+    })
+}
+
+/// One advertising channel PDU.
+pub struct AdvPdu {
+    pub pdu: u8,
+    /// The advertiser; scan and connect requests are addressed to it.
+    pub adv: MacAddr,
+    pub adv_random: bool,
+    /// The scanner, initiator, or directed advertising's target.
+    pub peer: Option<MacAddr>,
+    pub ad: AdData,
+    pub conn: Option<ConnParams>,
+}
+
+/// From the access address on, CRC included.
+pub fn parse_adv(data: &[u8]) -> Option<AdvPdu> {
+    if data.len() < LL_HDR_LEN + LL_CRC_LEN || le_u32(data) != ADV_ACCESS_ADDRESS {
+        return None;
+    }
+    let hdr = data[4];
+    let len = data[5] as usize;
+    if data.len() < LL_HDR_LEN + len {
+        return None;
+    }
+    let payload = &data[LL_HDR_LEN..LL_HDR_LEN + len];
+    let pdu = hdr & 0x0f;
+    let tx_random = hdr & 0x40 != 0;
+    let rx_random = hdr & 0x80 != 0;
+    if payload.len() < 6 {
+        return None;
+    }
+    let first = ble_addr(payload);
+    let second = if payload.len() >= 12 { Some(ble_addr(&payload[6..])) } else { None };
+
+    let mut out = AdvPdu {
+        pdu: pdu,
+        adv: first,
+        adv_random: tx_random,
+        peer: None,
+        ad: AdData::new(),
+        conn: None
+    };
+    match pdu {
+        ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | SCAN_RSP => {
+            out.ad = AdData::parse(&payload[6..]);
+        }
+        ADV_DIRECT_IND => {
+            out.peer = second;
+        }
+        SCAN_REQ | CONNECT_IND => {
+            //sent by the scanner or initiator, to the advertiser
+            out.adv = match second {
+                Some(a) => a,
+                None => return None
+            };
+            out.adv_random = rx_random;
+            out.peer = Some(first);
+            if pdu == CONNECT_IND {
+                out.conn = parse_conn_params(&payload[12..]);
+            }
+        }
+        _ => return None
+    }
+    Some(out)
+}
+
+pub struct BleSighting {
+    pub pdu: AdvPdu,
+    pub sensor: usize,
+    /// Capture time.
+    pub time: i64,
+    pub rssi: Option<i8>,
+    pub mhz: Option<u16>,
+    /// Another sensor already passed this one along; only the rssi is news.
+    pub repeat: bool,
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct BleDevice {
+    pub addr: MacAddr,
+    pub addr_type: &'static str,
+    pub name: Option<String>,
+    pub manufacturer: Option<String>,
+    pub mfr_data: Option<String>,
+    pub services: Vec<String>,
+    pub tx_power: Option<i8>,
+    pub appearance: Option<u16>,
+    pub connectable: bool,
+    pub adverts: u32,
+    pub scan_reqs: u32,
+    pub connects: u32,
+    /// Who's scanned or connected to it.
+    pub peers: Vec<MacAddr>,
+    pub conn: Option<ConnParams>,
+    pub rssi: Option<i8>,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+impl BleDevice {
+    fn new(addr: MacAddr, random: bool, now: i64) -> BleDevice {
+        BleDevice {
+            addr: addr,
+            addr_type: addr_type(&addr, random),
+            name: None,
+            manufacturer: None,
+            mfr_data: None,
+            services: Vec::new(),
+            tx_power: None,
+            appearance: None,
+            connectable: false,
+            adverts: 0,
+            scan_reqs: 0,
+            connects: 0,
+            peers: Vec::new(),
+            conn: None,
+            rssi: None,
+            first_seen: now,
+            last_seen: now
+        }
+    }
+
+    /// Whether anything worth telling the ui about changed.
+    fn update(&mut self, s: &BleSighting) -> bool {
+        let p = &s.pdu;
+        let mut changed = false;
+        match p.pdu {
+            SCAN_REQ => self.scan_reqs += 1,
+            CONNECT_IND => {
+                self.connects += 1;
+                self.conn = p.conn;
+                changed = true;
+            }
+            _ => {
+                self.adverts += 1;
+                self.connectable |= p.pdu == ADV_IND || p.pdu == ADV_DIRECT_IND;
+                self.rssi = s.rssi.or(self.rssi);
+            }
+        }
+        if let Some(peer) = p.peer {
+            if p.pdu != ADV_DIRECT_IND && !self.peers.contains(&peer) {
+                self.peers.push(peer);
+                changed = true;
+            }
+        }
+        let ad = &p.ad;
+        if ad.name.is_some() && ad.name != self.name {
+            self.name = ad.name.clone();
+            changed = true;
+        }
+        if let Some((id, ref data)) = ad.manufacturer {
+            let name = company_name(id).map(|n| n.to_string())
+                .unwrap_or_else(|| format!("0x{:04x}", id));
+            if self.manufacturer.as_ref() != Some(&name) {
+                self.manufacturer = Some(name);
+                changed = true;
+            }
+            self.mfr_data = Some(data.clone());
+        }
+        for u in ad.services.iter() {
+            if !self.services.contains(u) {
+                self.services.push(u.clone());
+                changed = true;
+            }
+        }
+        self.tx_power = ad.tx_power.or(self.tx_power);
+        self.appearance = ad.appearance.or(self.appearance);
+        if s.time > self.last_seen {
+            self.last_seen = s.time;
+        }
+        changed
+    }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct BleMsg {
+    typ: &'static str,
+    device: BleDevice,
+}
+
+//Advertisers repeat themselves every few hundred ms.
+const BLE_MSG_EVERY: u32 = 50;
+
+#[derive(Clone)]
+pub struct BleController {
+    pub map: Arc<RwLock<HashMap<MacAddr, BleDevice>>>,
+    ble_tx: Sender<BleSighting>,
+    mcast: Multicast<BleMsg>,
+}
+
+impl BleController {
+    /// Signal strengths are passed on to dist, for the advertiser or whoever
+    /// sent the request.
+    pub fn spawn(dist: Sender<SignalReading>) -> io::Result<BleController> {
+        let (ble_tx, ble_rx) = channel();
+        let out = BleController {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            ble_tx: ble_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("ble_handler".to_string()).spawn(move || {
+            loop {
+                let res = ble_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let s: BleSighting = res.unwrap();
+
+                if let (Some(dbm), Some(mhz)) = (s.rssi, s.mhz) {
+                    let sender = match s.pdu.pdu {
+                        SCAN_REQ | CONNECT_IND => s.pdu.peer.unwrap_or(s.pdu.adv),
+                        _ => s.pdu.adv
+                    };
+                    //only adverts carry it, so fall back on what we've heard before
+                    let tx_power = s.pdu.ad.tx_power.or_else(|| {
+                        ctl.map.read().unwrap().get(&sender).and_then(|d| d.tx_power)
+                    });
+                    let reading = SignalReading {
+                        sensor: s.sensor,
+                        time: s.time,
+                        mac: sender,
+                        mhz: mhz,
+                        dbm: dbm,
+                        tx_power: tx_power
+                    };
+                    if dist.send(reading).is_err() {
+                        break
+                    }
+                }
+                if s.repeat {
+                    continue
+                }
+
+                let mut map = ctl.map.write().unwrap();
+                let (dev, new) = match map.entry(s.pdu.adv) {
+                    Entry::Occupied(e) => (e.into_mut(), false),
+                    Entry::Vacant(e) => (e.insert(BleDevice::new(s.pdu.adv, s.pdu.adv_random, s.time)),
+                                         true)
+                };
+                let adverts = dev.adverts;
+                let changed = dev.update(&s);
+                if new || changed || (dev.adverts != adverts && dev.adverts % BLE_MSG_EVERY == 0) {
+                    ctl.mcast.send(Arc::new(BleMsg { typ: "ble_device", device: dev.clone() }));
+                }
+            }
+        }));
+
+        Ok(out)
+    }
+
+    pub fn sender(&self) -> Sender<BleSighting> {
+        self.ble_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<BleMsg>>) {
+        self.mcast.register(s);
+    }
+}
+
+/// Advertisements aren't sent to anyone in particular, so they're graphed
+/// as going to this.
+fn broadcast() -> MacAddr {
+    MacAddr::from_bytes(&[0xff; 6])
+}
+
+pub struct BleDissector {
+    phdr: bool,
+    graph: GraphSender<MacAddr>,
+    ble_tx: Sender<BleSighting>,
+    seen: Option<SeenFrames>,
+}
+
+impl Dissector for BleDissector {
+    fn dissect(&self, _: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let (mut rssi, mut mhz, mut ll) = (None, None, data);
+        if self.phdr {
+            if data.len() < PHDR_LEN {
+                return Err(ParseErr::UnknownPacket);
+            }
+            let flags = le_u16(&data[8..]);
+            if flags & PHDR_CRC_CHECKED != 0 && flags & PHDR_CRC_VALID == 0 {
+                return Ok(());
+            }
+            if flags & PHDR_SIGNAL_VALID != 0 {
+                rssi = Some(data[1] as i8);
+            }
+            mhz = channel_mhz(data[0]);
+            ll = &data[PHDR_LEN..];
+        }
+        let pdu = match parse_adv(ll) {
+            Some(p) => p,
+            //data channel, or something we don't know
+            None => return Ok(())
+        };
+
+        let repeat = match self.seen {
+            Some(ref s) => s.repeat(ctx.sensor, ctx.time, ll),
+            None => false
+        };
+        if !repeat {
+            let (src, dst) = match (pdu.pdu, pdu.peer) {
+                (SCAN_REQ, Some(p)) | (CONNECT_IND, Some(p)) => (p, pdu.adv),
+                (ADV_DIRECT_IND, Some(p)) => (pdu.adv, p),
+                _ => (pdu.adv, broadcast())
+            };
+            let size = ctx.len;
+            ctx.emit(&self.graph, src, dst, size);
+        }
+        ctx.wireless = true;
+        try!(self.ble_tx.send(BleSighting {
+            pdu: pdu,
+            sensor: ctx.sensor,
+            time: ctx.time,
+            rssi: rssi,
+            mhz: mhz,
+            repeat: repeat
+        }));
+        Ok(())
+    }
+}
+
+pub fn register_dissectors(reg: &mut DissectorRegistry, graph: GraphSender<MacAddr>,
+                           ble_tx: Sender<BleSighting>, seen: Option<SeenFrames>) {
+    reg.register(DissectKey::LinkType(cap::DLT_BLUETOOTH_LE_LL),
+                 Box::new(BleDissector { phdr: false, graph: graph.clone(), ble_tx: ble_tx.clone(),
+                                         seen: seen.clone() }));
+    reg.register(DissectKey::LinkType(cap::DLT_BLUETOOTH_LE_LL_WITH_PHDR),
+                 Box::new(BleDissector { phdr: true, graph: graph, ble_tx: ble_tx, seen: seen }));
+}
+
+#[cfg(test)]
+fn hex(s: &str) -> Vec<u8> {
+    use rustc_serialize::hex::FromHex;
+    s.replace(" ", "").from_hex().unwrap()
+}
+
+// A static random address advertising flags, a name, its tx power, the
+// battery service and some Apple data, CRC on the end.
+#[test]
+fn test_parse_adv_ind() {
+    let ll = hex("d6be898e 401c 5544332211c0 020106 0509 54696c65 020af4 03030f18 \
+                  05ff4c000215 aabbcc");
+    let p = parse_adv(&ll).unwrap();
+    assert_eq!(p.pdu, ADV_IND);
+    assert_eq!(p.adv, MacAddr::from_string("c0:11:22:33:44:55").unwrap());
+    assert!(p.adv_random);
+    assert_eq!(addr_type(&p.adv, p.adv_random), "static");
+    assert!(p.peer.is_none());
+    assert!(p.conn.is_none());
+
+    assert_eq!(p.ad.flags, Some(0x06));
+    assert_eq!(p.ad.name, Some("Tile".to_string()));
+    assert_eq!(p.ad.tx_power, Some(-12));
+    assert_eq!(p.ad.services, vec!["180f".to_string()]);
+    assert_eq!(p.ad.manufacturer, Some((0x004c, "0215".to_string())));
+
+    //a data channel packet has the connection's access address instead
+    let mut data = ll.clone();
+    data[0] = 0x43;
+    assert!(parse_adv(&data).is_none());
+}
+
+// A resolvable random initiator connecting to a public advertiser: InitA
+// comes first, then AdvA, then 30ms interval, latency 4 and a 720ms
+// supervision timeout in the LLData.
+#[test]
+fn test_parse_connect_ind() {
+    let ll = hex("d6be898e 4522 ffeeddccbb7a 554433221100 \
+                  434c6550 aabbcc 02 0000 1800 0400 4800 ffffffff1f 25 aabbcc");
+    let p = parse_adv(&ll).unwrap();
+    assert_eq!(p.pdu, CONNECT_IND);
+    assert_eq!(p.adv, MacAddr::from_string("00:11:22:33:44:55").unwrap());
+    assert!(!p.adv_random);
+    let peer = p.peer.unwrap();
+    assert_eq!(peer, MacAddr::from_string("7a:bb:cc:dd:ee:ff").unwrap());
+    assert_eq!(addr_type(&peer, true), "resolvable");
+
+    let conn = p.conn.unwrap();
+    assert_eq!(conn.access_address, 0x50654c43);
+    assert_eq!(conn.interval_ms, 30.0);
+    assert_eq!(conn.latency, 4);
+    assert_eq!(conn.timeout_ms, 720);
+
+    //cut off in the LLData: still a connect, just without its parameters
+    let short = hex("d6be898e 4510 ffeeddccbb7a 554433221100 434c6550 aabbcc");
+    let p = parse_adv(&short).unwrap();
+    assert_eq!(p.pdu, CONNECT_IND);
+    assert!(p.conn.is_none());
+}
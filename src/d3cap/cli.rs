@@ -1,65 +1,32 @@
-use std::iter;
-use std::collections::hash_map::{Entry, HashMap};
-use std::hash::{Hash};
-use std::fmt::{Display};
+use std::collections::hash_map::{HashMap};
 use std::thread::{self, JoinGuard};
-use std::old_io::net as old_net;
-use std::net;
 use std::io::{self};
 use std::error::FromError;
+use std::cmp::Ordering;
 
-use d3cap::{D3capController, ProtocolHandler, PhysDataController};
-use ether::{MacAddr};
-use ip::{AsStdIpAddr};
+use time;
 
-use readline::readline;
-
-trait TransAddr<T> {
-    fn trans(&mut self, addr: &T) -> String;
-}
-
-impl TransAddr<MacAddr> for HashMap<MacAddr, String> {
-    fn trans(&mut self, addr: &MacAddr) -> String {
-        match self.get(addr) {
-            Some(v) => v.clone(),
-            None => addr.to_string()
-        }
-    }
-}
-
-//TODO: waiting for https://github.com/rust-lang/rust/issues/22608
-fn new_to_old_ip(n: net::IpAddr) -> old_net::ip::IpAddr {
-    match n {
-        net::IpAddr::V4(addr) => {
-            let o = addr.octets();
-            old_net::ip::IpAddr::Ipv4Addr(o[0], o[1], o[2], o[3])
-        }
-        net::IpAddr::V6(addr) => {
-            let s = addr.segments();
-            old_net::ip::IpAddr::Ipv6Addr(s[0], s[1], s[2], s[3],
-                                          s[4], s[5], s[6], s[7])
-        }
-    }
-}
+use d3cap::{D3capController, GraphRef, PhysDataController};
+use pkt_graph::{PktStats, Rate, Rates};
+use names::{AddrNames, GraphAddr};
+use neighbor::{NeighborController};
+use eapol::{AuthController};
+use ap::{ApController};
+use probe::{ProbeController};
+use assoc::{AssocController};
+use alert::{AlertController};
+use airtime::{AirtimeController};
+use link::{LinkController, LinkStats};
+use distance::{DistanceController, DistanceEstimate};
+use position::PositionController;
+use signal::{parse_duration, sparkline, SignalBucket, SignalController};
+use topology::TopologyController;
+use presence::PresenceController;
+use devices::DeviceController;
+use ble::BleController;
+use ether::MacAddr;
 
-impl<T:AsStdIpAddr+Eq+Hash+Display+Clone> TransAddr<T> for HashMap<T, String> {
-    fn trans(&mut self, addr: &T) -> String {
-        let k = addr.clone();
-        match self.entry(k) {
-            Entry::Occupied(e) => e.get().clone(),
-            Entry::Vacant(e) => {
-                let a = addr.as_std_ip();
-                let n = match old_net::addrinfo::get_address_name(new_to_old_ip(a)) {
-                    Ok(name) => name,
-                    _ => addr.to_string()
-                };
-                let out = n.clone();
-                e.insert(n);
-                out
-            }
-        }
-    }
-}
+use readline::readline;
 
 #[derive(Debug)]
 enum CliErr {
@@ -72,6 +39,11 @@ impl FromError<io::Error> for CliErr {
     }
 }
 
+const SPARKLINE_WIDTH: usize = 60;
+const SIGNAL_ROWS: usize = 12;
+/// What `ls <graph>` can sort by: totals, or bytes/s over a window.
+const LS_SORTS: [&'static str; 5] = ["count", "size", "10s", "1m", "5m"];
+
 type CliFn = (&'static str, Box<FnMut(Vec<&str>, &mut D3capController)->Result<(), CliErr>>);
 
 pub fn start_cli<'a>(ctrl: D3capController) -> io::Result<JoinGuard<'a, ()>> {
@@ -98,53 +70,524 @@ pub fn start_cli<'a>(ctrl: D3capController) -> io::Result<JoinGuard<'a, ()>> {
                         })
                     })));
 
-        fn print_ls_addr<A, T>(ph: &ProtocolHandler<A>, t: &mut T)
-            where A: Eq+Hash+Copy+Clone+Display+Send+Sync,
-                  T: TransAddr<A>
-        {
-            let graph = ph.graph.read().unwrap();
-            let mut list: Vec<_> = graph.iter()
-                .flat_map(|(src_addr, astats)| {
-                    iter::repeat(src_addr).zip(astats.sent_iter())
-                }).collect();
+        fn fmt_rate(r: Rate) -> String {
+            let (v, unit) = match r.bytes {
+                b if b >= 1e6 => (b / 1e6, "MB"),
+                b if b >= 1e3 => (b / 1e3, "kB"),
+                b => (b, "B")
+            };
+            format!("{:.1} {}/s {:.1} pkt/s", v, unit, r.pkts)
+        }
+
+        fn fmt_rates(r: &Rates) -> String {
+            format!("10s: {}, 1m: {}, 5m: {}",
+                    fmt_rate(r.last_10s), fmt_rate(r.last_1m), fmt_rate(r.last_5m))
+        }
+
+        /// Biggest first, by total count or size, or bytes/s over a window.
+        fn sort_key(sort: &str, stats: &PktStats, rates: &Rates) -> f64 {
+            match sort {
+                "count" => stats.count as f64,
+                "size" => stats.size as f64,
+                w => rates.get(w).map(|r| r.bytes).unwrap_or(0.0)
+            }
+        }
+
+        fn print_ls_addr(graph: &GraphRef, names: &mut AddrNames, sort: &str) {
+            if !LS_SORTS.contains(&sort) {
+                println!("Unknown sort, try one of: {}", LS_SORTS.connect(", "));
+                return;
+            }
+            let mut list: Vec<_> = graph.routes(names).into_iter()
+                .map(|r| (sort_key(sort, &r.stats, &r.rates), r))
+                .collect();
+            list.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
 
-            list.sort_by(|a,b| (a.1).1.count.cmp(&(b.1).1.count).reverse());
+            for &(_, ref r) in list.iter() {
+                let tag_str = match r.tag {
+                    Some(t) => format!(" [{}]", t),
+                    None => "".to_string()
+                };
+                println!("{} -> {}{}: count: {}, size: {}, {}",
+                         r.src, r.dst, tag_str, r.stats.count, r.stats.size, fmt_rates(&r.rates));
+            }
+        }
+
+        fn print_ls_hosts(graph: &GraphRef, names: &mut AddrNames, sort: &str) {
+            if !LS_SORTS.contains(&sort) {
+                println!("Unknown sort, try one of: {}", LS_SORTS.connect(", "));
+                return;
+            }
+            let mut list: Vec<_> = graph.hosts(names).into_iter()
+                .map(|h| {
+                    let total = PktStats { count: h.sent.count + h.received.count,
+                                           size: h.sent.size + h.received.size };
+                    (sort_key(sort, &total, &h.sent_rates.plus(&h.received_rates)), h)
+                })
+                .collect();
+            list.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+            for &(_, ref h) in list.iter() {
+                let tag_str = match h.tag {
+                    Some(t) => format!(" [{}]", t),
+                    None => "".to_string()
+                };
+                println!("{}{}: sent: {} pkts, {} bytes, {}", h.addr, tag_str,
+                         h.sent.count, h.sent.size, fmt_rates(&h.sent_rates));
+                println!("    received: {} pkts, {} bytes, {}",
+                         h.received.count, h.received.size, fmt_rates(&h.received_rates));
+            }
+        }
 
-            for &(src_addr, (dst_addr, pstats)) in list.iter() {
-                println!("{} -> {}: count: {}, size: {}",
-                         t.trans(&src_addr), t.trans(&dst_addr), pstats.count, pstats.size);
+        fn fmt_dist(est: Option<DistanceEstimate>) -> String {
+            match est {
+                Some(e) => format!("{:.1}m ({:.1}-{:.1}m)", e.meters, e.low, e.high),
+                None => "-".to_string()
             }
         }
 
-        fn print_ls_tap<T:TransAddr<MacAddr>>(pd_ctrl: &PhysDataController, macs: &mut T) {
+        fn print_ls_tap(pd_ctrl: &PhysDataController, dist_ctrl: &DistanceController,
+                        names: &mut AddrNames) {
             let m = pd_ctrl.map.read().unwrap();
+            //distance is per transmitter, addr2
             let mut list: Vec<_> = m.iter()
-                .filter(|&(_, ref v)| v.dat.len() > 1).collect();
+                .filter(|&(_, ref v)| v.dat.len() > 1)
+                .map(|(k, v)| (k, v, dist_ctrl.estimate(&k.1[1])))
+                .collect();
 
-            list.sort_by(|a, b| a.1.avg_dist().partial_cmp(&b.1.avg_dist()).unwrap());
+            //closest first, anything we couldn't place at the end
+            list.sort_by(|a, b| match (a.2, b.2) {
+                (Some(x), Some(y)) => x.meters.partial_cmp(&y.meters).unwrap_or(Ordering::Equal),
+                (x, y) => x.is_none().cmp(&y.is_none())
+            });
 
-            for i in list.iter() {
-                let (ref k, ref v) = *i;
+            for &(k, v, est) in list.iter() {
                 println!("{:?} [{}, {}, {}]: total: {}, curr_len: {}, dist: {}",
                          k.0,
-                         macs.trans(&k.1[0]), macs.trans(&k.1[1]), macs.trans(&k.1[2]),
-                         v.count, v.dat.len(), v.avg_dist());
+                         k.1[0].name(names), k.1[1].name(names), k.1[2].name(names),
+                         v.count, v.dat.len(), fmt_dist(est));
+            }
+            println!("");
+
+            let rates = pd_ctrl.rates.read().unwrap();
+            let mut stations: Vec<_> = rates.iter().collect();
+            stations.sort_by(|a, b| a.1.dist.total.cmp(&b.1.dist.total).reverse());
+
+            for &(station, sr) in stations.iter() {
+                println!("{}: {} frames", station.name(names), sr.dist.total);
+                for rc in sr.dist.rates.iter() {
+                    println!("    {}: {} ({:.1}%)", rc.rate, rc.count,
+                             100.0 * rc.count as f32 / sr.dist.total as f32);
+                }
+            }
+            println!("");
+        }
+
+        fn print_ls_neighbors(nbr_ctrl: &NeighborController, names: &mut AddrNames) {
+            //not name(), we're already holding the neighbor table
+            let m = nbr_ctrl.map.read().unwrap();
+            let mut list: Vec<_> = m.values().collect();
+            list.sort_by(|a, b| a.nbr.name().cmp(b.nbr.name()));
+
+            for v in list.iter() {
+                let n = &v.nbr;
+                let mac = names.macs.get(&n.mac).cloned().unwrap_or(n.mac.to_string());
+                println!("{} {} [{}]: port: {}{}, caps: [{}], mgmt: {}, count: {}",
+                         n.proto, n.name(), mac, n.port_id,
+                         n.port_desc.as_ref().map(|d| format!(" ({})", d)).unwrap_or("".to_string()),
+                         n.capabilities.connect(", "),
+                         n.mgmt_addr.as_ref().map(|a| &a[..]).unwrap_or("-"),
+                         v.count);
+            }
+            println!("");
+        }
+
+        fn print_ls_auth(auth_ctrl: &AuthController, names: &mut AddrNames) {
+            let m = auth_ctrl.map.read().unwrap();
+            let mut list: Vec<_> = m.values().collect();
+            list.sort_by(|a, b| a.last_update.cmp(&b.last_update).reverse());
+
+            for st in list.iter() {
+                let hs: Vec<_> = st.handshake.iter().enumerate()
+                    .map(|(i, &seen)| if seen { format!("{}", i+1) } else { "-".to_string() })
+                    .collect();
+                println!("{} -> {}: {:?}, method: {}, identity: {}, eap: {}, 4-way: [{}], \
+                          attempts: {}, completed: {}, failures: {}, timeouts: {}",
+                         st.station.name(names),
+                         st.authenticator.map(|a| a.name(names)).unwrap_or("?".to_string()),
+                         st.status,
+                         st.eap_method.unwrap_or("-"),
+                         st.identity.as_ref().map(|i| &i[..]).unwrap_or("-"),
+                         match st.eap_success {
+                             Some(true) => "success",
+                             Some(false) => "failure",
+                             None => "-"
+                         },
+                         hs.connect(""),
+                         st.attempts, st.completed, st.failures, st.timeouts);
+            }
+            println!("");
+        }
+
+        fn print_ls_ap(ap_ctrl: &ApController, names: &mut AddrNames) {
+            let m = ap_ctrl.map.read().unwrap();
+            let mut list: Vec<_> = m.values().collect();
+            list.sort_by(|a, b| (&a.ssid, a.bssid.to_string()).cmp(&(&b.ssid, b.bssid.to_string())));
+
+            for ap in list.iter() {
+                let phy: Vec<_> = [(ap.ht, "n"), (ap.vht, "ac"), (ap.he, "ax")].iter()
+                    .filter(|&&(has, _)| has).map(|&(_, p)| p).collect();
+                let ciphers = ap.rsn.as_ref().or(ap.wpa.as_ref())
+                    .map(|s| format!(" ({}/{})", s.pairwise.connect(","), s.akms.connect(",")))
+                    .unwrap_or("".to_string());
+                println!("{} [{}]: ch: {}, {}{}, signal: {}, phy: [{}], rates: [{}], \
+                          country: {}, beacons: {}, probe resps: {}{}",
+                         ap.ssid.as_ref().map(|s| &s[..]).unwrap_or("<hidden>"),
+                         ap.bssid.name(names),
+                         ap.channel.map(|c| c.to_string()).unwrap_or("-".to_string()),
+                         ap.security, ciphers,
+                         ap.signal.map(|s| format!("{} dBm", s)).unwrap_or("-".to_string()),
+                         phy.connect(","),
+                         ap.rates.iter().map(|r| r.to_string()).collect::<Vec<_>>().connect(","),
+                         ap.country.as_ref().map(|c| &c[..]).unwrap_or("-"),
+                         ap.beacons, ap.probe_resps,
+                         if ap.issues.is_empty() { "".to_string() }
+                         else { format!(", SUSPECT: {}", ap.issues.connect(", ")) });
+            }
+            println!("");
+        }
+
+        fn print_ls_bss(topo_ctrl: &TopologyController, names: &mut AddrNames) {
+            let (nodes, _) = topo_ctrl.topology();
+            let mut aps: Vec<_> = nodes.iter().filter(|n| n.kind == "ap").collect();
+            //BSSes of one radio together
+            aps.sort_by(|a, b| (a.parent.map(|p| p.to_string()), a.addr.to_string())
+                        .cmp(&(b.parent.map(|p| p.to_string()), b.addr.to_string())));
+
+            let mut radio = None;
+            for ap in aps.iter() {
+                if ap.parent != radio {
+                    radio = ap.parent;
+                    println!("AP {}", radio.map(|r| r.to_string()).unwrap_or("?".to_string()));
+                }
+                println!("    {} \"{}\": ch: {}, {}", ap.addr.name(names),
+                         ap.ssid.as_ref().map(|s| &s[..]).unwrap_or(""),
+                         ap.channel.map(|c| c.to_string()).unwrap_or("-".to_string()),
+                         ap.security.unwrap_or("-"));
+                for c in nodes.iter().filter(|n| n.kind == "client" && n.parent == Some(ap.addr)) {
+                    println!("        {}", c.addr.name(names));
+                }
+            }
+
+            //clients of BSSes we haven't heard beacons from
+            let unknown: Vec<_> = nodes.iter()
+                .filter(|n| n.kind == "client")
+                .filter(|n| !aps.iter().any(|ap| n.parent == Some(ap.addr)))
+                .collect();
+            if !unknown.is_empty() {
+                println!("Unknown BSSes:");
+                for c in unknown.iter() {
+                    println!("    {} in {}", c.addr.name(names),
+                             c.parent.map(|p| p.name(names)).unwrap_or("?".to_string()));
+                }
+            }
+
+            let probing: Vec<_> = nodes.iter().filter(|n| n.kind == "probing").collect();
+            if !probing.is_empty() {
+                println!("Probing only:");
+                for p in probing.iter() {
+                    println!("    {}", p.addr.name(names));
+                }
+            }
+            println!("");
+        }
+
+        fn print_ls_clients(probe_ctrl: &ProbeController, names: &mut AddrNames) {
+            let m = probe_ctrl.map.read().unwrap();
+            let mut list: Vec<_> = m.values().collect();
+            list.sort_by(|a, b| a.last_seen.cmp(&b.last_seen).reverse());
+
+            //capture times, which for a file aren't anywhere near now
+            let hms = |t: i64| {
+                time::at(time::Timespec::new(t, 0)).strftime("%H:%M:%S").unwrap().to_string()
+            };
+            for c in list.iter() {
+                let signals: Vec<_> = c.signals.iter().map(|s| s.dbm.to_string()).collect();
+                println!("{}{}: ssids: [{}], wildcard: {}, probes: {}, rates: [{}], \
+                          first seen: {}, last seen: {}, signal: [{}]",
+                         c.mac.name(names), if c.randomized { " (random)" } else { "" },
+                         c.ssids.connect(", "), c.wildcard_probes, c.probes,
+                         c.rates.iter().map(|r| r.to_string()).collect::<Vec<_>>().connect(","),
+                         hms(c.first_seen), hms(c.last_seen), signals.connect(" "));
+            }
+            println!("");
+        }
+
+        fn print_ls_devices(device_ctrl: &DeviceController, names: &mut AddrNames) {
+            let devices = device_ctrl.devices.read().unwrap();
+            let mut list: Vec<_> = devices.iter().collect();
+            list.sort_by(|a, b| a.last_seen.cmp(&b.last_seen).reverse());
+
+            for d in list.iter() {
+                let macs: Vec<_> = d.macs.iter().map(|m| m.name(names)).collect();
+                let t = time::at(time::Timespec::new(d.last_seen, 0));
+                println!("device {} [{}]: {} address{}{}, ssids: [{}], probes: {}, last seen: {}",
+                         d.id, d.fingerprint, macs.len(), if macs.len() == 1 { "" } else { "es" },
+                         d.linked_by.map(|l| format!(" (linked by {})", l)).unwrap_or("".to_string()),
+                         d.ssids.connect(", "), d.probes, t.strftime("%H:%M:%S").unwrap());
+                println!("    {}", macs.connect(", "));
+            }
+            println!("");
+        }
+
+        fn print_ls_ble_devices(ble_ctrl: &BleController, names: &mut AddrNames) {
+            let m = ble_ctrl.map.read().unwrap();
+            let mut list: Vec<_> = m.values().collect();
+            list.sort_by(|a, b| a.last_seen.cmp(&b.last_seen).reverse());
+
+            //capture times, like the clients'
+            let hms = |t: i64| {
+                time::at(time::Timespec::new(t, 0)).strftime("%H:%M:%S").unwrap().to_string()
+            };
+            for d in list.iter() {
+                println!("{} ({}){}: {}{}, services: [{}], adverts: {}, scan reqs: {}, connects: {}, \
+                          rssi: {}, last seen: {}",
+                         d.addr.name(names), d.addr_type,
+                         d.name.as_ref().map(|n| format!(" \"{}\"", n)).unwrap_or("".to_string()),
+                         d.manufacturer.as_ref().map(|m| &m[..]).unwrap_or("-"),
+                         if d.connectable { ", connectable" } else { "" },
+                         d.services.connect(", "), d.adverts, d.scan_reqs, d.connects,
+                         d.rssi.map(|r| r.to_string()).unwrap_or("-".to_string()),
+                         hms(d.last_seen));
+                if !d.peers.is_empty() {
+                    let peers: Vec<_> = d.peers.iter().map(|p| p.name(names)).collect();
+                    println!("    peers: {}", peers.connect(", "));
+                }
+            }
+            println!("");
+        }
+
+        fn print_ls_assoc(assoc_ctrl: &AssocController, names: &mut AddrNames) {
+            let m = assoc_ctrl.map.read().unwrap();
+            let mut list: Vec<_> = m.values().collect();
+            list.sort_by(|a, b| a.last_update.cmp(&b.last_update).reverse());
+
+            for st in list.iter() {
+                println!("{} -> {}: {:?}, auths: {}, assoc reqs: {}, assocs: {}, rejections: {}, \
+                          deauths: {}, disassocs: {}, last code: {}",
+                         st.station.name(names), st.bssid.name(names), st.status,
+                         st.auths, st.assoc_reqs, st.assocs, st.rejections,
+                         st.deauths, st.disassocs,
+                         match (st.last_code, st.last_code_name) {
+                             (Some(c), Some(n)) => format!("{} ({})", c, n),
+                             _ => "-".to_string()
+                         });
+            }
+            println!("");
+        }
+
+        fn print_ls_alerts(alert_ctrl: &AlertController) {
+            let recent = alert_ctrl.recent.read().unwrap();
+            let now = time::get_time().sec;
+            for a in recent.iter() {
+                println!("{}s ago [{}]: {}", now - a.time, a.kind, a.message);
+            }
+            println!("");
+        }
+
+        fn print_ls_presence(presence_ctrl: &PresenceController) {
+            let m = presence_ctrl.map.read().unwrap();
+            let mut list: Vec<_> = m.values().collect();
+            list.sort_by(|a, b| (!a.present, &a.name).cmp(&(!b.present, &b.name)));
+
+            for st in list.iter() {
+                let t = time::at(time::Timespec::new(st.since, 0));
+                println!("{} ({}): {} since {}, last seen via {}{}, arrivals: {}, departures: {}",
+                         st.name, st.mac, if st.present { "here" } else { "gone" },
+                         t.strftime("%Y-%m-%d %H:%M:%S").unwrap(), st.source,
+                         st.signal.map(|s| format!(" at {} dBm", s)).unwrap_or("".to_string()),
+                         st.arrivals, st.departures);
+            }
+
+            println!("\nRecent events:");
+            for ev in presence_ctrl.recent.read().unwrap().iter() {
+                let t = time::at(time::Timespec::new(ev.time, 0));
+                println!("    {} {} {} via {}", t.strftime("%Y-%m-%d %H:%M:%S").unwrap(),
+                         ev.name, ev.kind, ev.source);
+            }
+            println!("");
+        }
+
+        fn print_ls_airtime(airtime_ctrl: &AirtimeController, names: &mut AddrNames) {
+            let channels = airtime_ctrl.channels.read().unwrap();
+            let mut list: Vec<_> = channels.values().collect();
+            list.sort_by(|a, b| a.mhz.cmp(&b.mhz));
+
+            for ch in list.iter() {
+                println!("{} MHz: utilization: {:.1}%, reserved: {:.1}%, frames: {}, \
+                          airtime: {}ms, mgmt: {}, data: {}, rts: {}, cts: {}, ack: {}, \
+                          block ack: {}, other ctrl: {}",
+                         ch.mhz, 100.0 * ch.utilization, 100.0 * ch.reserved, ch.frames,
+                         ch.airtime_us / 1000, ch.mgmt, ch.data, ch.rts, ch.cts, ch.ack,
+                         ch.block_ack, ch.other_ctrl);
+                for st in airtime_ctrl.top_stations(ch.mhz, 10).iter() {
+                    println!("    {}: {}ms ({:.1}%), frames: {}",
+                             st.mac.name(names), st.airtime_us / 1000,
+                             100.0 * st.airtime_us as f32 / ch.airtime_us as f32, st.frames);
+                }
+            }
+            println!("");
+        }
+
+        fn print_ls_distance(dist_ctrl: &DistanceController, pos_ctrl: &PositionController,
+                             names: &mut AddrNames) {
+            let conf = &*dist_ctrl.conf;
+            println!("model: {:?}, smoothing: {:?}, tx power: {}dBm",
+                     conf.model, conf.smoothing, conf.tx_power);
+            let m = dist_ctrl.map.read().unwrap();
+            let mut list: Vec<_> = m.iter()
+                .filter_map(|(&(sensor, ref mac), sd)| sd.estimate.map(|e| (sensor, mac, sd.mhz, e)))
+                .collect();
+            //a bad config can make NaNs, which shouldn't take the cli down
+            list.sort_by(|a, b| {
+                a.3.meters.partial_cmp(&b.3.meters).unwrap_or(Ordering::Equal)
+            });
+
+            for &(sensor, mac, mhz, est) in list.iter() {
+                let from = pos_ctrl.sensors.get(sensor)
+                    .map(|s| format!(" from {}", s.name)).unwrap_or(String::new());
+                println!("{}{}{}: {}, signal: {:.1}dBm @ {} MHz, samples: {}",
+                         mac.name(names),
+                         if conf.calibration.contains_key(mac) { " (calibrated)" } else { "" },
+                         from, fmt_dist(Some(est)), est.signal, mhz, est.samples);
+            }
+            println!("");
+        }
+
+        fn print_ls_position(pos_ctrl: &PositionController, names: &mut AddrNames) {
+            for s in pos_ctrl.sensors.iter() {
+                println!("sensor {} at ({:.1}, {:.1})", s.name, s.x, s.y);
+            }
+            let m = pos_ctrl.positions.read().unwrap();
+            for (mac, p) in m.iter() {
+                println!("{}: ({:.1}, {:.1}) +/- {:.1}m from {} sensors",
+                         mac.name(names), p.x, p.y, p.error, p.sensors);
             }
             println!("");
         }
 
+        fn print_link_stats(label: &str, st: &LinkStats) {
+            println!("{}: frames: {}, retries: {:.1}%, fcs failures: {:.1}%, bad version: {:.1}%, \
+                      captured: {:.1}% ({} missed, {} repeats)",
+                     label, st.frames, 100.0 * st.retry_rate(), 100.0 * st.fcs_rate(),
+                     100.0 * st.bad_version_rate(), 100.0 * st.completeness(),
+                     st.missed, st.repeats);
+        }
+
+        fn print_ls_link(link_ctrl: &LinkController, names: &mut AddrNames) {
+            print_link_stats("All frames", &*link_ctrl.overall.read().unwrap());
+
+            println!("\nStations, worst retry rate first:");
+            for &(mac, ref st) in link_ctrl.worst_stations(20).iter() {
+                print_link_stats(&format!("    {}", mac.name(names)), st);
+            }
+
+            println!("\nBSSIDs:");
+            let bssids = link_ctrl.bssids.read().unwrap();
+            for (mac, st) in bssids.iter() {
+                print_link_stats(&format!("    {}", mac.name(names)), st);
+            }
+            println!("");
+        }
 
         cmds.insert("ls".to_string(),
                     ("ls", Box::new(|cmd, ctrl| {
                         Ok(match &cmd[1..] {
-                            ["mac"] => print_ls_addr(&ctrl.pg_ctrl.mac, &mut ctrl.mac_names),
-                            ["ip4"] => print_ls_addr(&ctrl.pg_ctrl.ip4, &mut ctrl.ip4_names),
-                            ["ip6"] => print_ls_addr(&ctrl.pg_ctrl.ip6, &mut ctrl.ip6_names),
-                            ["tap"] => print_ls_tap(&ctrl.pd_ctrl, &mut ctrl.mac_names),
+                            ["tap"] => print_ls_tap(&ctrl.pd_ctrl, &ctrl.dist_ctrl, &mut ctrl.names),
+                            ["distance"] => print_ls_distance(&ctrl.dist_ctrl, &ctrl.pos_ctrl, &mut ctrl.names),
+                            ["position"] => print_ls_position(&ctrl.pos_ctrl, &mut ctrl.names),
+                            ["neighbors"] => print_ls_neighbors(&ctrl.nbr_ctrl, &mut ctrl.names),
+                            ["auth"] => print_ls_auth(&ctrl.auth_ctrl, &mut ctrl.names),
+                            ["ap"] => print_ls_ap(&ctrl.ap_ctrl, &mut ctrl.names),
+                            ["bss"] => print_ls_bss(&ctrl.topo_ctrl, &mut ctrl.names),
+                            ["clients"] => print_ls_clients(&ctrl.probe_ctrl, &mut ctrl.names),
+                            ["devices"] => print_ls_devices(&ctrl.device_ctrl, &mut ctrl.names),
+                            ["ble-devices"] => print_ls_ble_devices(&ctrl.ble_ctrl, &mut ctrl.names),
+                            ["assoc"] => print_ls_assoc(&ctrl.assoc_ctrl, &mut ctrl.names),
+                            ["alerts"] => print_ls_alerts(&ctrl.alert_ctrl),
+                            ["presence"] => print_ls_presence(&ctrl.presence_ctrl),
+                            ["airtime"] => print_ls_airtime(&ctrl.airtime_ctrl, &mut ctrl.names),
+                            ["link"] => print_ls_link(&ctrl.link_ctrl, &mut ctrl.names),
+                            [typ, rest..] if rest.len() <= 2 => match ctrl.pg_ctrl.get(typ) {
+                                Some(g) => match rest {
+                                    [] => print_ls_addr(g, &mut ctrl.names, "count"),
+                                    ["hosts"] => print_ls_hosts(g, &mut ctrl.names, "count"),
+                                    ["hosts", sort] => print_ls_hosts(g, &mut ctrl.names, sort),
+                                    [sort] => print_ls_addr(g, &mut ctrl.names, sort),
+                                    _ => println!("Illegal argument")
+                                },
+                                None => println!("Unknown graph, try one of: {}",
+                                                 ctrl.pg_ctrl.types().connect(", "))
+                            },
                             _ => println!("Illegal argument")
                         })
                     })));
 
+        fn fmt_noise(noise: Option<f32>) -> String {
+            noise.map(|n| format!("{:.1}", n)).unwrap_or("-".to_string())
+        }
+
+        fn print_signal(signal_ctrl: &SignalController, pos_ctrl: &PositionController,
+                        mac: &MacAddr, secs: i64, names: &mut AddrNames) {
+            let history = signal_ctrl.history(mac, secs);
+            if history.is_empty() {
+                println!("No signal recorded for {}", mac.name(names));
+                return
+            }
+            for &(sensor, ref buckets) in history.iter() {
+                let sensor_name = pos_ctrl.sensors.get(sensor)
+                    .map(|s| s.name.clone())
+                    .unwrap_or_else(|| format!("sensor{}", sensor));
+                //squeeze long histories down to fit a line
+                let per_col = (buckets.len() + SPARKLINE_WIDTH - 1) / SPARKLINE_WIDTH;
+                let cols: Vec<_> = buckets.chunks(per_col).map(SignalBucket::merge).collect();
+                let all = SignalBucket::merge(&buckets);
+
+                println!("{} from {}, last {}s in {}s intervals:",
+                         mac.name(names), sensor_name, secs, signal_ctrl.conf.interval_secs);
+                println!("    [{}]", sparkline(&cols));
+                println!("    min: {} dBm, avg: {:.1} dBm, max: {} dBm, noise: {} dBm, samples: {}",
+                         all.min, all.avg, all.max, fmt_noise(all.noise), all.samples);
+
+                let per_row = (buckets.len() + SIGNAL_ROWS - 1) / SIGNAL_ROWS;
+                for b in buckets.chunks(per_row).map(SignalBucket::merge) {
+                    let t = time::at(time::Timespec::new(b.start, 0));
+                    println!("    {}: {:4} {:6.1} {:4}  noise {}",
+                             t.strftime("%H:%M:%S").unwrap(), b.min, b.avg, b.max, fmt_noise(b.noise));
+                }
+            }
+            println!("");
+        }
+
+        cmds.insert("signal".to_string(),
+                    ("signal <mac> [duration, e.g. 90s, 30m, 1h]", Box::new(|cmd, ctrl| {
+                        let (mac, secs) = match &cmd[1..] {
+                            [mac] => (mac, Some(ctrl.signal_ctrl.conf.retention_secs)),
+                            [mac, dur] => (mac, parse_duration(dur)),
+                            _ => return Ok(println!("Illegal argument"))
+                        };
+                        //by address or by known-macs name
+                        let addr = MacAddr::from_string(*mac).or_else(|| {
+                            ctrl.names.macs.iter().find(|&(_, n)| &n[..] == *mac).map(|(a, _)| *a)
+                        });
+                        Ok(match (addr, secs) {
+                            (Some(a), Some(s)) if s > 0 => print_signal(&ctrl.signal_ctrl, &ctrl.pos_ctrl,
+                                                                        &a, s, &mut ctrl.names),
+                            (None, _) => println!("Unknown station {}", mac),
+                            _ => println!("Bad duration")
+                        })
+                    })));
+
         let maxlen = cmds.keys().map(|x| x.len()).max().unwrap();
 
         loop {
@@ -2,24 +2,46 @@ use std::thread::{self, JoinHandle};
 use std::error::{FromError};
 use std::hash::{Hash};
 use std::collections::hash_map::{Entry, HashMap};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{self, Read};
-use std::num::Float;
 use std::sync::{Arc,RwLock};
-use std::sync::mpsc::{channel, Sender, SendError};
+use std::sync::mpsc::{channel, Sender};
 
 use toml;
+use time;
 
 use multicast::Multicast;
 use json_serve::uiserver::UIServer;
 
-use util::{ntohs, skip_bytes_cast, skip_cast};
-use ip::{IP4Addr, IP6Addr, IP4Header, IP6Header};
-use ether::{EthernetHeader, MacAddr,
-            ETHERTYPE_ARP, ETHERTYPE_IP4, ETHERTYPE_IP6, ETHERTYPE_802_1X};
+use util::{be_u16, checked_cast};
+use ip::{self, IP4Addr, IP6Addr};
+use ether::{self, MacAddr};
+use tunnel::{self, TunnelConf, TunnelTag};
+use neighbor::{self, NeighborController};
+use eapol::{self, AuthController};
+use wpa::{Keyring, KeyringRef};
+use ap::{self, ApController};
+use probe::{self, ProbeController};
+use assoc::{self, AssocController, FloodConf};
+use alert::AlertController;
+use airtime::{self, AirtimeController, AirtimeSample};
+use link::{LinkController, LinkSample};
+use distance::{DistanceConf, DistanceController, SignalReading};
+use position::{sensors_from_toml, PositionController};
+use signal::{SignalConf, SignalController, SignalSample};
+use topology::{MemberSample, TopologyController};
+use presence::{PresenceConf, PresenceController, Sighting};
+use devices::DeviceController;
+use rogue::networks_from_toml;
+use ble::{self, BleController};
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, GraphSender, ParseErr,
+              Radio, SeenFrames};
+use names::{AddrNames, GraphAddr, MacMap};
 use dot11::{self, FrameType};
 use tap;
-use pkt_graph::{PktMeta, ProtocolGraph, RouteStats};
+use radio_hdr::RadioHdr;
+use pkt_graph::{PktMeta, PktStats, ProtocolGraph, Rates, RouteStats};
 use fixed_ring::FixedRingBuffer;
 use pcap::rustpcap as cap;
 
@@ -30,13 +52,6 @@ struct RouteStatsMsg<T> {
     route: RouteStats<T>,
 }
 
-#[derive(Debug)]
-enum Pkt {
-    Mac(PktMeta<MacAddr>),
-    IP4(PktMeta<IP4Addr>),
-    IP6(PktMeta<IP6Addr>),
-}
-
 #[derive(Clone)]
 pub struct ProtocolHandler<T:Eq+Hash+Send+Sync+'static> {
     pub typ: &'static str,
@@ -44,13 +59,28 @@ pub struct ProtocolHandler<T:Eq+Hash+Send+Sync+'static> {
     stats_mcast: Multicast<RouteStatsMsg<T>>,
 }
 
-impl <T:Send+Sync+Copy+Clone+Eq+Hash> ProtocolHandler<T> {
-    fn new(typ: &'static str) -> io::Result<ProtocolHandler<T>> {
-        Ok(ProtocolHandler {
+impl <T:GraphAddr> ProtocolHandler<T> {
+    /// Starts a thread feeding the graph from the returned sender.
+    fn spawn(typ: &'static str) -> io::Result<(ProtocolHandler<T>, GraphSender<T>)> {
+        let (tx, rx) = channel();
+        let ph = ProtocolHandler {
             typ: typ,
             graph: Arc::new(RwLock::new(ProtocolGraph::new())),
             stats_mcast: try!(Multicast::spawn())
-        })
+        };
+
+        let mut phctl = ph.clone();
+        try!(thread::Builder::new().name(format!("protocol_handler_{}", typ)).spawn(move || {
+            loop {
+                let pkt = rx.recv();
+                if pkt.is_err() {
+                    break
+                }
+                phctl.update(&pkt.unwrap());
+            }
+        }));
+
+        Ok((ph, GraphSender::new(tx)))
     }
 
     fn update(&mut self, pkt: &PktMeta<T>) {
@@ -65,161 +95,165 @@ impl <T:Send+Sync+Copy+Clone+Eq+Hash> ProtocolHandler<T> {
     }
 }
 
-#[derive(Clone)]
-pub struct ProtoGraphController {
-    pub cap_tx: Sender<Pkt>,
-    pub mac: ProtocolHandler<MacAddr>,
-    pub ip4: ProtocolHandler<IP4Addr>,
-    pub ip6: ProtocolHandler<IP6Addr>,
+/// One src -> dst line of a graph, with the addresses already named.
+pub struct RouteLine {
+    pub src: String,
+    pub dst: String,
+    pub tag: Option<TunnelTag>,
+    pub stats: PktStats,
+    pub rates: Rates,
 }
 
-impl ProtoGraphController {
-    fn spawn() -> io::Result<ProtoGraphController> {
-        let (cap_tx, cap_rx) = channel();
-        let ctl = ProtoGraphController {
-            cap_tx: cap_tx,
-            mac: try!(ProtocolHandler::new("mac")),
-            ip4: try!(ProtocolHandler::new("ip4")),
-            ip6: try!(ProtocolHandler::new("ip6")),
-        };
+/// One address of a graph, with everything it's sent and received.
+pub struct HostLine {
+    pub addr: String,
+    pub tag: Option<TunnelTag>,
+    pub sent: PktStats,
+    pub sent_rates: Rates,
+    pub received: PktStats,
+    pub received_rates: Rates,
+}
 
-        let mut phctl = ctl.clone();
-        try!(thread::Builder::new().name("protocol_handler".to_string()).spawn(move || {
-            loop {
-                let pkt = cap_rx.recv();
-                if pkt.is_err() {
-                    break
-                }
-                match pkt.unwrap() {
-                    Pkt::Mac(ref p) => phctl.mac.update(p),
-                    Pkt::IP4(ref p) => phctl.ip4.update(p),
-                    Pkt::IP6(ref p) => phctl.ip6.update(p),
-                }
-            }
-        }));
+/// What the cli and ui need from a graph, without caring about its address type.
+pub trait AnyGraph {
+    fn typ(&self) -> &'static str;
+    fn register_ui(&self, ui: &UIServer);
+    fn routes(&self, names: &mut AddrNames) -> Vec<RouteLine>;
+    fn hosts(&self, names: &mut AddrNames) -> Vec<HostLine>;
+    fn box_clone(&self) -> GraphRef;
+}
 
-        Ok(ctl)
+impl<T:GraphAddr> AnyGraph for ProtocolHandler<T> {
+    fn typ(&self) -> &'static str {
+        self.typ
     }
 
-    fn sender(&self) -> Sender<Pkt> {
-        self.cap_tx.clone()
+    fn register_ui(&self, ui: &UIServer) {
+        self.stats_mcast.register(ui.create_sender());
     }
 
-    fn register_mac_listener(&self, s: Sender<Arc<RouteStatsMsg<MacAddr>>>) {
-        self.mac.stats_mcast.register(s);
+    fn routes(&self, names: &mut AddrNames) -> Vec<RouteLine> {
+        let graph = self.graph.read().unwrap();
+        let now = graph.now();
+        let mut out = Vec::new();
+        for (&(tag, ref src_addr), astats) in graph.iter() {
+            for (dst_addr, pstats) in astats.sent_iter() {
+                out.push(RouteLine {
+                    src: src_addr.name(names),
+                    dst: dst_addr.name(names),
+                    tag: tag,
+                    stats: *pstats,
+                    rates: astats.get_sent_to_rates(dst_addr, now)
+                });
+            }
+        }
+        out
     }
 
-    fn register_ip4_listener(&self, s: Sender<Arc<RouteStatsMsg<IP4Addr>>>) {
-        self.ip4.stats_mcast.register(s);
+    fn hosts(&self, names: &mut AddrNames) -> Vec<HostLine> {
+        let graph = self.graph.read().unwrap();
+        let now = graph.now();
+        graph.iter().map(|(&(tag, ref addr), astats)| HostLine {
+            addr: addr.name(names),
+            tag: tag,
+            sent: astats.get_sent(),
+            sent_rates: astats.get_sent_rates(now),
+            received: astats.get_received(),
+            received_rates: astats.get_received_rates(now)
+        }).collect()
     }
 
-    fn register_ip6_listener(&self, s: Sender<Arc<RouteStatsMsg<IP6Addr>>>) {
-        self.ip6.stats_mcast.register(s);
+    fn box_clone(&self) -> GraphRef {
+        Box::new(self.clone())
     }
 }
 
-enum ParseErr {
-    Send,
-    UnknownPacket
+pub type GraphRef = Box<AnyGraph+Send>;
+
+pub struct ProtoGraphController {
+    graphs: Vec<GraphRef>,
 }
 
-impl<T> FromError<SendError<T>> for ParseErr {
-    fn from_error(_: SendError<T>) -> ParseErr {
-        ParseErr::Send
+impl Clone for ProtoGraphController {
+    fn clone(&self) -> ProtoGraphController {
+        ProtoGraphController { graphs: self.graphs.iter().map(|g| g.box_clone()).collect() }
     }
 }
 
-trait PktParser {
-    fn parse(&mut self, pkt: &cap::PcapData) -> Result<(), ParseErr>;
+impl ProtoGraphController {
+    fn new() -> ProtoGraphController {
+        ProtoGraphController { graphs: Vec::new() }
+    }
+
+    /// Add a graph of typ, returning the sender for dissectors to update it with.
+    pub fn add<T:GraphAddr>(&mut self, typ: &'static str) -> io::Result<GraphSender<T>> {
+        let (ph, tx) = try!(ProtocolHandler::<T>::spawn(typ));
+        self.graphs.push(Box::new(ph));
+        Ok(tx)
+    }
+
+    pub fn get(&self, typ: &str) -> Option<&GraphRef> {
+        self.graphs.iter().find(|g| g.typ() == typ)
+    }
+
+    pub fn types(&self) -> Vec<&'static str> {
+        self.graphs.iter().map(|g| g.typ()).collect()
+    }
+
+    fn register_ui(&self, ui: &UIServer) {
+        for g in self.graphs.iter() {
+            g.register_ui(ui);
+        }
+    }
 }
 
 struct CaptureCtx {
     sess: cap::PcapSession,
-    parser: Box<PktParser+'static>
+    link: DissectKey,
+    sensor: usize,
+    dissectors: DissectorRegistry
 }
 
 impl CaptureCtx {
     fn parse_next(&mut self) {
-        let p = &mut self.parser;
-        self.sess.next(|cap| {
-            match p.parse(cap) {
+        let link = self.link;
+        let sensor = self.sensor;
+        let reg = &self.dissectors;
+        self.sess.next(|pkt| {
+            let mut ctx = DissectCtx::new(pkt);
+            ctx.sensor = sensor;
+            match reg.dispatch(link, &mut ctx, pkt.data()).and_then(|_| ctx.flush()) {
                 _ => () //just ignore
             }
         });
     }
 }
 
-struct EthernetParser {
-    pkts: Sender<Pkt>,
-}
-
-impl PktParser for EthernetParser {
-
-    fn parse(&mut self, pkt: &cap::PcapData) -> Result<(), ParseErr> {
-        let ether_hdr = unsafe { &*(pkt.pkt_ptr() as *const EthernetHeader) };
-        try!(self.pkts.send(Pkt::Mac(PktMeta::new(ether_hdr.src, ether_hdr.dst, pkt.len()))));
-        Ok(match ether_hdr.typ {
-            ETHERTYPE_ARP => {
-                //io::println("ARP!");
-            },
-            ETHERTYPE_IP4 => {
-                let ipp: &IP4Header = unsafe { skip_cast(ether_hdr) };
-                try!(self.pkts.send(Pkt::IP4(PktMeta::new(ipp.src, ipp.dst,
-                                                          ntohs(ipp.len) as u32))));
-            },
-            ETHERTYPE_IP6 => {
-                let ipp: &IP6Header = unsafe { skip_cast(ether_hdr) };
-                try!(self.pkts.send(Pkt::IP6(PktMeta::new(ipp.src, ipp.dst,
-                                                          ntohs(ipp.len) as u32))));
-            },
-            ETHERTYPE_802_1X => {
-                //io::println("802.1X!");
-            },
-            _ => {
-                //println!("Unknown type: {:x}", x);
-            }
-        })
-    }
-}
-
 #[derive(Debug)]
 struct PhysData { // TODO: this name sucks
+    sensor: usize,
+    time: i64,
     frame_ty: FrameType,
     addrs: [MacAddr; 3],
-    rate: Option<tap::Rate>,
-    channel: tap::Channel,
-    antenna_signal: tap::AntennaSignal,
-    antenna_noise: tap::AntennaNoise,
-    antenna: tap::Antenna,
+    radio: tap::RadioFields,
+    /// Another sensor already passed this frame along.
+    repeat: bool,
 }
 
 impl PhysData {
-    fn new(frame_ty: FrameType,
+    fn new(ctx: &DissectCtx,
+           frame_ty: FrameType,
            addrs: [MacAddr; 3],
-           rate: Option<tap::Rate>,
-           channel: tap::Channel,
-           antenna_signal: tap::AntennaSignal,
-           antenna_noise: tap::AntennaNoise,
-           antenna: tap::Antenna,
-           ) -> PhysData {
+           radio: tap::RadioFields) -> PhysData {
         PhysData {
+            sensor: ctx.sensor,
+            time: ctx.time,
             frame_ty: frame_ty,
             addrs: addrs,
-            rate: rate,
-            channel: channel,
-            antenna_signal: antenna_signal,
-            antenna_noise: antenna_noise,
-            antenna: antenna
+            radio: radio,
+            repeat: false
         }
     }
-
-    fn dist(&self) -> f32 {
-        let freq = self.channel.mhz as f32;
-        let signal = self.antenna_signal.dbm as f32;
-
-        let exp = (27.55 - (20.0 * freq.log10()) + signal.abs()) / 20.0;
-        (10.0f32).powf(exp)
-    }
 }
 
 #[derive(PartialEq, Eq, Hash)]
@@ -237,29 +271,79 @@ impl PhysDataVal {
             count: 0
         }
     }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct RateCount {
+    pub rate: tap::PhyRate,
+    pub count: u32,
+}
+
+/// How often a station has transmitted at each rate.
+#[derive(RustcEncodable, Clone)]
+pub struct RateDist {
+    pub total: u32,
+    pub rates: Vec<RateCount>,
+}
+
+impl RateDist {
+    fn new() -> RateDist {
+        RateDist { total: 0, rates: Vec::new() }
+    }
 
-    pub fn avg_dist(&self) -> f32 {
-        let mut s = 0.0;
-        for pd in self.dat.iter() {
-            s += pd.dist();
+    fn add(&mut self, rate: tap::PhyRate) {
+        self.total += 1;
+        for rc in self.rates.iter_mut() {
+            if rc.rate.same_mode(&rate) {
+                rc.count += 1;
+                return;
+            }
         }
-        s / (self.dat.len() as f32)
+        self.rates.push(RateCount { rate: rate, count: 1 });
+        self.rates.sort_by(|a, b| a.rate.mbps.partial_cmp(&b.rate.mbps).unwrap());
     }
+}
 
+pub struct StationRates {
+    pub dist: RateDist,
+    last_sent: time::Timespec,
 }
 
+#[derive(RustcEncodable, Clone)]
+pub struct WifiMsg {
+    typ: &'static str,
+    station: MacAddr,
+    channel: Option<u16>,
+    signal: Option<i8>,
+    rates: RateDist,
+}
+
+//Stations send a lot of frames; don't update the ui for every one.
+const WIFI_MSG_INTERVAL_SECS: i64 = 1;
+
 #[derive(Clone)]
 pub struct PhysDataController {
     pub map:  Arc<RwLock<HashMap<PhysDataKey, PhysDataVal>>>,
-    pd_tx: Sender<PhysData>
+    pub rates: Arc<RwLock<HashMap<MacAddr, StationRates>>>,
+    pd_tx: Sender<PhysData>,
+    dist_tx: Sender<SignalReading>,
+    signal_tx: Sender<SignalSample>,
+    presence_tx: Sender<Sighting>,
+    wifi_mcast: Multicast<WifiMsg>,
 }
 
 impl PhysDataController {
-    fn spawn() -> io::Result<PhysDataController> {
+    fn spawn(dist_tx: Sender<SignalReading>, signal_tx: Sender<SignalSample>,
+             presence_tx: Sender<Sighting>) -> io::Result<PhysDataController> {
         let (pd_tx, pd_rx) = channel();
         let out = PhysDataController {
             pd_tx: pd_tx,
-            map: Arc::new(RwLock::new(HashMap::new()))
+            dist_tx: dist_tx,
+            signal_tx: signal_tx,
+            presence_tx: presence_tx,
+            map: Arc::new(RwLock::new(HashMap::new())),
+            rates: Arc::new(RwLock::new(HashMap::new())),
+            wifi_mcast: try!(Multicast::spawn())
         };
 
         let ctl = out.clone();
@@ -271,6 +355,48 @@ impl PhysDataController {
                 }
                 let pd = res.unwrap();
 
+                match pd.radio.phy_rate {
+                    Some(rate) if !pd.repeat => ctl.update_rates(&pd, rate),
+                    _ => {}
+                }
+                let sighting = Sighting {
+                    mac: pd.addrs[1],
+                    time: pd.time,
+                    source: "wifi",
+                    signal: pd.radio.signal()
+                };
+                if ctl.presence_tx.send(sighting).is_err() {
+                    break
+                }
+                if let Some(dbm) = pd.radio.signal() {
+                    let sample = SignalSample {
+                        sensor: pd.sensor,
+                        time: pd.time,
+                        mac: pd.addrs[1],
+                        dbm: dbm,
+                        noise: pd.radio.antenna_noise.map(|n| n.dbm)
+                    };
+                    if ctl.signal_tx.send(sample).is_err() {
+                        break
+                    }
+                }
+                if let (Some(mhz), Some(dbm)) = (pd.radio.mhz(), pd.radio.signal()) {
+                    let reading = SignalReading {
+                        sensor: pd.sensor,
+                        time: pd.time,
+                        mac: pd.addrs[1],
+                        mhz: mhz,
+                        dbm: dbm,
+                        tx_power: None
+                    };
+                    if ctl.dist_tx.send(reading).is_err() {
+                        break
+                    }
+                }
+                if pd.repeat {
+                    continue
+                }
+
                 match ctl.map.write().unwrap().entry(PhysDataKey(pd.frame_ty, pd.addrs)) {
                     Entry::Occupied(mut e) => {
                         let mut pdc = e.get_mut();
@@ -290,96 +416,302 @@ impl PhysDataController {
         Ok(out)
     }
 
+    /// Rates are counted against the transmitter, addr2.
+    fn update_rates(&self, pd: &PhysData, rate: tap::PhyRate) {
+        let station = pd.addrs[1];
+        let now = time::get_time();
+        let mut rates = self.rates.write().unwrap();
+        let sr = match rates.entry(station) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(StationRates {
+                dist: RateDist::new(),
+                last_sent: time::Timespec::new(0, 0)
+            })
+        };
+        sr.dist.add(rate);
+        if now.sec - sr.last_sent.sec >= WIFI_MSG_INTERVAL_SECS {
+            sr.last_sent = now;
+            self.wifi_mcast.send(Arc::new(WifiMsg {
+                typ: "wifi",
+                station: station,
+                channel: pd.radio.mhz(),
+                signal: pd.radio.signal(),
+                rates: sr.dist.clone()
+            }));
+        }
+    }
+
     fn sender(&self) -> Sender<PhysData> {
         self.pd_tx.clone()
     }
+
+    pub fn register_listener(&self, s: Sender<Arc<WifiMsg>>) {
+        self.wifi_mcast.register(s);
+    }
 }
 
-struct RadiotapParser {
-    pkts: Sender<Pkt>,
-    phys: Sender<PhysData>
-}
-
-impl RadiotapParser {
-    fn parse_known_headers(&self,
-                           frame_ty: FrameType,
-                           addrs: [MacAddr; 3],
-                           tap_hdr: &tap::RadiotapHeader) {
-        match &tap_hdr.it_present {
-            &tap::COMMON_A => {
-                if let Some(vals) = tap::CommonA::parse(tap_hdr) {
-                    self.phys.send(PhysData::new(
-                        frame_ty,
-                        addrs,
-                        Some(vals.rate),
-                        vals.channel,
-                        vals.antenna_signal,
-                        vals.antenna_noise,
-                        vals.antenna
-                    ));
-                }
-            },
-            &tap::COMMON_B => {
-                if let Some(vals) = tap::CommonB::parse(tap_hdr) {
-                    self.phys.send(PhysData::new(
-                        frame_ty,
-                        addrs,
-                        None,
-                        vals.channel,
-                        vals.antenna_signal,
-                        vals.antenna_noise,
-                        vals.antenna
-                    ));
-                }
-            },
-            _ => {} //Unknown header
+struct Dot11Dissector {
+    hdr: RadioHdr,
+    mac: GraphSender<MacAddr>,
+    phys: Sender<PhysData>,
+    airtime: Sender<AirtimeSample>,
+    link: Sender<LinkSample>,
+    topo: Sender<MemberSample>,
+    keys: KeyringRef,
+    /// Only when there's more than one sensor.
+    seen: Option<SeenFrames>,
+}
+
+impl Dot11Dissector {
+    /// Data frames carry an LLC/SNAP header, same as 802.3, so hand them off
+    /// the same way ethernet does, decrypting first if we have the keys.  An
+    /// A-MSDU packs several of those into one frame, each behind its own
+    /// DA/SA/length header.
+    fn dissect_data_body(&self, reg: &DissectorRegistry, ctx: &mut DissectCtx,
+                         hdr: &dot11::DataFrameHeader, frame: &[u8]) -> Result<(), ParseErr> {
+        let fc = &hdr.base.fr_ctrl;
+        if fc.frame_subtype() & dot11::NULL_SUBTYPE != 0 {
+            return Ok(());
+        }
+        let off = hdr.body_offset();
+        if frame.len() < off {
+            return Err(ParseErr::UnknownPacket);
+        }
+        let plain;
+        let body = if fc.has_flag(dot11::PROTECTED_FRAME) {
+            plain = match self.keys.read().unwrap().decrypt(hdr, frame) {
+                Some(p) => p,
+                None => return Ok(())
+            };
+            &plain[..]
+        } else {
+            &frame[off..]
+        };
+        if !hdr.qos_ctl(frame).map(|q| q.is_amsdu()).unwrap_or(false) {
+            return ether::dispatch_llc(reg, ctx, body);
+        }
+
+        let mut rest = body;
+        while rest.len() >= dot11::AMSDU_SUBFRAME_HDR_LEN {
+            let len = be_u16(&rest[12..]) as usize;
+            let end = dot11::AMSDU_SUBFRAME_HDR_LEN + len;
+            if rest.len() < end {
+                return Err(ParseErr::UnknownPacket);
+            }
+            ctx.dst_mac = Some(MacAddr::from_bytes(&rest[0..6]));
+            ctx.src_mac = Some(MacAddr::from_bytes(&rest[6..12]));
+            try!(ether::dispatch_llc(reg, ctx, &rest[dot11::AMSDU_SUBFRAME_HDR_LEN..end]));
+            //subframes are padded out to a multiple of 4, except the last
+            let padded = (end + 3) & !3;
+            rest = if rest.len() > padded { &rest[padded..] } else { &[] };
         }
+        Ok(())
     }
 }
 
+/// The BSSID, sequence control and TID the link stats want from a frame.
+fn link_ids(ft: FrameType, frame: &[u8])
+            -> (Option<MacAddr>, Option<dot11::SequenceControl>, Option<u8>) {
+    match ft {
+        //the fixed 24 bytes, so deauths and other bodyless frames count too
+        FrameType::Management => match checked_cast::<dot11::ManagementFrameHeader>(frame) {
+            Some(m) => (Some(m.addr3), Some(m.seq()), None),
+            //snapped off before the sequence number, but the bssid made it
+            None if frame.len() >= 22 => (Some(MacAddr::from_bytes(&frame[16..22])), None, None),
+            None => (None, None, None)
+        },
+        FrameType::Data => match checked_cast::<dot11::DataFrameHeader>(frame) {
+            Some(d) => (d.addrs(frame).and_then(|a| a.bssid), Some(d.seq()),
+                        d.qos_ctl(frame).map(|q| q.tid())),
+            None => (None, None, None)
+        },
+        _ => (None, None, None)
+    }
+}
 
-impl PktParser for RadiotapParser {
-    fn parse(&mut self, pkt: &cap::PcapData) ->  Result<(), ParseErr> {
+impl Dissector for Dot11Dissector {
+    fn dissect(&self, reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let (radio, hdr_len) = match self.hdr.parse(data) {
+            Some(r) => r,
+            None => return Err(ParseErr::UnknownPacket)
+        };
+        let mut frame = &data[hdr_len..];
+
+        //what went over the air, FCS and all, even if we didn't capture it all
+        let it_len = hdr_len as u32;
+        let mut air_len = if ctx.len > it_len { ctx.len - it_len } else { 0 };
+        if radio.has_flag(tap::INCLUDES_FCS) {
+            if frame.len() >= 4 {
+                frame = &frame[..frame.len()-4];
+            }
+        } else {
+            air_len += 4;
+        }
 
-        let tap_hdr = unsafe { &*(pkt.pkt_ptr() as *const tap::RadiotapHeader) };
+        let base: &dot11::Dot11BaseHeader = match checked_cast(frame) {
+            Some(h) => h,
+            None => return Err(ParseErr::UnknownPacket)
+        };
 
-        fn magic<U>(pkt: &tap::RadiotapHeader) -> &U {
-            unsafe { skip_bytes_cast(pkt, pkt.it_len as isize) }
+        let fc = &base.fr_ctrl;
+        let ft = fc.frame_type();
+        let repeat = match self.seen {
+            Some(ref s) => s.repeat(ctx.sensor, ctx.time, frame),
+            None => false
+        };
+        if repeat {
+            //already counted; all it adds is how it sounded to this sensor,
+            //and that this sensor didn't miss it
+            if ft != FrameType::Control && frame.len() >= 16 {
+                if let (bssid, Some(seq), tid) = link_ids(ft, frame) {
+                    try!(self.link.send(LinkSample {
+                        sensor: ctx.sensor,
+                        ta: MacAddr::from_bytes(&frame[10..16]),
+                        bssid: bssid,
+                        retry: fc.has_flag(dot11::RETRY),
+                        fcs_failed: false,
+                        bad_version: false,
+                        seq: Some(seq),
+                        tid: tid,
+                        repeat: true
+                    }));
+                }
+            }
+            if ft != FrameType::Control && frame.len() >= 22 {
+                let addrs = [MacAddr::from_bytes(&frame[4..10]),
+                             MacAddr::from_bytes(&frame[10..16]),
+                             MacAddr::from_bytes(&frame[16..22])];
+                let mut pd = PhysData::new(ctx, ft, addrs, radio);
+                pd.repeat = true;
+                try!(self.phys.send(pd));
+            }
+            return Ok(());
         }
 
-        let base: &dot11::Dot11BaseHeader = magic(tap_hdr);
+        let fcs_failed = radio.has_flag(tap::FAILED_FCS_CHK);
+        let bad_version = fc.protocol_version() != 0;
+        if fcs_failed || bad_version {
+            //nothing in here can be trusted, but it says something about the link
+            if frame.len() >= 16 {
+                try!(self.link.send(LinkSample {
+                    sensor: ctx.sensor,
+                    ta: MacAddr::from_bytes(&frame[10..16]),
+                    bssid: None,
+                    retry: false,
+                    fcs_failed: fcs_failed,
+                    bad_version: bad_version,
+                    seq: None,
+                    tid: None,
+                    repeat: false
+                }));
+            }
+            return if bad_version { Err(ParseErr::UnknownPacket) } else { Ok(()) };
+        }
 
-        let fc = &base.fr_ctrl;
-        if fc.protocol_version() != 0 {
-            // bogus packet, bail
-            return Err(ParseErr::UnknownPacket);
+        ctx.radio = Some(Radio { mhz: radio.mhz(), signal: radio.signal() });
+
+        let (ta, ctrl_subtype) = match ft {
+            FrameType::Control => match dot11::ControlFrame::parse(frame) {
+                Some(cf) => (cf.ta, Some(cf.subtype)),
+                None => return Err(ParseErr::UnknownPacket)
+            },
+            _ if frame.len() >= 16 => (Some(MacAddr::from_bytes(&frame[10..16])), None),
+            _ => (None, None)
+        };
+        if let Some(us) = airtime::tx_time_us(&radio, air_len) {
+            try!(self.airtime.send(AirtimeSample {
+                mhz: radio.mhz(),
+                ta: ta,
+                frame_ty: ft,
+                ctrl_subtype: ctrl_subtype,
+                airtime_us: us,
+                nav_us: base.dur_id.duration_us().unwrap_or(0) as u32,
+                time: ctx.time
+            }));
+        }
+        if let Some(ta) = ta {
+            let (bssid, seq, tid) = link_ids(ft, frame);
+            try!(self.link.send(LinkSample {
+                sensor: ctx.sensor,
+                ta: ta,
+                bssid: bssid,
+                retry: fc.has_flag(dot11::RETRY),
+                fcs_failed: false,
+                bad_version: false,
+                seq: seq,
+                tid: tid,
+                repeat: false
+            }));
         }
 
-        Ok(match fc.frame_type() {
-            ft @ FrameType::Management => {
-                let mgt: &dot11::ManagementFrameHeader = magic(tap_hdr);
-                self.parse_known_headers(ft, [mgt.addr1, mgt.addr2, mgt.addr3], tap_hdr);
+        match ft {
+            FrameType::Management => {
+                let mgt: &dot11::ManagementFrameHeader = match checked_cast(frame) {
+                    Some(h) => h,
+                    None => return Err(ParseErr::UnknownPacket)
+                };
+                let addrs = [mgt.addr1, mgt.addr2, mgt.addr3];
+                try!(self.phys.send(PhysData::new(ctx, ft, addrs, radio)));
+                reg.dispatch(DissectKey::Dot11Mgmt(fc.frame_subtype()), ctx, frame)
             }
             FrameType::Control => {
-                //println!("Control frame");
+                //nothing above this layer, just airtime
+                Ok(())
             }
-            ft @ FrameType::Data => {
-                let data: &dot11::DataFrameHeader = magic(tap_hdr);
-                //TODO: get length
-                try!(self.pkts.send(Pkt::Mac(PktMeta::new(data.addr1, data.addr2, 1))));
-                self.parse_known_headers(ft, [data.addr1, data.addr2, data.addr3], tap_hdr);
+            FrameType::Data => {
+                let data: &dot11::DataFrameHeader = match checked_cast(frame) {
+                    Some(h) => h,
+                    None => return Err(ParseErr::UnknownPacket)
+                };
+                let ends = match data.addrs(frame) {
+                    Some(a) => a,
+                    None => return Err(ParseErr::UnknownPacket)
+                };
+                if let Some(bssid) = ends.bssid {
+                    let station = if ends.ta == bssid { ends.ra } else { ends.ta };
+                    if !station.is_multicast() {
+                        try!(self.topo.send(MemberSample { station: station, bssid: bssid,
+                                                           time: ctx.time }));
+                    }
+                }
+                ctx.src_mac = Some(ends.sa);
+                ctx.dst_mac = Some(ends.da);
+                ctx.wireless = true;
+                //count the frame as sent, less the FCS
+                let mpdu_len = if air_len > 4 { air_len - 4 } else { 0 };
+                ctx.emit(&self.mac, ends.sa, ends.da, mpdu_len);
+                let addrs = [data.addr1, data.addr2, data.addr3];
+                try!(self.phys.send(PhysData::new(ctx, ft, addrs, radio)));
+                self.dissect_data_body(reg, ctx, data, frame)
             }
             FrameType::Unknown => {
                 //println!("Unknown frame type");
+                Ok(())
             }
-        })
+        }
     }
+}
 
+fn register_dissectors(reg: &mut DissectorRegistry, mac: GraphSender<MacAddr>,
+                       phys: Sender<PhysData>, airtime: Sender<AirtimeSample>,
+                       link: Sender<LinkSample>, topo: Sender<MemberSample>, keys: KeyringRef,
+                       seen: Option<SeenFrames>) {
+    let link_types = [(cap::DLT_IEEE802_11_RADIO, RadioHdr::Radiotap),
+                      (cap::DLT_PPI, RadioHdr::Ppi),
+                      (cap::DLT_PRISM_HEADER, RadioHdr::Prism),
+                      (cap::DLT_IEEE802_11_RADIO_AVS, RadioHdr::Avs),
+                      (cap::DLT_IEEE802_11, RadioHdr::Raw)];
+    for &(dlt, hdr) in link_types.iter() {
+        reg.register(DissectKey::LinkType(dlt),
+                     Box::new(Dot11Dissector { hdr: hdr, mac: mac.clone(), phys: phys.clone(),
+                                               airtime: airtime.clone(), link: link.clone(),
+                                               topo: topo.clone(), keys: keys.clone(),
+                                               seen: seen.clone() }));
+    }
 }
 
-pub fn init_capture(conf: D3capConf,
-                    pkt_sender: Sender<Pkt>,
-                    pd_sender: Sender<PhysData>) -> CaptureCtx {
+pub fn init_capture(conf: D3capConf, sensor: usize, dissectors: DissectorRegistry) -> CaptureCtx {
     let sess = match conf.file {
         Some(ref f) => cap::PcapSession::from_file(&f),
         None => {
@@ -397,104 +729,201 @@ pub fn init_capture(conf: D3capConf,
         }
     };
 
-    let parser = match sess.datalink() {
-        cap::DLT_ETHERNET => {
-            Box::new(EthernetParser { pkts: pkt_sender }) as Box<PktParser>
-        }
-        cap::DLT_IEEE802_11_RADIO => {
-            Box::new(RadiotapParser { pkts: pkt_sender, phys: pd_sender }) as Box<PktParser>
-        }
-        x => panic!("unsupported datalink type: {}", x)
-    };
+    let link = DissectKey::LinkType(sess.datalink());
+    if !dissectors.has(link) {
+        panic!("unsupported datalink type: {}", sess.datalink());
+    }
 
-    CaptureCtx { sess: sess, parser: parser }
+    CaptureCtx { sess: sess, link: link, sensor: sensor, dissectors: dissectors }
 }
 
-pub fn start_capture<'a>(conf: D3capConf,
-                         pkt_sender: Sender<Pkt>,
-                         pd_sender: Sender<PhysData>) -> io::Result<JoinHandle> {
-    thread::Builder::new().name("packet_capture".to_string()).spawn(move || {
-        let mut cap = init_capture(conf, pkt_sender, pd_sender);
+pub fn start_capture<'a>(conf: D3capConf, sensor: usize,
+                         dissectors: DissectorRegistry) -> io::Result<JoinHandle> {
+    let name = format!("packet_capture_{}", sensor);
+    thread::Builder::new().name(name).spawn(move || {
+        let mut cap = init_capture(conf, sensor, dissectors);
         loop {
             cap.parse_next();
         }
     })
 }
 
-enum LoadMacError {
+enum LoadConfError {
     IOError(io::Error),
     TomlError
 }
-impl FromError<io::Error> for LoadMacError {
-    fn from_error(err: io::Error) -> LoadMacError {
-        LoadMacError::IOError(err)
+impl FromError<io::Error> for LoadConfError {
+    fn from_error(err: io::Error) -> LoadConfError {
+        LoadConfError::IOError(err)
     }
 }
 
-fn load_mac_addrs(file: String) -> Result<HashMap<MacAddr, String>, LoadMacError> {
+fn load_conf(file: &str) -> Result<toml::Table, LoadConfError> {
     let mut s = String::new();
 
-    let mut f = try!(File::open(&file));
+    let mut f = try!(File::open(file));
     try!(f.read_to_string(&mut s));
 
     let mut parser = toml::Parser::new(&s);
-    if let Some(t) = parser.parse() {
-        if let Some(k) = t.get(&"known-macs".to_string()) {
-            if let Some(tbl) = k.as_table() {
-                return Ok(tbl.iter()
-                          .map(|(k,v)| (MacAddr::from_string(&k), v.as_str()))
-                          .filter_map(|x| match x {
-                              (Some(addr), Some(alias)) => Some((addr, alias.to_string())),
-                              _ => None
-                          })
-                          .collect())
-            }
+    match parser.parse() {
+        Some(t) => Ok(t),
+        None => Err(LoadConfError::TomlError)
+    }
+}
+
+fn load_mac_addrs(conf: &toml::Table) -> MacMap {
+    if let Some(k) = conf.get("known-macs") {
+        if let Some(tbl) = k.as_table() {
+            return tbl.iter()
+                .map(|(k,v)| (MacAddr::from_string(&k), v.as_str()))
+                .filter_map(|x| match x {
+                    (Some(addr), Some(alias)) => Some((addr, alias.to_string())),
+                    _ => None
+                })
+                .collect()
         }
     }
-    Err(LoadMacError::TomlError)
+    HashMap::new()
 }
 
-fn start_websocket(port: u16, mac_map: &MacMap, pg_ctl: &ProtoGraphController) -> io::Result<()> {
-    let ui = try!(UIServer::spawn(port, mac_map));
-    pg_ctl.register_mac_listener(ui.create_sender());
-    pg_ctl.register_ip4_listener(ui.create_sender());
-    pg_ctl.register_ip6_listener(ui.create_sender());
+fn start_websocket(port: u16, ctrl: &D3capController) -> io::Result<()> {
+    let ui = try!(UIServer::spawn(port, &ctrl.names.macs));
+    ctrl.pg_ctrl.register_ui(&ui);
+    ctrl.pd_ctrl.register_listener(ui.create_sender());
+    ctrl.dist_ctrl.register_listener(ui.create_sender());
+    ctrl.signal_ctrl.register_listener(ui.create_sender());
+    ctrl.pos_ctrl.register_listener(ui.create_sender());
+    ctrl.nbr_ctrl.register_listener(ui.create_sender());
+    ctrl.auth_ctrl.register_listener(ui.create_sender());
+    ctrl.probe_ctrl.register_listener(ui.create_sender());
+    ctrl.assoc_ctrl.register_listener(ui.create_sender());
+    ctrl.alert_ctrl.register_listener(ui.create_sender());
+    ctrl.airtime_ctrl.register_listener(ui.create_sender());
+    ctrl.link_ctrl.register_listener(ui.create_sender());
+    ctrl.topo_ctrl.register_listener(ui.create_sender());
+    ctrl.presence_ctrl.register_listener(ui.create_sender());
+    ctrl.device_ctrl.register_listener(ui.create_sender());
+    ctrl.ble_ctrl.register_listener(ui.create_sender());
     Ok(())
 }
 
-type MacMap = HashMap<MacAddr, String>;
-type IP4Map = HashMap<IP4Addr, String>;
-type IP6Map = HashMap<IP6Addr, String>;
-
 #[derive(Clone)]
 pub struct D3capController {
     pub pg_ctrl: ProtoGraphController,
     pub pd_ctrl: PhysDataController,
-    pub mac_names: MacMap,
-    pub ip4_names: IP4Map,
-    pub ip6_names: IP6Map,
+    pub dist_ctrl: DistanceController,
+    pub signal_ctrl: SignalController,
+    pub pos_ctrl: PositionController,
+    pub nbr_ctrl: NeighborController,
+    pub auth_ctrl: AuthController,
+    pub ap_ctrl: ApController,
+    pub probe_ctrl: ProbeController,
+    pub device_ctrl: DeviceController,
+    pub assoc_ctrl: AssocController,
+    pub alert_ctrl: AlertController,
+    pub airtime_ctrl: AirtimeController,
+    pub link_ctrl: LinkController,
+    pub topo_ctrl: TopologyController,
+    pub presence_ctrl: PresenceController,
+    pub ble_ctrl: BleController,
+    pub names: AddrNames,
     pub server_started: bool
 }
 
 impl D3capController {
     pub fn spawn(conf: D3capConf) -> io::Result<D3capController> {
-        let mac_names = conf.conf.as_ref()
-            .map(|x| load_mac_addrs(x.to_string()).unwrap_or_else(|_| HashMap::new()))
-            .unwrap_or_else(HashMap::new);
-        let ip4_names = HashMap::new();
-        let ip6_names = HashMap::new();
+        let conf_tbl = conf.conf.as_ref()
+            .and_then(|x| load_conf(x).ok())
+            .unwrap_or_else(BTreeMap::new);
+
+        let mut pg_ctrl = ProtoGraphController::new();
+        let mac = try!(pg_ctrl.add::<MacAddr>("mac"));
+        let ip4 = try!(pg_ctrl.add::<IP4Addr>("ip4"));
+        let ip6 = try!(pg_ctrl.add::<IP6Addr>("ip6"));
+        let ble_graph = try!(pg_ctrl.add::<MacAddr>("ble"));
+
+        let pos_ctrl = try!(PositionController::spawn(sensors_from_toml(&conf_tbl)));
+        let ranges = if pos_ctrl.sensors.len() >= 3 { Some(pos_ctrl.sender()) } else { None };
+        let dist_ctrl = try!(DistanceController::spawn(DistanceConf::from_toml(&conf_tbl), ranges));
+        let signal_ctrl = try!(SignalController::spawn(SignalConf::from_toml(&conf_tbl)));
+        let known_macs = load_mac_addrs(&conf_tbl);
+        let presence_ctrl = try!(PresenceController::spawn(PresenceConf::from_toml(&conf_tbl),
+                                                           known_macs.clone()));
+        let pd_ctrl = try!(PhysDataController::spawn(dist_ctrl.sender(), signal_ctrl.sender(),
+                                                     presence_ctrl.sender()));
+        let ble_ctrl = try!(BleController::spawn(dist_ctrl.sender()));
+        let airtime_ctrl = try!(AirtimeController::spawn());
+        let link_ctrl = try!(LinkController::spawn());
+        let nbr_ctrl = try!(NeighborController::spawn());
+        let auth_ctrl = try!(AuthController::spawn());
+        let device_ctrl = try!(DeviceController::spawn());
+        let probe_ctrl = try!(ProbeController::spawn(device_ctrl.sender()));
+        let alert_ctrl = try!(AlertController::spawn());
+        let ap_ctrl = try!(ApController::spawn(networks_from_toml(&conf_tbl), alert_ctrl.sender()));
+        let assoc_ctrl = try!(AssocController::spawn(FloodConf::from_toml(&conf_tbl),
+                                                     alert_ctrl.sender()));
+        let topo_ctrl = try!(TopologyController::spawn(ap_ctrl.map.clone(), probe_ctrl.map.clone(),
+                                                       assoc_ctrl.map.clone()));
+
+        let keys = Arc::new(RwLock::new(Keyring::from_toml(&conf_tbl)));
+        let tunnel_conf = TunnelConf::from_toml(&conf_tbl);
+
+        {
+            //one frame heard by several sensors is only counted once
+            let seen = if pos_ctrl.sensors.len() > 1 { Some(SeenFrames::new()) } else { None };
+            //each capture thread gets its own
+            let dissectors = || {
+                let mut reg = DissectorRegistry::new();
+                ether::register_dissectors(&mut reg, mac.clone(), presence_ctrl.sender());
+                register_dissectors(&mut reg, mac.clone(), pd_ctrl.sender(), airtime_ctrl.sender(),
+                                    link_ctrl.sender(), topo_ctrl.sender(), keys.clone(),
+                                    seen.clone());
+                ip::register_dissectors(&mut reg, ip4.clone(), ip6.clone());
+                tunnel::register_dissectors(&mut reg, &tunnel_conf);
+                neighbor::register_dissectors(&mut reg, nbr_ctrl.sender());
+                eapol::register_dissectors(&mut reg, auth_ctrl.sender(), keys.clone());
+                ap::register_dissectors(&mut reg, ap_ctrl.sender());
+                probe::register_dissectors(&mut reg, probe_ctrl.sender());
+                assoc::register_dissectors(&mut reg, assoc_ctrl.sender());
+                ble::register_dissectors(&mut reg, ble_graph.clone(), ble_ctrl.sender(),
+                                         seen.clone());
+                reg
+            };
 
-        let pg_ctrl = try!(ProtoGraphController::spawn());
-        let pd_ctrl = try!(PhysDataController::spawn());
+            //configured sensors replace the interface or file on the command line
+            if pos_ctrl.sensors.is_empty() {
+                start_capture(conf, 0, dissectors()).unwrap();
+            } else {
+                for (i, s) in pos_ctrl.sensors.iter().enumerate() {
+                    let mut sconf = conf.clone();
+                    sconf.interface = s.interface.clone();
+                    sconf.file = s.file.clone();
+                    start_capture(sconf, i, dissectors()).unwrap();
+                }
+            }
+        }
 
-        start_capture(conf, pg_ctrl.sender(), pd_ctrl.sender()).unwrap();
+        let names = AddrNames::new(known_macs, nbr_ctrl.map.clone());
 
         Ok(D3capController {
             pg_ctrl: pg_ctrl,
             pd_ctrl: pd_ctrl,
-            mac_names: mac_names,
-            ip4_names: ip4_names,
-            ip6_names: ip6_names,
+            dist_ctrl: dist_ctrl,
+            signal_ctrl: signal_ctrl,
+            pos_ctrl: pos_ctrl,
+            nbr_ctrl: nbr_ctrl,
+            auth_ctrl: auth_ctrl,
+            ap_ctrl: ap_ctrl,
+            probe_ctrl: probe_ctrl,
+            device_ctrl: device_ctrl,
+            assoc_ctrl: assoc_ctrl,
+            alert_ctrl: alert_ctrl,
+            airtime_ctrl: airtime_ctrl,
+            link_ctrl: link_ctrl,
+            topo_ctrl: topo_ctrl,
+            presence_ctrl: presence_ctrl,
+            ble_ctrl: ble_ctrl,
+            names: names,
             server_started: false
         })
     }
@@ -503,7 +932,7 @@ impl D3capController {
         if self.server_started {
             println!("server already started");
         } else {
-            try!(start_websocket(port, &self.mac_names, &self.pg_ctrl));
+            try!(start_websocket(port, self));
             self.server_started = true;
         }
         Ok(())
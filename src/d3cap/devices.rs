@@ -0,0 +1,195 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use multicast::Multicast;
+
+use ether::MacAddr;
+use dot11::seq_distance;
+
+// Phones probe from a fresh random address every so often, so one phone
+// shows up as dozens of clients.  What usually survives the change is the
+// shape of its probes (ie::fingerprint) and its sequence counter, which many
+// chipsets don't reset; failing that, a new address with the same
+// fingerprint appearing right as the old one goes quiet is a decent guess.
+
+/// Set on addresses that weren't assigned by the manufacturer, which is
+/// what random ones are.
+pub fn is_locally_administered(mac: &MacAddr) -> bool {
+    !mac.is_multicast() && mac.as_bytes()[0] & 0x02 != 0
+}
+
+pub struct ProbeSighting {
+    pub mac: MacAddr,
+    /// Capture time.
+    pub time: i64,
+    pub seq: u16,
+    pub fingerprint: u64,
+    pub ssid: Option<String>,
+}
+
+/// The sequence counter moves on with every frame, on every channel, so
+/// allow for the ones we missed while listening elsewhere.
+const SEQ_MAX_GAP: u16 = 128;
+/// How long a device can go quiet and still be picked up by sequence number.
+const SEQ_MAX_SECS: i64 = 300;
+/// Without sequence continuity, a new address has to show up this soon
+/// after the old one's last probe.
+const TIMING_MAX_SECS: i64 = 60;
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct Device {
+    pub id: usize,
+    pub fingerprint: String,
+    pub randomized: bool,
+    /// Every address it's used, oldest first.
+    pub macs: Vec<MacAddr>,
+    pub ssids: Vec<String>,
+    pub probes: u32,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// How the latest address was tied to the earlier ones: "seq", "timing",
+    /// or None if it hasn't changed.
+    pub linked_by: Option<&'static str>,
+    last_seq: u16,
+    fp: u64,
+}
+
+impl Device {
+    fn new(id: usize, s: &ProbeSighting) -> Device {
+        Device {
+            id: id,
+            fingerprint: format!("{:016x}", s.fingerprint),
+            randomized: is_locally_administered(&s.mac),
+            macs: vec![s.mac],
+            ssids: Vec::new(),
+            probes: 0,
+            first_seen: s.time,
+            last_seen: s.time,
+            linked_by: None,
+            last_seq: s.seq,
+            fp: s.fingerprint
+        }
+    }
+
+    fn update(&mut self, s: &ProbeSighting) {
+        self.probes += 1;
+        if s.time >= self.last_seen {
+            self.last_seen = s.time;
+            self.last_seq = s.seq;
+        }
+        if let Some(ref ssid) = s.ssid {
+            if !self.ssids.contains(ssid) {
+                self.ssids.push(ssid.clone());
+            }
+        }
+    }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct DeviceMsg {
+    typ: &'static str,
+    device: Device,
+}
+
+#[derive(Clone)]
+pub struct DeviceController {
+    pub devices: Arc<RwLock<Vec<Device>>>,
+    /// Index into devices for each address.
+    pub macs: Arc<RwLock<HashMap<MacAddr, usize>>>,
+    probe_tx: Sender<ProbeSighting>,
+    mcast: Multicast<DeviceMsg>,
+}
+
+impl DeviceController {
+    pub fn spawn() -> io::Result<DeviceController> {
+        let (probe_tx, probe_rx) = channel();
+        let out = DeviceController {
+            devices: Arc::new(RwLock::new(Vec::new())),
+            macs: Arc::new(RwLock::new(HashMap::new())),
+            probe_tx: probe_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("device_handler".to_string()).spawn(move || {
+            loop {
+                let res = probe_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let s: ProbeSighting = res.unwrap();
+
+                let mut devices = ctl.devices.write().unwrap();
+                let mut macs = ctl.macs.write().unwrap();
+                let (idx, new_mac) = match macs.entry(s.mac) {
+                    Entry::Occupied(e) => (*e.get(), false),
+                    Entry::Vacant(e) => {
+                        let idx = match find_previous(&devices, &s) {
+                            Some((i, how)) => {
+                                let d = &mut devices[i];
+                                d.macs.push(s.mac);
+                                d.linked_by = Some(how);
+                                i
+                            }
+                            None => {
+                                let i = devices.len();
+                                devices.push(Device::new(i, &s));
+                                i
+                            }
+                        };
+                        (*e.insert(idx), true)
+                    }
+                };
+                let d = &mut devices[idx];
+                let was_probes = d.probes;
+                d.update(&s);
+                //new addresses, and now and then otherwise
+                if new_mac || was_probes % 20 == 0 {
+                    ctl.mcast.send(Arc::new(DeviceMsg { typ: "device", device: d.clone() }));
+                }
+            }
+        }));
+
+        Ok(out)
+    }
+
+    pub fn sender(&self) -> Sender<ProbeSighting> {
+        self.probe_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<DeviceMsg>>) {
+        self.mcast.register(s);
+    }
+}
+
+/// Which known device a new random address most likely belongs to, and why.
+fn find_previous(devices: &[Device], s: &ProbeSighting) -> Option<(usize, &'static str)> {
+    if !is_locally_administered(&s.mac) {
+        return None;
+    }
+    let candidates: Vec<_> = devices.iter()
+        .filter(|d| d.randomized && d.fp == s.fingerprint && d.last_seen <= s.time)
+        .collect();
+
+    //closest sequence number that's plausibly just after theirs
+    let by_seq = candidates.iter()
+        .filter(|d| s.time - d.last_seen <= SEQ_MAX_SECS)
+        .map(|d| (d, seq_distance(d.last_seq, s.seq)))
+        .filter(|&(_, gap)| gap > 0 && gap <= SEQ_MAX_GAP)
+        .min_by(|&(_, gap)| gap);
+    if let Some((d, _)) = by_seq {
+        return Some((d.id, "seq"));
+    }
+
+    //only when there's no doubt which one it was
+    let recent: Vec<_> = candidates.iter()
+        .filter(|d| s.time - d.last_seen <= TIMING_MAX_SECS)
+        .collect();
+    if recent.len() == 1 {
+        return Some((recent[0].id, "timing"));
+    }
+    None
+}
@@ -0,0 +1,261 @@
+use std::collections::hash_map::HashMap;
+use std::error::FromError;
+use std::hash::{Hash, Hasher, SipHasher};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, SendError};
+
+use time;
+
+use ether::MacAddr;
+use pkt_graph::PktMeta;
+use tunnel::{Decap, GraphMode, TunnelTag};
+use pcap::rustpcap as cap;
+
+// Packets are taken apart by a chain of dissectors, each of which handles one
+// protocol and hands whatever it's carrying off to the next layer by looking
+// it up in the registry: link type -> ethertype -> ip protocol -> port.  To
+// add a protocol, write a Dissector and register it under the key the layer
+// below it will use; nothing else needs to know about it.
+
+pub enum ParseErr {
+    Send,
+    UnknownPacket
+}
+
+impl<T> FromError<SendError<T>> for ParseErr {
+    fn from_error(_: SendError<T>) -> ParseErr {
+        ParseErr::Send
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum DissectKey {
+    LinkType(cap::DataLinkType),
+    EtherType(u16), //same byte order as EthernetHeader.typ
+    Snap([u8; 3], u16), //for SNAP OUIs other than plain ethertype encapsulation
+    IpProto(u8),
+    UdpPort(u16),
+    TcpPort(u16),
+    Dot11Mgmt(u8), //management frame subtype; data starts at the 802.11 header
+}
+
+pub trait Dissector: Send {
+    fn dissect(&self, reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr>;
+}
+
+pub struct DissectorRegistry {
+    dissectors: HashMap<DissectKey, Box<Dissector+'static>>
+}
+
+impl DissectorRegistry {
+    pub fn new() -> DissectorRegistry {
+        DissectorRegistry { dissectors: HashMap::new() }
+    }
+
+    /// Registering a second dissector under the same key replaces the first.
+    pub fn register(&mut self, key: DissectKey, d: Box<Dissector+'static>) {
+        self.dissectors.insert(key, d);
+    }
+
+    pub fn has(&self, key: DissectKey) -> bool {
+        self.dissectors.contains_key(&key)
+    }
+
+    /// Hand data off to whatever is registered for key.  Nothing being
+    /// registered isn't an error, it just means we don't care about it.
+    pub fn dispatch(&self, key: DissectKey, ctx: &mut DissectCtx, data: &[u8])
+                    -> Result<(), ParseErr> {
+        match self.dissectors.get(&key) {
+            Some(d) => d.dissect(self, ctx, data),
+            None => Ok(())
+        }
+    }
+}
+
+/// The sending half of a graph, for dissectors to report the endpoints they
+/// find at their layer.
+pub struct GraphSender<T> {
+    tx: Sender<PktMeta<T>>
+}
+
+impl<T> GraphSender<T> {
+    pub fn new(tx: Sender<PktMeta<T>>) -> GraphSender<T> {
+        GraphSender { tx: tx }
+    }
+}
+
+impl<T> Clone for GraphSender<T> {
+    fn clone(&self) -> GraphSender<T> {
+        GraphSender { tx: self.tx.clone() }
+    }
+}
+
+trait PendingUpdate: Send {
+    fn send(self: Box<Self>) -> Result<(), ParseErr>;
+}
+
+struct Pending<T> {
+    tx: Sender<PktMeta<T>>,
+    pkt: PktMeta<T>
+}
+
+impl<T: Send+'static> PendingUpdate for Pending<T> {
+    fn send(self: Box<Self>) -> Result<(), ParseErr> {
+        let p = *self;
+        try!(p.tx.send(p.pkt));
+        Ok(())
+    }
+}
+
+/// Where we are in a (possibly tunneled) packet: which tunnel we're inside
+/// of, where in the pending updates this layer's start, and how deep we are.
+#[derive(Copy, Clone)]
+pub struct Encap {
+    pub tag: Option<TunnelTag>,
+    pub start: usize,
+    pub depth: u8
+}
+
+//Don't let somebody stack tunnels until we blow the stack.
+const MAX_ENCAP_DEPTH: u8 = 8;
+
+/// What the radio told us about a frame, for dissectors above 802.11.
+#[derive(Copy, Clone, Debug)]
+pub struct Radio {
+    pub mhz: Option<u16>,
+    pub signal: Option<i8>,
+}
+
+//A frame from another sensor this close in capture time is the same frame.
+const SEEN_WINDOW_SECS: i64 = 1;
+//Clear out old frames once there are this many.
+const SEEN_MAX: usize = 10000;
+
+/// With several sensors in earshot of each other, most frames are captured
+/// more than once.  Whatever counts frames (graphs, airtime, link stats)
+/// should only count the first of them; the rest only say how loud the
+/// frame was somewhere else.  Shared by all the capture threads.
+#[derive(Clone)]
+pub struct SeenFrames {
+    map: Arc<Mutex<HashMap<u64, (usize, i64)>>>,
+}
+
+impl SeenFrames {
+    pub fn new() -> SeenFrames {
+        SeenFrames { map: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Whether another sensor has already passed this frame along.  If not,
+    /// it's remembered as this sensor's.
+    pub fn repeat(&self, sensor: usize, time: i64, frame: &[u8]) -> bool {
+        let mut h = SipHasher::new();
+        frame.hash(&mut h);
+        let key = h.finish();
+
+        let mut map = self.map.lock().unwrap();
+        let repeat = match map.get(&key) {
+            Some(&(s, t)) => s != sensor && (time - t).abs() <= SEEN_WINDOW_SECS,
+            None => false
+        };
+        if !repeat {
+            map.insert(key, (sensor, time));
+        }
+        if map.len() > SEEN_MAX {
+            //files from different sensors don't read in step, so old can be
+            //either side of this one
+            let old: Vec<u64> = map.iter()
+                .filter(|&(_, &(_, t))| (time - t).abs() > SEEN_WINDOW_SECS)
+                .map(|(&k, _)| k)
+                .collect();
+            for k in old.iter() {
+                map.remove(k);
+            }
+            //more than that in a second; missing a few repeats beats
+            //scanning all of them on every frame
+            if map.len() > SEEN_MAX {
+                map.clear();
+            }
+        }
+        repeat
+    }
+}
+
+/// Per-packet state threaded through the dissectors.
+pub struct DissectCtx {
+    /// Length of the whole packet on the wire.
+    pub len: u32,
+    pub encap: Encap,
+    /// Link-layer addresses of the innermost frame seen so far.
+    pub src_mac: Option<MacAddr>,
+    pub dst_mac: Option<MacAddr>,
+    pub wireless: bool,
+    pub radio: Option<Radio>,
+    /// Which capture this came from, and when it was captured.
+    pub sensor: usize,
+    pub time: i64,
+    updates: Vec<Box<PendingUpdate+'static>>,
+}
+
+impl DissectCtx {
+    pub fn new(pkt: &cap::PcapData) -> DissectCtx {
+        DissectCtx {
+            len: pkt.len(),
+            encap: Encap { tag: None, start: 0, depth: 0 },
+            src_mac: None,
+            dst_mac: None,
+            wireless: false,
+            radio: None,
+            sensor: 0,
+            time: pkt.ts().sec(),
+            updates: Vec::new()
+        }
+    }
+
+    /// Queue up a graph update.  Updates aren't sent until the whole packet
+    /// has been dissected, since finding a tunnel further in can mean the
+    /// outer endpoints shouldn't be graphed at all.
+    pub fn emit<T: Send+'static>(&mut self, graph: &GraphSender<T>, src: T, dst: T, size: u32) {
+        let pkt = PktMeta::tagged(src, dst, size, time::Timespec::new(self.time, 0),
+                                  self.encap.tag);
+        self.updates.push(Box::new(Pending { tx: graph.tx.clone(), pkt: pkt }));
+    }
+
+    /// Dissect the inside of a tunnel, graphing the outer hosts, the inner
+    /// hosts, or both according to mode.
+    pub fn decap(&mut self, reg: &DissectorRegistry, d: Decap, mode: GraphMode)
+                 -> Result<(), ParseErr> {
+        if self.encap.depth >= MAX_ENCAP_DEPTH {
+            return Ok(());
+        }
+        match mode {
+            GraphMode::Outer => return Ok(()),
+            GraphMode::Inner => self.updates.truncate(self.encap.start),
+            GraphMode::Both => {}
+        }
+        let outer = self.encap;
+        self.encap = Encap { tag: Some(d.tag), start: self.updates.len(), depth: outer.depth + 1 };
+        let res = reg.dispatch(DissectKey::EtherType(d.typ), self, d.payload);
+        self.encap = outer;
+        res
+    }
+
+    pub fn flush(self) -> Result<(), ParseErr> {
+        for u in self.updates.into_iter() {
+            try!(u.send());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_seen_frames() {
+    let seen = SeenFrames::new();
+    let frame = [0x80, 0, 0, 0, 1, 2, 3];
+    assert!(!seen.repeat(0, 100, &frame));
+    //the same sensor hearing it again is a new frame, another sensor isn't
+    assert!(!seen.repeat(0, 100, &frame));
+    assert!(seen.repeat(1, 101, &frame));
+    //but not long after
+    assert!(!seen.repeat(1, 105, &frame));
+}
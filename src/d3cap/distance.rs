@@ -0,0 +1,418 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::num::Float;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use time;
+use toml;
+
+use multicast::Multicast;
+use fixed_ring::FixedRingBuffer;
+
+use ether::MacAddr;
+use position::RangeSample;
+
+// How far away each transmitter is, from how loud it is.  A propagation
+// model turns received signal into distance given the signal at 1m, which
+// comes from calibration if we have it, or else an assumed transmit power
+// less the model's loss over the first meter.  Signal bounces around a lot,
+// so it's smoothed before converting, and the spread that's left gives the
+// error bars.
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PathLoss {
+    FreeSpace,
+    LogDistance(f32),
+}
+
+impl PathLoss {
+    pub fn exponent(&self) -> f32 {
+        match *self {
+            PathLoss::FreeSpace => 2.0,
+            PathLoss::LogDistance(n) => n
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Smoothing {
+    /// Mean of the last n samples.
+    Window(usize),
+    /// Exponentially weighted, with this weight on each new sample.
+    Ewma(f32),
+    /// One-dimensional Kalman filter with this process and measurement noise
+    /// (dB^2): how fast the true signal drifts, and how noisy readings are.
+    Kalman(f32, f32),
+}
+
+impl Smoothing {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Smoothing::Window(_) => "window",
+            Smoothing::Ewma(_) => "ewma",
+            Smoothing::Kalman(_, _) => "kalman"
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DistanceConf {
+    pub model: PathLoss,
+    /// dBm, for transmitters we haven't calibrated and that don't advertise it.
+    pub tx_power: f32,
+    /// Loss at 1m in the 2.4, 5 and 6GHz bands, if not free space.
+    pub ref_loss: [Option<f32>; 3],
+    /// Measured signal at 1m for known transmitters.
+    pub calibration: HashMap<MacAddr, f32>,
+    pub smoothing: Smoothing,
+}
+
+fn band(mhz: u16) -> usize {
+    match mhz {
+        0...2500 => 0,
+        2501...5925 => 1,
+        _ => 2
+    }
+}
+
+fn num(v: &toml::Value) -> Option<f32> {
+    v.as_float().map(|f| f as f32).or(v.as_integer().map(|i| i as f32))
+}
+
+impl DistanceConf {
+    pub fn new() -> DistanceConf {
+        DistanceConf {
+            model: PathLoss::LogDistance(3.0),
+            tx_power: 20.0,
+            ref_loss: [None; 3],
+            calibration: HashMap::new(),
+            smoothing: Smoothing::Window(10)
+        }
+    }
+
+    /// Reads the [distance] table, e.g.:
+    ///
+    ///     [distance]
+    ///     model = "log-distance"
+    ///     exponent = 3.0
+    ///     tx-power = 20
+    ///     ref-loss-2ghz = 40.0
+    ///     smoothing = "kalman"
+    ///     kalman-q = 0.5
+    ///     kalman-r = 9.0
+    ///
+    ///     [distance.calibration]
+    ///     "00:11:22:33:44:55" = -38
+    ///
+    /// Anything missing or unparseable keeps its default.
+    pub fn from_toml(conf: &toml::Table) -> DistanceConf {
+        let mut out = DistanceConf::new();
+        let tbl = match conf.get("distance").and_then(|t| t.as_table()) {
+            Some(t) => t,
+            None => return out
+        };
+        let get = |key: &str| tbl.get(key).and_then(num);
+
+        let exponent = match get("exponent") {
+            Some(n) if n > 0.0 => n,
+            Some(n) => {
+                println!("exponent {} isn't positive, using {}", n, out.model.exponent());
+                out.model.exponent()
+            }
+            None => out.model.exponent()
+        };
+        out.model = match tbl.get("model").and_then(|v| v.as_str()) {
+            Some("free-space") => PathLoss::FreeSpace,
+            Some("log-distance") | None => PathLoss::LogDistance(exponent),
+            Some(m) => {
+                println!("Unknown distance model {}, using log-distance", m);
+                PathLoss::LogDistance(exponent)
+            }
+        };
+        out.tx_power = get("tx-power").unwrap_or(out.tx_power);
+        out.ref_loss = [get("ref-loss-2ghz"), get("ref-loss-5ghz"), get("ref-loss-6ghz")];
+
+        out.smoothing = match tbl.get("smoothing").and_then(|v| v.as_str()) {
+            Some("ewma") => Smoothing::Ewma(match get("ewma-alpha") {
+                Some(a) if a > 0.0 && a <= 1.0 => a,
+                Some(a) => {
+                    println!("ewma-alpha {} isn't in (0, 1], using 0.2", a);
+                    0.2
+                }
+                None => 0.2
+            }),
+            Some("kalman") => Smoothing::Kalman(match get("kalman-q") {
+                Some(q) if q >= 0.0 => q,
+                Some(q) => {
+                    println!("kalman-q {} is negative, using 0.5", q);
+                    0.5
+                }
+                None => 0.5
+            }, match get("kalman-r") {
+                Some(r) if r > 0.0 => r,
+                Some(r) => {
+                    println!("kalman-r {} isn't positive, using 9.0", r);
+                    9.0
+                }
+                None => 9.0
+            }),
+            _ => Smoothing::Window(match get("window") {
+                Some(w) if w >= 1.0 => w as usize,
+                Some(w) => {
+                    println!("window {} is less than 1, using 10", w);
+                    10
+                }
+                None => 10
+            })
+        };
+
+        if let Some(cal) = tbl.get("calibration").and_then(|t| t.as_table()) {
+            out.calibration = cal.iter()
+                .filter_map(|(k, v)| match (MacAddr::from_string(k), num(v)) {
+                    (Some(mac), Some(dbm)) => Some((mac, dbm)),
+                    _ => None
+                })
+                .collect();
+        }
+        out
+    }
+
+    /// Expected signal at 1m from this transmitter, which may have said what
+    /// power it transmits at.
+    fn signal_at_1m(&self, mac: &MacAddr, mhz: u16, tx_power: Option<f32>) -> f32 {
+        if let Some(dbm) = self.calibration.get(mac) {
+            return *dbm;
+        }
+        let loss = match (self.model, self.ref_loss[band(mhz)]) {
+            (PathLoss::LogDistance(_), Some(l)) => l,
+            _ => 20.0 * (mhz as f32).log10() - 27.55 //free space at 1m
+        };
+        tx_power.unwrap_or(self.tx_power) - loss
+    }
+
+    pub fn meters(&self, mac: &MacAddr, mhz: u16, tx_power: Option<f32>, dbm: f32) -> f32 {
+        let exp = (self.signal_at_1m(mac, mhz, tx_power) - dbm) / (10.0 * self.model.exponent());
+        10.0f32.powf(exp)
+    }
+}
+
+/// Smoothed signal in dBm, and how far off it might be.
+pub enum SignalFilter {
+    Window(FixedRingBuffer<f32>),
+    Ewma { alpha: f32, mean: f32, var: f32, n: u32 },
+    Kalman { q: f32, r: f32, x: f32, p: f32, n: u32 },
+}
+
+impl SignalFilter {
+    pub fn new(s: Smoothing) -> SignalFilter {
+        match s {
+            Smoothing::Window(n) => SignalFilter::Window(FixedRingBuffer::new(n)),
+            Smoothing::Ewma(a) => SignalFilter::Ewma { alpha: a, mean: 0.0, var: 0.0, n: 0 },
+            Smoothing::Kalman(q, r) => SignalFilter::Kalman { q: q, r: r, x: 0.0, p: r, n: 0 }
+        }
+    }
+
+    pub fn add(&mut self, dbm: f32) {
+        match *self {
+            SignalFilter::Window(ref mut buf) => buf.push(dbm),
+            SignalFilter::Ewma { alpha, ref mut mean, ref mut var, ref mut n } => {
+                if *n == 0 {
+                    *mean = dbm;
+                } else {
+                    let d = dbm - *mean;
+                    *mean += alpha * d;
+                    *var = (1.0 - alpha) * (*var + alpha * d * d);
+                }
+                *n += 1;
+            }
+            SignalFilter::Kalman { q, r, ref mut x, ref mut p, ref mut n } => {
+                if *n == 0 {
+                    *x = dbm;
+                    *p = r;
+                } else {
+                    *p += q;
+                    let k = *p / (*p + r);
+                    *x += k * (dbm - *x);
+                    *p *= 1.0 - k;
+                }
+                *n += 1;
+            }
+        }
+    }
+
+    /// (mean, standard deviation), once there's anything to go on.
+    pub fn estimate(&self) -> Option<(f32, f32)> {
+        match *self {
+            SignalFilter::Window(ref buf) => {
+                if buf.is_empty() {
+                    return None;
+                }
+                let n = buf.len() as f32;
+                let mean = buf.iter().fold(0.0, |s, x| s + *x) / n;
+                let var = buf.iter().fold(0.0, |s, x| s + (*x - mean) * (*x - mean)) / n;
+                Some((mean, var.sqrt()))
+            }
+            SignalFilter::Ewma { mean, var, n, .. } => {
+                if n == 0 { None } else { Some((mean, var.sqrt())) }
+            }
+            SignalFilter::Kalman { x, p, n, .. } => {
+                if n == 0 { None } else { Some((x, p.sqrt())) }
+            }
+        }
+    }
+}
+
+#[derive(RustcEncodable, Copy, Clone, Debug)]
+pub struct DistanceEstimate {
+    pub meters: f32,
+    /// One standard deviation of signal either way.
+    pub low: f32,
+    pub high: f32,
+    pub signal: f32,
+    pub samples: u32,
+}
+
+pub struct StationDistance {
+    pub mhz: u16,
+    pub filter: SignalFilter,
+    pub samples: u32,
+    pub estimate: Option<DistanceEstimate>,
+    last_sent: i64,
+}
+
+pub struct SignalReading {
+    pub sensor: usize,
+    /// Capture time.
+    pub time: i64,
+    pub mac: MacAddr,
+    pub mhz: u16,
+    pub dbm: i8,
+    /// dBm, if the transmitter advertises it.
+    pub tx_power: Option<i8>,
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct DistanceMsg {
+    typ: &'static str,
+    sensor: usize,
+    mac: MacAddr,
+    model: &'static str,
+    smoothing: &'static str,
+    calibrated: bool,
+    estimate: DistanceEstimate,
+}
+
+const DISTANCE_MSG_INTERVAL_SECS: i64 = 2;
+
+#[derive(Clone)]
+pub struct DistanceController {
+    pub conf: Arc<DistanceConf>,
+    /// Keyed by sensor and transmitter.
+    pub map: Arc<RwLock<HashMap<(usize, MacAddr), StationDistance>>>,
+    reading_tx: Sender<SignalReading>,
+    mcast: Multicast<DistanceMsg>,
+}
+
+impl DistanceController {
+    /// Fresh estimates are passed on to ranges, if there's anything to
+    /// trilaterate with.
+    pub fn spawn(conf: DistanceConf, ranges: Option<Sender<RangeSample>>)
+                 -> io::Result<DistanceController> {
+        let (reading_tx, reading_rx) = channel();
+        let out = DistanceController {
+            conf: Arc::new(conf),
+            map: Arc::new(RwLock::new(HashMap::new())),
+            reading_tx: reading_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("distance_handler".to_string()).spawn(move || {
+            loop {
+                let res = reading_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let r: SignalReading = res.unwrap();
+                let now = time::get_time().sec;
+                let conf = &*ctl.conf;
+
+                let mut map = ctl.map.write().unwrap();
+                let sd = match map.entry((r.sensor, r.mac)) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(StationDistance {
+                        mhz: r.mhz,
+                        filter: SignalFilter::new(conf.smoothing),
+                        samples: 0,
+                        estimate: None,
+                        last_sent: 0
+                    })
+                };
+                //a different band is a different loss, start over
+                if band(r.mhz) != band(sd.mhz) {
+                    sd.filter = SignalFilter::new(conf.smoothing);
+                    sd.samples = 0;
+                }
+                sd.mhz = r.mhz;
+                sd.filter.add(r.dbm as f32);
+                sd.samples += 1;
+                let tx = r.tx_power.map(|p| p as f32);
+                sd.estimate = sd.filter.estimate().map(|(mean, sd_db)| DistanceEstimate {
+                    meters: conf.meters(&r.mac, r.mhz, tx, mean),
+                    low: conf.meters(&r.mac, r.mhz, tx, mean + sd_db),
+                    high: conf.meters(&r.mac, r.mhz, tx, mean - sd_db),
+                    signal: mean,
+                    samples: sd.samples
+                });
+
+                if let (Some(est), Some(tx)) = (sd.estimate, ranges.as_ref()) {
+                    let range = RangeSample { sensor: r.sensor, mac: r.mac, time: r.time, estimate: est };
+                    if tx.send(range).is_err() {
+                        break
+                    }
+                }
+                if let Some(est) = sd.estimate {
+                    if now - sd.last_sent >= DISTANCE_MSG_INTERVAL_SECS {
+                        sd.last_sent = now;
+                        ctl.mcast.send(Arc::new(DistanceMsg {
+                            typ: "distance",
+                            sensor: r.sensor,
+                            mac: r.mac,
+                            model: match conf.model {
+                                PathLoss::FreeSpace => "free-space",
+                                PathLoss::LogDistance(_) => "log-distance"
+                            },
+                            smoothing: conf.smoothing.name(),
+                            calibrated: conf.calibration.contains_key(&r.mac),
+                            estimate: est
+                        }));
+                    }
+                }
+            }
+        }));
+
+        Ok(out)
+    }
+
+    /// From whichever sensor is closest.
+    pub fn estimate(&self, mac: &MacAddr) -> Option<DistanceEstimate> {
+        let map = self.map.read().unwrap();
+        map.iter()
+            .filter(|&(&(_, m), _)| m == *mac)
+            .filter_map(|(_, sd)| sd.estimate)
+            .fold(None, |best: Option<DistanceEstimate>, e| match best {
+                Some(b) if b.meters <= e.meters => Some(b),
+                _ => Some(e)
+            })
+    }
+
+    pub fn sender(&self) -> Sender<SignalReading> {
+        self.reading_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<DistanceMsg>>) {
+        self.mcast.register(s);
+    }
+}
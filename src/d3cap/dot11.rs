@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use ether::{MacAddr};
+use util::{checked_cast, le_u16};
 use std::fmt::{Debug, Formatter, Error};
 
 // For possible reference:
@@ -72,6 +73,15 @@ pub struct DurationID {
     dur_id: u16
 }
 
+impl DurationID {
+    /// Microseconds the medium is reserved for after this frame (the NAV),
+    /// or None when the field holds an association ID instead.
+    pub fn duration_us(&self) -> Option<u16> {
+        let d = u16::from_le(self.dur_id);
+        if d & 0x8000 == 0 { Some(d) } else { None }
+    }
+}
+
 #[derive(Debug)]
 #[repr(packed)]
 pub struct Dot11BaseHeader {
@@ -110,6 +120,67 @@ type FCS = [u8; 4];
 
 // 8.3.1 Control Frames
 
+// 8.2.4.1.3 Type and Subtype fields, for control frames
+pub const CTRL_WRAPPER: u8    = 7;
+pub const CTRL_BAR: u8        = 8;
+pub const CTRL_BA: u8         = 9;
+pub const CTRL_PS_POLL: u8    = 10;
+pub const CTRL_RTS: u8        = 11;
+pub const CTRL_CTS: u8        = 12;
+pub const CTRL_ACK: u8        = 13;
+pub const CTRL_CF_END: u8     = 14;
+pub const CTRL_CF_END_ACK: u8 = 15;
+
+/// The addresses out of any control frame.  Every one of them starts with
+/// the RA; the ones that name a transmitter put it right after.
+#[derive(Copy, Clone, Debug)]
+pub struct ControlFrame {
+    pub subtype: u8,
+    pub ra: MacAddr,
+    pub ta: Option<MacAddr>,
+}
+
+impl ControlFrame {
+    pub fn parse(frame: &[u8]) -> Option<ControlFrame> {
+        let base: &Dot11BaseHeader = match checked_cast(frame) {
+            Some(h) => h,
+            None => return None
+        };
+        let subtype = base.fr_ctrl.frame_subtype();
+        if frame.len() < 10 {
+            return None;
+        }
+        let has_ta = match subtype {
+            CTRL_BAR | CTRL_BA | CTRL_PS_POLL | CTRL_RTS | CTRL_CF_END | CTRL_CF_END_ACK => true,
+            _ => false
+        };
+        Some(ControlFrame {
+            subtype: subtype,
+            ra: MacAddr::from_bytes(&frame[4..10]),
+            ta: if has_ta && frame.len() >= 16 {
+                Some(MacAddr::from_bytes(&frame[10..16]))
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self.subtype {
+            CTRL_WRAPPER => "wrapper",
+            CTRL_BAR => "block-ack-req",
+            CTRL_BA => "block-ack",
+            CTRL_PS_POLL => "ps-poll",
+            CTRL_RTS => "rts",
+            CTRL_CTS => "cts",
+            CTRL_ACK => "ack",
+            CTRL_CF_END => "cf-end",
+            CTRL_CF_END_ACK => "cf-end-ack",
+            _ => "unknown"
+        }
+    }
+}
+
 // 8.3.1.2 RTS
 #[repr(packed)]
 pub struct RTS {
@@ -208,35 +279,174 @@ pub struct DataFrameHeader {
 // | 1     | 0       | RA = BSSID | TA = SA    | DA    | BSSID  | N/A  | N/A    |
 // | 1     | 1       | RA         | TA         | DA    | BSSID  | SA   | BSSID  |
 
+// Data subtypes with bit 3 set are QoS data, and carry a 2-byte QoS Control
+// field after the addresses.
+pub const QOS_SUBTYPE: u8 = 0x08;
+// ...and bit 2 set means there's no body at all (Null, QoS Null, CF-Ack, ...).
+pub const NULL_SUBTYPE: u8 = 0x04;
+
+// Each A-MSDU subframe starts with DA (6), SA (6), and a big-endian length.
+pub const AMSDU_SUBFRAME_HDR_LEN: usize = 14;
+
 impl DataFrameHeader {
-    fn get_src(&self) -> MacAddr {
-        match (self.base.fr_ctrl.has_flag(TO_DS), self.base.fr_ctrl.has_flag(FROM_DS)) {
-            (false, false) => self.addr2,
-            (false, true)  => self.addr3,
-            (true,  false) => self.addr2,
-            (true,  true)  => panic!("can't handle this yet")
+    pub fn seq(&self) -> SequenceControl {
+        SequenceControl(le_u16(&self.seq_ctl))
+    }
+
+    /// Offset of the frame body from the start of the header: past addr4 when
+    /// going between distribution systems, QoS Control, and the HT Control
+    /// field that the Order bit signals on QoS frames.
+    pub fn body_offset(&self) -> usize {
+        let fc = &self.base.fr_ctrl;
+        let mut off = if self.is_wds() { 30 } else { 24 };
+        if fc.frame_subtype() & QOS_SUBTYPE != 0 {
+            off += 2;
+            if fc.has_flag(ORDER) {
+                off += 4;
+            }
+        }
+        off
+    }
+
+    fn is_wds(&self) -> bool {
+        let fc = &self.base.fr_ctrl;
+        fc.has_flag(TO_DS) && fc.has_flag(FROM_DS)
+    }
+
+    /// Only present when going between distribution systems.
+    pub fn addr4(&self, frame: &[u8]) -> Option<MacAddr> {
+        if self.is_wds() && frame.len() >= 30 {
+            Some(MacAddr::from_bytes(&frame[24..30]))
+        } else {
+            None
+        }
+    }
+
+    /// 8.2.4.5 QoS Control, on QoS data subtypes.
+    pub fn qos_ctl(&self, frame: &[u8]) -> Option<QosControl> {
+        if self.base.fr_ctrl.frame_subtype() & QOS_SUBTYPE == 0 {
+            return None;
+        }
+        let off = if self.is_wds() { 30 } else { 24 };
+        if frame.len() < off + 2 {
+            return None;
         }
+        Some(QosControl(frame[off] as u16 | (frame[off+1] as u16) << 8))
     }
 
-    fn get_dest(&self) -> MacAddr {
-        match (self.base.fr_ctrl.has_flag(TO_DS), self.base.fr_ctrl.has_flag(FROM_DS)) {
-            (false, false) => self.addr1,
-            (false, true)  => self.addr1,
-            (true,  false) => self.addr3,
-            (true,  true)  => self.addr3
+    /// Work out who the frame is really from and to, per the table above.
+    /// For A-MSDUs the SA and DA in the header are just the BSSID, so the
+    /// real ones come from the first subframe header.
+    pub fn addrs(&self, frame: &[u8]) -> Option<DataAddrs> {
+        let fc = &self.base.fr_ctrl;
+        let (mut da, mut sa, bssid) = match (fc.has_flag(TO_DS), fc.has_flag(FROM_DS)) {
+            (false, false) => (self.addr1, self.addr2, Some(self.addr3)),
+            (false, true)  => (self.addr1, self.addr3, Some(self.addr2)),
+            (true,  false) => (self.addr3, self.addr2, Some(self.addr1)),
+            (true,  true)  => match self.addr4(frame) {
+                Some(a4) => (self.addr3, a4, None),
+                None => return None
+            }
+        };
+        if self.qos_ctl(frame).map(|q| q.is_amsdu()).unwrap_or(false) {
+            let off = self.body_offset();
+            if frame.len() < off + 12 {
+                return None;
+            }
+            da = MacAddr::from_bytes(&frame[off..off+6]);
+            sa = MacAddr::from_bytes(&frame[off+6..off+12]);
         }
+        Some(DataAddrs {
+            ra: self.addr1,
+            ta: self.addr2,
+            da: da,
+            sa: sa,
+            bssid: bssid
+        })
     }
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct QosControl(pub u16);
+
+impl QosControl {
+    pub fn tid(&self) -> u8 {
+        (self.0 & 0x000f) as u8
+    }
+
+    pub fn is_amsdu(&self) -> bool {
+        self.0 & 0x0080 != 0
+    }
+}
+
+/// 8.2.4.4 Sequence Control: a 12-bit sequence number per MSDU, and a
+/// 4-bit fragment number within it.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SequenceControl(pub u16);
+
+/// Sequence numbers wrap at this.
+pub const SEQ_MODULUS: u16 = 4096;
+
+impl SequenceControl {
+    pub fn number(&self) -> u16 {
+        self.0 >> 4
+    }
+
+    pub fn fragment(&self) -> u8 {
+        (self.0 & 0x000f) as u8
+    }
+}
+
+/// How far b is ahead of a, allowing for wraparound.
+pub fn seq_distance(a: u16, b: u16) -> u16 {
+    (b + SEQ_MODULUS - a) % SEQ_MODULUS
+}
+
+/// Receiver and transmitter are the radios on either end of this hop;
+/// source and destination are the end stations.  There's no BSSID on
+/// four-address frames between distribution systems.
+#[derive(Copy, Clone, Debug)]
+pub struct DataAddrs {
+    pub ra: MacAddr,
+    pub ta: MacAddr,
+    pub da: MacAddr,
+    pub sa: MacAddr,
+    pub bssid: Option<MacAddr>,
+}
+
 // 8.3.3 Management Frames
 
-// 8.3.3.1 Management Frame Format
+// 8.2.4.1.3 Type and Subtype fields, for management frames
+pub const MGMT_ASSOC_REQ: u8    = 0;
+pub const MGMT_ASSOC_RESP: u8   = 1;
+pub const MGMT_REASSOC_REQ: u8  = 2;
+pub const MGMT_REASSOC_RESP: u8 = 3;
+pub const MGMT_PROBE_REQ: u8    = 4;
+pub const MGMT_PROBE_RESP: u8   = 5;
+pub const MGMT_BEACON: u8       = 8;
+pub const MGMT_DISASSOC: u8     = 10;
+pub const MGMT_AUTH: u8         = 11;
+pub const MGMT_DEAUTH: u8       = 12;
+pub const MGMT_ACTION: u8       = 13;
+
+// 8.3.3.1 Management Frame Format.  Just the fixed part: HT Control only
+// follows when the Order bit is set, see body_offset.
 #[repr(packed)]
 pub struct ManagementFrameHeader {
     pub base: Dot11BaseHeader,
     pub addr1: MacAddr,
     pub addr2: MacAddr,
     pub addr3: MacAddr,
-    pub seq_ctl: [u8; 2],
-    pub ht_ctl: [u8; 4]
+    pub seq_ctl: [u8; 2]
+}
+
+impl ManagementFrameHeader {
+    pub fn seq(&self) -> SequenceControl {
+        SequenceControl(le_u16(&self.seq_ctl))
+    }
+
+    /// The HT Control field is only there when the Order bit is set.
+    pub fn body_offset(&self) -> usize {
+        if self.base.fr_ctrl.has_flag(ORDER) { 28 } else { 24 }
+    }
 }
@@ -0,0 +1,519 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::str;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use time;
+
+use multicast::Multicast;
+
+use util::{be_u16, checked_cast};
+use ether::{MacAddr, ETHERTYPE_802_1X};
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, ParseErr};
+use wpa::KeyringRef;
+
+// 802.1X-2010 11.3: EAPOL frames.  These carry EAP for 802.1X authentication,
+// and on 802.11 networks also the EAPOL-Key frames of the WPA 4-way handshake.
+
+#[repr(packed)]
+pub struct EapolHeader {
+    pub version: u8,
+    pub typ: u8,
+    pub len: u16
+}
+
+pub const EAPOL_EAP: u8 = 0;
+pub const EAPOL_START: u8 = 1;
+pub const EAPOL_LOGOFF: u8 = 2;
+pub const EAPOL_KEY: u8 = 3;
+
+// RFC 3748 4
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EapCode {
+    Request,
+    Response,
+    Success,
+    Failure,
+    Unknown(u8)
+}
+
+impl EapCode {
+    fn from_u8(c: u8) -> EapCode {
+        match c {
+            1 => EapCode::Request,
+            2 => EapCode::Response,
+            3 => EapCode::Success,
+            4 => EapCode::Failure,
+            x => EapCode::Unknown(x)
+        }
+    }
+}
+
+pub const EAP_TYPE_IDENTITY: u8 = 1;
+pub const EAP_TYPE_NOTIFICATION: u8 = 2;
+pub const EAP_TYPE_NAK: u8 = 3;
+
+pub fn eap_method_name(m: u8) -> &'static str {
+    match m {
+        1 => "identity",
+        2 => "notification",
+        3 => "nak",
+        4 => "md5",
+        6 => "gtc",
+        13 => "tls",
+        17 => "leap",
+        18 => "sim",
+        21 => "ttls",
+        23 => "aka",
+        25 => "peap",
+        26 => "mschapv2",
+        43 => "fast",
+        47 => "psk",
+        50 => "aka'",
+        52 => "pwd",
+        _ => "unknown"
+    }
+}
+
+pub struct Eap<'a> {
+    pub code: EapCode,
+    pub id: u8,
+    pub method: Option<u8>,
+    pub data: &'a [u8]
+}
+
+bitflags!(flags KeyInfo: u16 {
+    const KEY_DESC_VERSION = 0x0007,
+    const KEY_PAIRWISE     = 0x0008,
+    const KEY_INSTALL      = 0x0040,
+    const KEY_ACK          = 0x0080,
+    const KEY_MIC          = 0x0100,
+    const KEY_SECURE       = 0x0200,
+    const KEY_ERROR        = 0x0400,
+    const KEY_REQUEST      = 0x0800,
+    const KEY_ENCRYPTED    = 0x1000
+});
+
+// 802.11-2012 11.6.2
+pub struct EapolKey<'a> {
+    pub desc_type: u8,
+    pub info: KeyInfo,
+    pub key_len: u16,
+    pub replay_counter: &'a [u8],
+    pub nonce: &'a [u8],
+    pub iv: &'a [u8],
+    pub rsc: &'a [u8],
+    pub mic: &'a [u8],
+    pub key_data: &'a [u8],
+    /// The whole EAPOL frame this key was in, since that's what the MIC covers.
+    pub raw: &'a [u8]
+}
+
+pub const EAPOL_KEY_FIXED_LEN: usize = 95;
+//Offset of the MIC from the start of the whole EAPOL frame, header included.
+pub const EAPOL_KEY_MIC_OFFSET: usize = 4 + 77;
+
+impl<'a> EapolKey<'a> {
+    pub fn descriptor_version(&self) -> u16 {
+        (self.info & KEY_DESC_VERSION).bits()
+    }
+
+    /// Which message of the 4-way handshake this is, if it's part of one.
+    pub fn handshake_msg(&self) -> Option<u8> {
+        if !self.info.contains(KEY_PAIRWISE) {
+            return None; //group key handshake
+        }
+        let ack = self.info.contains(KEY_ACK);
+        let mic = self.info.contains(KEY_MIC);
+        let install = self.info.contains(KEY_INSTALL);
+        match (ack, mic, install) {
+            (true, false, false) => Some(1),
+            (true, true, true) => Some(3),
+            (false, true, false) => {
+                //2 and 4 look alike, but 4 carries no key data.  Secure
+                //doesn't tell them apart: a rekey's message 2 has it set.
+                if self.key_data.is_empty() {
+                    Some(4)
+                } else {
+                    Some(2)
+                }
+            }
+            _ => None
+        }
+    }
+}
+
+pub enum Eapol<'a> {
+    Eap(Eap<'a>),
+    Start,
+    Logoff,
+    Key(EapolKey<'a>),
+    Other(u8)
+}
+
+pub fn parse(data: &[u8]) -> Option<Eapol> {
+    let hdr: &EapolHeader = match checked_cast(data) {
+        Some(h) => h,
+        None => return None
+    };
+    let len = 4 + be_u16(&data[2..]) as usize;
+    if data.len() < len {
+        return None;
+    }
+    let raw = &data[..len];
+    let body = &data[4..len];
+
+    match hdr.typ {
+        EAPOL_EAP => {
+            if body.len() < 4 {
+                return None;
+            }
+            let code = EapCode::from_u8(body[0]);
+            let eap_len = be_u16(&body[2..]) as usize;
+            if eap_len < 4 || eap_len > body.len() {
+                return None;
+            }
+            let (method, data) = match code {
+                EapCode::Request | EapCode::Response if eap_len > 4 => {
+                    (Some(body[4]), &body[5..eap_len])
+                }
+                _ => (None, &body[4..eap_len])
+            };
+            Some(Eapol::Eap(Eap { code: code, id: body[1], method: method, data: data }))
+        }
+        EAPOL_START => Some(Eapol::Start),
+        EAPOL_LOGOFF => Some(Eapol::Logoff),
+        EAPOL_KEY => {
+            if body.len() < EAPOL_KEY_FIXED_LEN {
+                return None;
+            }
+            let key_data_len = be_u16(&body[93..]) as usize;
+            if body.len() < EAPOL_KEY_FIXED_LEN + key_data_len {
+                return None;
+            }
+            Some(Eapol::Key(EapolKey {
+                desc_type: body[0],
+                info: KeyInfo::from_bits_truncate(be_u16(&body[1..])),
+                key_len: be_u16(&body[3..]),
+                replay_counter: &body[5..13],
+                nonce: &body[13..45],
+                iv: &body[45..61],
+                rsc: &body[61..69],
+                mic: &body[77..93],
+                key_data: &body[95..95+key_data_len],
+                raw: raw
+            }))
+        }
+        x => Some(Eapol::Other(x))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum AuthEventKind {
+    Start,
+    Logoff,
+    EapRequest(Option<u8>),
+    EapResponse(Option<u8>, Option<String>),
+    EapSuccess,
+    EapFailure,
+    Handshake(u8)
+}
+
+/// Something that happened in a station's authentication, already pulled
+/// out of the packet so it can be shipped off the capture thread.
+#[derive(Clone, Debug)]
+pub struct AuthEvent {
+    pub station: MacAddr,
+    pub peer: MacAddr,
+    pub wireless: bool,
+    pub kind: AuthEventKind,
+    /// Capture time.
+    pub time: i64
+}
+
+impl AuthEvent {
+    /// Works out which side of the exchange is the station (supplicant) from
+    /// the direction the frame is going in.
+    pub fn from_eapol(src: MacAddr, dst: MacAddr, wireless: bool, e: &Eapol,
+                      time: i64) -> Option<AuthEvent> {
+        let (from_station, kind) = match *e {
+            Eapol::Start => (true, AuthEventKind::Start),
+            Eapol::Logoff => (true, AuthEventKind::Logoff),
+            Eapol::Eap(ref eap) => match eap.code {
+                EapCode::Request => (false, AuthEventKind::EapRequest(eap.method)),
+                EapCode::Response => {
+                    let identity = match eap.method {
+                        Some(EAP_TYPE_IDENTITY) => str::from_utf8(eap.data).ok().map(|s| s.to_string()),
+                        _ => None
+                    };
+                    (true, AuthEventKind::EapResponse(eap.method, identity))
+                }
+                EapCode::Success => (false, AuthEventKind::EapSuccess),
+                EapCode::Failure => (false, AuthEventKind::EapFailure),
+                EapCode::Unknown(_) => return None
+            },
+            Eapol::Key(ref key) => match key.handshake_msg() {
+                Some(n) => (!key.info.contains(KEY_ACK), AuthEventKind::Handshake(n)),
+                None => return None
+            },
+            Eapol::Other(_) => return None
+        };
+        let (station, peer) = if from_station { (src, dst) } else { (dst, src) };
+        Some(AuthEvent { station: station, peer: peer, wireless: wireless, kind: kind, time: time })
+    }
+}
+
+#[derive(RustcEncodable, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AuthStatus {
+    InProgress,
+    Authenticated,
+    Failed,
+    TimedOut,
+    LoggedOff
+}
+
+/// How long an attempt can go without hearing anything before we call it.
+pub const AUTH_TIMEOUT_SECS: i64 = 10;
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct AuthState {
+    pub station: MacAddr,
+    pub authenticator: Option<MacAddr>,
+    pub wireless: bool,
+    pub identity: Option<String>,
+    pub eap_method: Option<&'static str>,
+    pub eap_success: Option<bool>,
+    pub handshake: [bool; 4],
+    pub status: AuthStatus,
+    pub attempts: u32,
+    pub completed: u32,
+    pub failures: u32,
+    pub timeouts: u32,
+    pub started: i64,
+    pub last_update: i64
+}
+
+impl AuthState {
+    fn new(station: MacAddr, wireless: bool, now: i64) -> AuthState {
+        AuthState {
+            station: station,
+            authenticator: None,
+            wireless: wireless,
+            identity: None,
+            eap_method: None,
+            eap_success: None,
+            handshake: [false; 4],
+            status: AuthStatus::InProgress,
+            attempts: 0,
+            completed: 0,
+            failures: 0,
+            timeouts: 0,
+            started: now,
+            last_update: now
+        }
+    }
+
+    fn new_attempt(&mut self, now: i64) {
+        self.identity = None;
+        self.eap_method = None;
+        self.eap_success = None;
+        self.handshake = [false; 4];
+        self.status = AuthStatus::InProgress;
+        self.attempts += 1;
+        self.started = now;
+    }
+
+    fn update(&mut self, ev: &AuthEvent, now: i64) {
+        if !ev.peer.is_multicast() {
+            self.authenticator = Some(ev.peer);
+        }
+
+        let active = self.attempts > 0 && self.status == AuthStatus::InProgress;
+        match ev.kind {
+            AuthEventKind::Logoff => {}
+            AuthEventKind::Start => self.new_attempt(now),
+            _ if !active => self.new_attempt(now),
+            //A fresh M1 mid-attempt means the authenticator is starting the
+            //handshake over, but keep whatever EAP told us.
+            AuthEventKind::Handshake(1) => self.handshake = [false; 4],
+            _ => {}
+        }
+
+        match ev.kind {
+            AuthEventKind::Start | AuthEventKind::EapRequest(None) => {}
+            AuthEventKind::Logoff => self.status = AuthStatus::LoggedOff,
+            AuthEventKind::EapRequest(Some(m)) | AuthEventKind::EapResponse(Some(m), _) => {
+                if m != EAP_TYPE_IDENTITY && m != EAP_TYPE_NOTIFICATION && m != EAP_TYPE_NAK {
+                    self.eap_method = Some(eap_method_name(m));
+                }
+                if let AuthEventKind::EapResponse(_, Some(ref id)) = ev.kind {
+                    self.identity = Some(id.clone());
+                }
+            }
+            AuthEventKind::EapResponse(None, _) => {}
+            AuthEventKind::EapSuccess => {
+                self.eap_success = Some(true);
+                //Wired 802.1X is done here, wireless still has a handshake to go.
+                if !self.wireless {
+                    self.status = AuthStatus::Authenticated;
+                    self.completed += 1;
+                }
+            }
+            AuthEventKind::EapFailure => {
+                self.eap_success = Some(false);
+                self.status = AuthStatus::Failed;
+                self.failures += 1;
+            }
+            AuthEventKind::Handshake(n) => {
+                self.handshake[(n - 1) as usize] = true;
+                if n == 4 {
+                    self.status = AuthStatus::Authenticated;
+                    self.completed += 1;
+                }
+            }
+        }
+        self.last_update = now;
+    }
+
+    fn check_timeout(&mut self, now: i64) -> bool {
+        if self.status == AuthStatus::InProgress && now - self.last_update > AUTH_TIMEOUT_SECS {
+            self.status = AuthStatus::TimedOut;
+            self.timeouts += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct AuthStateMsg {
+    typ: &'static str,
+    auth: AuthState,
+}
+
+#[derive(Clone)]
+pub struct AuthController {
+    pub map: Arc<RwLock<HashMap<MacAddr, AuthState>>>,
+    ev_tx: Sender<AuthEvent>,
+    mcast: Multicast<AuthStateMsg>,
+}
+
+impl AuthController {
+    pub fn spawn() -> io::Result<AuthController> {
+        let (ev_tx, ev_rx) = channel();
+        let (tick_tx, tick_rx) = channel();
+        let out = AuthController {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            ev_tx: ev_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        try!(thread::Builder::new().name("auth_timer".to_string()).spawn(move || {
+            loop {
+                thread::sleep_ms(1000);
+                if tick_tx.send(()).is_err() {
+                    break
+                }
+            }
+        }));
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("auth_handler".to_string()).spawn(move || {
+            //Capture time, run on at wall speed between events, as in
+            //presence, so a handshake times out the same however fast a
+            //file is read.
+            let mut clock: Option<(i64, i64)> = None;
+            loop {
+                select!(
+                    ev = ev_rx.recv() => {
+                        if ev.is_err() {
+                            break
+                        }
+                        let ev: AuthEvent = ev.unwrap();
+                        if clock.map(|(latest, _)| ev.time > latest).unwrap_or(true) {
+                            clock = Some((ev.time, time::get_time().sec));
+                        }
+                        //anything stalled since the last event ran out in between
+                        ctl.expire(clock.map(|(latest, _)| latest).unwrap());
+                        let now = ev.time;
+                        let state = {
+                            let mut m = ctl.map.write().unwrap();
+                            let st = match m.entry(ev.station) {
+                                Entry::Occupied(e) => e.into_mut(),
+                                Entry::Vacant(e) => e.insert(AuthState::new(ev.station, ev.wireless, now))
+                            };
+                            st.update(&ev, now);
+                            st.clone()
+                        };
+                        ctl.mcast.send(Arc::new(AuthStateMsg { typ: "auth", auth: state }));
+                    },
+                    _ = tick_rx.recv() => {
+                        if let Some((latest, seen_at)) = clock {
+                            ctl.expire(latest + time::get_time().sec - seen_at)
+                        }
+                    }
+                )
+            }
+        }));
+
+        Ok(out)
+    }
+
+    fn expire(&self, now: i64) {
+        let timed_out: Vec<_> = {
+            let mut m = self.map.write().unwrap();
+            m.values_mut()
+                .filter_map(|st| if st.check_timeout(now) { Some(st.clone()) } else { None })
+                .collect()
+        };
+        for st in timed_out.into_iter() {
+            self.mcast.send(Arc::new(AuthStateMsg { typ: "auth", auth: st }));
+        }
+    }
+
+    pub fn sender(&self) -> Sender<AuthEvent> {
+        self.ev_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<AuthStateMsg>>) {
+        self.mcast.register(s);
+    }
+}
+
+pub struct EapolDissector {
+    auth_tx: Sender<AuthEvent>,
+    keys: KeyringRef
+}
+
+impl Dissector for EapolDissector {
+    fn dissect(&self, _: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let (src, dst) = match (ctx.src_mac, ctx.dst_mac) {
+            (Some(s), Some(d)) => (s, d),
+            _ => return Err(ParseErr::UnknownPacket)
+        };
+        let eapol = match parse(data) {
+            Some(e) => e,
+            None => return Err(ParseErr::UnknownPacket)
+        };
+        if let Eapol::Key(ref key) = eapol {
+            if ctx.wireless {
+                let (aa, spa) = if key.info.contains(KEY_ACK) { (src, dst) } else { (dst, src) };
+                self.keys.write().unwrap().handle_key(aa, spa, key);
+            }
+        }
+        match AuthEvent::from_eapol(src, dst, ctx.wireless, &eapol, ctx.time) {
+            Some(ev) => Ok(try!(self.auth_tx.send(ev))),
+            None => Err(ParseErr::UnknownPacket)
+        }
+    }
+}
+
+pub fn register_dissectors(reg: &mut DissectorRegistry, auth_tx: Sender<AuthEvent>,
+                          keys: KeyringRef) {
+    reg.register(DissectKey::EtherType(ETHERTYPE_802_1X),
+                 Box::new(EapolDissector { auth_tx: auth_tx, keys: keys }));
+}
@@ -1,8 +1,15 @@
 use std::fmt::{Display, Error, Formatter};
+use std::mem::size_of;
+use std::sync::mpsc::Sender;
 
 use rustc_serialize::hex::FromHex;
 use rustc_serialize::{Encoder,Encodable};
 
+use util::{ntohs, checked_cast};
+use presence::Sighting;
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, GraphSender, ParseErr};
+use pcap::rustpcap as cap;
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub struct MacAddr([u8; 6]);
 
@@ -21,6 +28,23 @@ impl Encodable for MacAddr {
 }
 
 impl MacAddr {
+    /// Group addresses: broadcast, multicast, and the 802.1 link-local ones.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn from_bytes(b: &[u8]) -> MacAddr {
+        let mut out = [0; 6];
+        for (o, x) in out.iter_mut().zip(b.iter()) {
+            *o = *x;
+        }
+        MacAddr(out)
+    }
+
     pub fn from_string(mac: &str) -> Option<MacAddr> {
         let v: Vec<_> = mac.split(':').collect();
         if v.len() == 6 {
@@ -51,3 +75,91 @@ pub const ETHERTYPE_ARP: u16 = 0x0608;
 pub const ETHERTYPE_IP4: u16 = 0x0008;
 pub const ETHERTYPE_IP6: u16 = 0xDD86;
 pub const ETHERTYPE_802_1X: u16 = 0x8E88;
+pub const ETHERTYPE_LLDP: u16 = 0xCC88;
+//transparent ethernet bridging, i.e. a whole ethernet frame as payload
+pub const ETHERTYPE_TEB: u16 = 0x5865;
+
+/// Ethertypes at or below this are actually 802.3 length fields, and the
+/// frame carries an 802.2 LLC header instead.
+pub const ETHER_MAX_LEN: u16 = 1500;
+
+#[repr(packed)]
+pub struct LlcSnapHeader {
+    pub dsap: u8,
+    pub ssap: u8,
+    pub ctrl: u8,
+    pub oui: [u8; 3],
+    pub pid: u16
+}
+
+pub const LLC_SNAP_SAP: u8 = 0xAA;
+//plain ethertype encapsulation
+pub const OUI_RFC1042: [u8; 3] = [0x00, 0x00, 0x00];
+pub const OUI_CISCO: [u8; 3] = [0x00, 0x00, 0x0C];
+//big-endian, like the ethertypes
+pub const SNAP_PID_CDP: u16 = 0x0020;
+
+/// Hand off the payload of an 802.2 LLC/SNAP header, as found in 802.3
+/// frames and 802.11 data frames.
+pub fn dispatch_llc(reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+                    -> Result<(), ParseErr> {
+    let llc: &LlcSnapHeader = match checked_cast(data) {
+        Some(h) => h,
+        None => return Ok(())
+    };
+    if llc.dsap != LLC_SNAP_SAP || llc.ssap != LLC_SNAP_SAP || llc.ctrl != 0x03 {
+        return Ok(());
+    }
+    let payload = &data[size_of::<LlcSnapHeader>()..];
+    if llc.oui == OUI_RFC1042 {
+        reg.dispatch(DissectKey::EtherType(llc.pid), ctx, payload)
+    } else {
+        reg.dispatch(DissectKey::Snap(llc.oui, llc.pid), ctx, payload)
+    }
+}
+
+pub struct EthernetDissector {
+    mac: GraphSender<MacAddr>,
+    presence: Sender<Sighting>,
+}
+
+impl Dissector for EthernetDissector {
+    fn dissect(&self, reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let ether_hdr: &EthernetHeader = match checked_cast(data) {
+            Some(h) => h,
+            None => return Err(ParseErr::UnknownPacket)
+        };
+        //Frames that came out of a tunnel are only as big as what's left.
+        let size = if ctx.encap.depth == 0 { ctx.len } else { data.len() as u32 };
+        ctx.emit(&self.mac, ether_hdr.src, ether_hdr.dst, size);
+        //hosts behind a tunnel endpoint aren't here
+        if ctx.encap.depth == 0 && !ether_hdr.src.is_multicast() {
+            try!(self.presence.send(Sighting {
+                mac: ether_hdr.src,
+                time: ctx.time,
+                source: "ethernet",
+                signal: None
+            }));
+        }
+        ctx.src_mac = Some(ether_hdr.src);
+        ctx.dst_mac = Some(ether_hdr.dst);
+        ctx.wireless = false;
+
+        let payload = &data[size_of::<EthernetHeader>()..];
+        if ntohs(ether_hdr.typ) <= ETHER_MAX_LEN {
+            dispatch_llc(reg, ctx, payload)
+        } else {
+            reg.dispatch(DissectKey::EtherType(ether_hdr.typ), ctx, payload)
+        }
+    }
+}
+
+pub fn register_dissectors(reg: &mut DissectorRegistry, mac: GraphSender<MacAddr>,
+                          presence: Sender<Sighting>) {
+    reg.register(DissectKey::LinkType(cap::DLT_ETHERNET),
+                 Box::new(EthernetDissector { mac: mac.clone(), presence: presence.clone() }));
+    //ethernet-over-whatever coming out of tunnels
+    reg.register(DissectKey::EtherType(ETHERTYPE_TEB),
+                 Box::new(EthernetDissector { mac: mac, presence: presence }));
+}
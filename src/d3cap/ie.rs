@@ -0,0 +1,241 @@
+use std::str;
+use std::hash::{Hash, Hasher, SipHasher};
+
+use util::le_u16;
+
+// Information elements: the (id, length, value) records that make up most
+// of a management frame body.  802.11-2012 8.4.2
+
+pub const IE_SSID: u8          = 0;
+pub const IE_RATES: u8         = 1;
+pub const IE_DS_PARAMS: u8     = 3;
+pub const IE_COUNTRY: u8       = 7;
+pub const IE_HT_CAPS: u8       = 45;
+pub const IE_RSN: u8           = 48;
+pub const IE_EXT_RATES: u8     = 50;
+pub const IE_HT_OPERATION: u8  = 61;
+pub const IE_EXT_CAPS: u8      = 127;
+pub const IE_VHT_CAPS: u8      = 191;
+pub const IE_VHT_OPERATION: u8 = 192;
+pub const IE_VENDOR: u8        = 221;
+pub const IE_EXTENSION: u8     = 255;
+
+pub const IE_EXT_HE_CAPS: u8 = 35;
+
+pub const OUI_IEEE: [u8; 3] = [0x00, 0x0F, 0xAC];
+const OUI_MICROSOFT: [u8; 3] = [0x00, 0x50, 0xF2];
+const WPA_VENDOR_TYPE: u8 = 1;
+
+pub struct IeIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for IeIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        let id = self.data[0];
+        let len = self.data[1] as usize;
+        if 2 + len > self.data.len() {
+            //truncated element, nothing after it can be trusted either
+            self.data = &[];
+            return None;
+        }
+        let val = &self.data[2..2+len];
+        self.data = &self.data[2+len..];
+        Some((id, val))
+    }
+}
+
+pub fn iter<'a>(data: &'a [u8]) -> IeIter<'a> {
+    IeIter { data: data }
+}
+
+/// What a station's probes look like apart from what they ask for: which
+/// elements it sends, in what order, and the capabilities in them.  Address
+/// randomization leaves all of that alone, so one fingerprint under several
+/// random addresses is probably one device.
+pub fn fingerprint(data: &[u8]) -> u64 {
+    let mut h = SipHasher::new();
+    for (id, val) in iter(data) {
+        id.hash(&mut h);
+        match id {
+            IE_RATES | IE_EXT_RATES | IE_HT_CAPS | IE_VHT_CAPS | IE_EXT_CAPS => val.hash(&mut h),
+            IE_EXTENSION if val.len() >= 1 => {
+                if val[0] == IE_EXT_HE_CAPS { val.hash(&mut h) } else { val[0].hash(&mut h) }
+            }
+            //just whose it is, the contents often carry per-session values
+            IE_VENDOR if val.len() >= 4 => val[..4].hash(&mut h),
+            _ => {}
+        }
+    }
+    h.finish()
+}
+
+/// An RSN element, or Microsoft's WPA element that came before it.
+#[derive(RustcEncodable, Clone, PartialEq, Debug)]
+pub struct Security {
+    pub proto: &'static str,
+    pub group: &'static str,
+    pub pairwise: Vec<&'static str>,
+    pub akms: Vec<&'static str>,
+}
+
+fn cipher_name(oui: &[u8], typ: u8) -> &'static str {
+    match (oui == OUI_IEEE || oui == OUI_MICROSOFT, typ) {
+        (true, 1) => "WEP-40",
+        (true, 2) => "TKIP",
+        (true, 4) => "CCMP",
+        (true, 5) => "WEP-104",
+        (true, 8) if oui == OUI_IEEE => "GCMP",
+        (true, 9) if oui == OUI_IEEE => "GCMP-256",
+        (true, 10) if oui == OUI_IEEE => "CCMP-256",
+        _ => "unknown"
+    }
+}
+
+fn akm_name(oui: &[u8], typ: u8) -> &'static str {
+    if oui == OUI_MICROSOFT {
+        return match typ {
+            1 => "802.1X",
+            2 => "PSK",
+            _ => "unknown"
+        };
+    }
+    if oui != OUI_IEEE {
+        return "unknown";
+    }
+    match typ {
+        1 => "802.1X",
+        2 => "PSK",
+        3 => "FT-802.1X",
+        4 => "FT-PSK",
+        5 => "802.1X-SHA256",
+        6 => "PSK-SHA256",
+        8 => "SAE",
+        9 => "FT-SAE",
+        11 => "802.1X-SuiteB",
+        12 => "802.1X-SuiteB-192",
+        18 => "OWE",
+        _ => "unknown"
+    }
+}
+
+// version, group suite, then counted lists of pairwise and AKM suites.  The
+// lists are optional from the end, defaulting to CCMP and 802.1X for RSN.
+fn parse_security(proto: &'static str, data: &[u8]) -> Option<Security> {
+    if data.len() < 2 || le_u16(data) != 1 {
+        return None;
+    }
+    let (default_cipher, default_akm) = if proto == "RSN" {
+        ("CCMP", "802.1X")
+    } else {
+        ("TKIP", "802.1X")
+    };
+    let mut sec = Security {
+        proto: proto,
+        group: default_cipher,
+        pairwise: vec![default_cipher],
+        akms: vec![default_akm]
+    };
+    let mut off = 2;
+    if off + 4 > data.len() {
+        return Some(sec);
+    }
+    sec.group = cipher_name(&data[off..off+3], data[off+3]);
+    off += 4;
+
+    fn suites(data: &[u8], off: &mut usize, name: fn(&[u8], u8) -> &'static str)
+              -> Option<Vec<&'static str>> {
+        if *off + 2 > data.len() {
+            return None;
+        }
+        let count = le_u16(&data[*off..]) as usize;
+        *off += 2;
+        let mut out = Vec::new();
+        for _ in 0..count {
+            if *off + 4 > data.len() {
+                break;
+            }
+            out.push(name(&data[*off..*off+3], data[*off+3]));
+            *off += 4;
+        }
+        Some(out)
+    }
+
+    if let Some(p) = suites(data, &mut off, cipher_name) {
+        sec.pairwise = p;
+        if let Some(a) = suites(data, &mut off, akm_name) {
+            sec.akms = a;
+        }
+    }
+    Some(sec)
+}
+
+fn lossy_str(b: &[u8]) -> String {
+    match str::from_utf8(b) {
+        Ok(s) => s.to_string(),
+        Err(_) => b.iter().map(|x| format!("\\x{:02x}", x)).collect()
+    }
+}
+
+/// The elements we care about, pulled out of a management frame body.
+#[derive(Clone, Debug)]
+pub struct Ies {
+    /// None when hidden: empty or all zeros.
+    pub ssid: Option<String>,
+    /// In Mbps, basic and extended rates together.
+    pub rates: Vec<f32>,
+    pub channel: Option<u8>,
+    pub ht: bool,
+    pub vht: bool,
+    pub he: bool,
+    pub rsn: Option<Security>,
+    pub wpa: Option<Security>,
+    pub country: Option<String>,
+}
+
+impl Ies {
+    pub fn parse(data: &[u8]) -> Ies {
+        let mut ies = Ies {
+            ssid: None,
+            rates: Vec::new(),
+            channel: None,
+            ht: false,
+            vht: false,
+            he: false,
+            rsn: None,
+            wpa: None,
+            country: None
+        };
+        for (id, val) in iter(data) {
+            match id {
+                IE_SSID => if val.iter().any(|&b| b != 0) {
+                    ies.ssid = Some(lossy_str(val));
+                },
+                IE_RATES | IE_EXT_RATES => {
+                    //the high bit just marks basic rates
+                    ies.rates.extend(val.iter().map(|&r| (r & 0x7f) as f32 / 2.0));
+                }
+                IE_DS_PARAMS if val.len() >= 1 => ies.channel = Some(val[0]),
+                IE_HT_OPERATION if val.len() >= 1 && ies.channel.is_none() => {
+                    ies.channel = Some(val[0])
+                }
+                IE_COUNTRY if val.len() >= 2 => ies.country = Some(lossy_str(&val[..2])),
+                IE_HT_CAPS => ies.ht = true,
+                IE_VHT_CAPS => ies.vht = true,
+                IE_EXTENSION if val.len() >= 1 && val[0] == IE_EXT_HE_CAPS => ies.he = true,
+                IE_RSN => ies.rsn = parse_security("RSN", val),
+                IE_VENDOR if val.len() >= 4 && &val[..3] == OUI_MICROSOFT
+                    && val[3] == WPA_VENDOR_TYPE => {
+                    ies.wpa = parse_security("WPA", &val[4..])
+                }
+                _ => {}
+            }
+        }
+        ies
+    }
+}
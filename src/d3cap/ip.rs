@@ -1,8 +1,13 @@
 use std::fmt::{Display,Error,Formatter};
+use std::mem::size_of;
 use std::{net};
 
 use rustc_serialize::{Encodable, Encoder};
 
+use util::{ntohs, checked_cast};
+use ether::{ETHERTYPE_IP4, ETHERTYPE_IP6};
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, GraphSender, ParseErr};
+
 pub trait AsStdIpAddr {
     fn as_std_ip(&self) -> net::IpAddr;
 }
@@ -46,6 +51,17 @@ pub struct IP4Header {
     pub dst: IP4Addr,
 }
 
+impl IP4Header {
+    pub fn header_len(&self) -> usize {
+        ((self.ver_ihl & 0x0F) as usize) * 4
+    }
+
+    /// Only the first fragment of a datagram has the next layer's header.
+    pub fn is_later_fragment(&self) -> bool {
+        ntohs(self.flags_frag) & 0x1FFF != 0
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub struct IP6Addr([u16; 8]);
 
@@ -96,3 +112,129 @@ pub struct IP6Header {
     pub src: IP6Addr,
     pub dst: IP6Addr
 }
+
+#[repr(packed)]
+pub struct UDPHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub len: u16,
+    pub chksum: u16
+}
+
+#[repr(packed)]
+pub struct TCPHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub off_flags: u16,
+    pub window: u16,
+    pub chksum: u16,
+    pub urgent: u16
+}
+
+impl TCPHeader {
+    pub fn header_len(&self) -> usize {
+        ((ntohs(self.off_flags) >> 12) as usize) * 4
+    }
+}
+
+// http://www.iana.org/assignments/protocol-numbers
+pub const IPPROTO_IPIP: u8 = 4;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_IPV6: u8 = 41;
+pub const IPPROTO_GRE: u8 = 47;
+
+pub struct IP4Dissector {
+    ip4: GraphSender<IP4Addr>
+}
+
+impl Dissector for IP4Dissector {
+    fn dissect(&self, reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let ipp: &IP4Header = match checked_cast(data) {
+            Some(h) => h,
+            None => return Err(ParseErr::UnknownPacket)
+        };
+        ctx.emit(&self.ip4, ipp.src, ipp.dst, ntohs(ipp.len) as u32);
+
+        let hlen = ipp.header_len();
+        if hlen < size_of::<IP4Header>() || data.len() < hlen || ipp.is_later_fragment() {
+            return Ok(());
+        }
+        reg.dispatch(DissectKey::IpProto(ipp.proto), ctx, &data[hlen..])
+    }
+}
+
+pub struct IP6Dissector {
+    ip6: GraphSender<IP6Addr>
+}
+
+impl Dissector for IP6Dissector {
+    fn dissect(&self, reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let ipp: &IP6Header = match checked_cast(data) {
+            Some(h) => h,
+            None => return Err(ParseErr::UnknownPacket)
+        };
+        ctx.emit(&self.ip6, ipp.src, ipp.dst, ntohs(ipp.len) as u32);
+
+        //TODO: walk extension headers
+        reg.dispatch(DissectKey::IpProto(ipp.nxthdr), ctx, &data[size_of::<IP6Header>()..])
+    }
+}
+
+/// Ports are dispatched on destination first, then source, so a dissector
+/// registered for a server's port sees both directions of the conversation.
+fn dispatch_ports(reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8],
+                  src: DissectKey, dst: DissectKey) -> Result<(), ParseErr> {
+    if reg.has(dst) {
+        reg.dispatch(dst, ctx, data)
+    } else {
+        reg.dispatch(src, ctx, data)
+    }
+}
+
+pub struct UDPDissector;
+
+impl Dissector for UDPDissector {
+    fn dissect(&self, reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let udp: &UDPHeader = match checked_cast(data) {
+            Some(h) => h,
+            None => return Err(ParseErr::UnknownPacket)
+        };
+        dispatch_ports(reg, ctx, &data[size_of::<UDPHeader>()..],
+                       DissectKey::UdpPort(ntohs(udp.src_port)),
+                       DissectKey::UdpPort(ntohs(udp.dst_port)))
+    }
+}
+
+pub struct TCPDissector;
+
+impl Dissector for TCPDissector {
+    fn dissect(&self, reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let tcp: &TCPHeader = match checked_cast(data) {
+            Some(h) => h,
+            None => return Err(ParseErr::UnknownPacket)
+        };
+        let hlen = tcp.header_len();
+        if hlen < size_of::<TCPHeader>() || data.len() < hlen {
+            return Err(ParseErr::UnknownPacket);
+        }
+        dispatch_ports(reg, ctx, &data[hlen..],
+                       DissectKey::TcpPort(ntohs(tcp.src_port)),
+                       DissectKey::TcpPort(ntohs(tcp.dst_port)))
+    }
+}
+
+pub fn register_dissectors(reg: &mut DissectorRegistry,
+                           ip4: GraphSender<IP4Addr>,
+                           ip6: GraphSender<IP6Addr>) {
+    reg.register(DissectKey::EtherType(ETHERTYPE_IP4), Box::new(IP4Dissector { ip4: ip4 }));
+    reg.register(DissectKey::EtherType(ETHERTYPE_IP6), Box::new(IP6Dissector { ip6: ip6 }));
+    reg.register(DissectKey::IpProto(IPPROTO_UDP), Box::new(UDPDissector));
+    reg.register(DissectKey::IpProto(IPPROTO_TCP), Box::new(TCPDissector));
+}
@@ -0,0 +1,269 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use time;
+
+use multicast::Multicast;
+
+use ether::MacAddr;
+use dot11::{seq_distance, SequenceControl};
+
+// How healthy each station's link looks from here: how often it has to
+// retransmit, and how many of its frames arrive mangled.  Frames that fail
+// the FCS check or claim a protocol version that doesn't exist can't be
+// trusted for anything else, including their addresses, so they only count
+// against stations we've already heard from cleanly.
+//
+// Sequence numbers also say how much we're missing: a transmitter numbers
+// each MSDU one more than the last (per TID for QoS data), so a jump of n
+// means n-1 went by without us hearing them.  That's the monitor interface's
+// loss, not the station's, and says how far to trust everything else.
+
+pub struct LinkSample {
+    pub sensor: usize,
+    pub ta: MacAddr,
+    pub bssid: Option<MacAddr>,
+    pub retry: bool,
+    pub fcs_failed: bool,
+    pub bad_version: bool,
+    /// Management and data frames only.
+    pub seq: Option<SequenceControl>,
+    /// QoS data is numbered separately for each TID.
+    pub tid: Option<u8>,
+    /// Another sensor already counted this frame; it only moves this
+    /// sensor's sequence numbers along.
+    pub repeat: bool,
+}
+
+impl LinkSample {
+    fn is_damaged(&self) -> bool {
+        self.fcs_failed || self.bad_version
+    }
+}
+
+fn ratio(n: u64, d: u64) -> f32 {
+    if d == 0 { 0.0 } else { n as f32 / d as f32 }
+}
+
+/// What a frame's sequence number says, given the last one from the same
+/// transmitter and TID.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum SeqOutcome {
+    /// A new MSDU, after this many we didn't hear.
+    New(u16),
+    /// The next fragment of the same MSDU.
+    Fragment,
+    /// A retransmission of something we already heard.
+    Repeat,
+    /// Too far off to say; the counter was reset, or we'd been away.
+    Unknown,
+}
+
+/// Bigger jumps than this are more likely a reset than that much loss.
+const SEQ_MAX_GAP: u16 = 512;
+
+fn seq_outcome(last: Option<SequenceControl>, seq: SequenceControl) -> SeqOutcome {
+    let last = match last {
+        Some(l) => l,
+        None => return SeqOutcome::New(0)
+    };
+    match seq_distance(last.number(), seq.number()) {
+        0 if seq.fragment() > last.fragment() => SeqOutcome::Fragment,
+        0 => SeqOutcome::Repeat,
+        gap if gap <= SEQ_MAX_GAP => SeqOutcome::New(gap - 1),
+        _ => SeqOutcome::Unknown
+    }
+}
+
+#[derive(RustcEncodable, Copy, Clone, Debug)]
+pub struct LinkStats {
+    pub frames: u64,
+    pub retries: u64,
+    pub fcs_failures: u64,
+    pub bad_version: u64,
+    /// Distinct sequence numbers heard.
+    pub msdus: u64,
+    /// Sequence numbers skipped over, i.e. MSDUs we never heard at all.
+    pub missed: u64,
+    /// Retransmissions of MSDUs we'd already heard.
+    pub repeats: u64,
+    pub last_seen: i64,
+}
+
+impl LinkStats {
+    fn new(now: i64) -> LinkStats {
+        LinkStats {
+            frames: 0, retries: 0, fcs_failures: 0, bad_version: 0,
+            msdus: 0, missed: 0, repeats: 0, last_seen: now
+        }
+    }
+
+    fn add(&mut self, s: &LinkSample, seq: Option<SeqOutcome>, now: i64) {
+        self.frames += 1;
+        if s.retry { self.retries += 1; }
+        if s.fcs_failed { self.fcs_failures += 1; }
+        if s.bad_version { self.bad_version += 1; }
+        match seq {
+            Some(SeqOutcome::New(missed)) => {
+                self.msdus += 1;
+                self.missed += missed as u64;
+            }
+            Some(SeqOutcome::Repeat) => self.repeats += 1,
+            Some(SeqOutcome::Unknown) => self.msdus += 1,
+            _ => {}
+        }
+        self.last_seen = now;
+    }
+
+    /// The fraction of a transmitter's MSDUs we actually heard.
+    pub fn completeness(&self) -> f32 {
+        if self.msdus == 0 { 1.0 } else { ratio(self.msdus, self.msdus + self.missed) }
+    }
+
+    pub fn retry_rate(&self) -> f32 {
+        ratio(self.retries, self.frames)
+    }
+
+    pub fn fcs_rate(&self) -> f32 {
+        ratio(self.fcs_failures, self.frames)
+    }
+
+    pub fn bad_version_rate(&self) -> f32 {
+        ratio(self.bad_version, self.frames)
+    }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct LinkMsg {
+    typ: &'static str,
+    mac: MacAddr,
+    /// "station" or "bssid"
+    kind: &'static str,
+    stats: LinkStats,
+    retry_rate: f32,
+    fcs_rate: f32,
+    bad_version_rate: f32,
+    completeness: f32,
+}
+
+impl LinkMsg {
+    fn new(mac: MacAddr, kind: &'static str, stats: &LinkStats) -> LinkMsg {
+        LinkMsg {
+            typ: "link",
+            mac: mac,
+            kind: kind,
+            stats: *stats,
+            retry_rate: stats.retry_rate(),
+            fcs_rate: stats.fcs_rate(),
+            bad_version_rate: stats.bad_version_rate(),
+            completeness: stats.completeness()
+        }
+    }
+}
+
+fn update_stats(map: &RwLock<HashMap<MacAddr, LinkStats>>, mac: MacAddr,
+                s: &LinkSample, seq: Option<SeqOutcome>, now: i64) -> Option<LinkStats> {
+    let mut map = map.write().unwrap();
+    let st = match map.entry(mac) {
+        Entry::Occupied(e) => e.into_mut(),
+        Entry::Vacant(_) if s.is_damaged() => return None,
+        Entry::Vacant(e) => e.insert(LinkStats::new(now))
+    };
+    st.add(s, seq, now);
+    Some(*st)
+}
+
+const LINK_MSG_INTERVAL_SECS: i64 = 5;
+
+#[derive(Clone)]
+pub struct LinkController {
+    pub stations: Arc<RwLock<HashMap<MacAddr, LinkStats>>>,
+    pub bssids: Arc<RwLock<HashMap<MacAddr, LinkStats>>>,
+    /// Every frame, including damaged ones we couldn't pin on anyone.
+    pub overall: Arc<RwLock<LinkStats>>,
+    link_tx: Sender<LinkSample>,
+    mcast: Multicast<LinkMsg>,
+}
+
+impl LinkController {
+    pub fn spawn() -> io::Result<LinkController> {
+        let (link_tx, link_rx) = channel();
+        let out = LinkController {
+            stations: Arc::new(RwLock::new(HashMap::new())),
+            bssids: Arc::new(RwLock::new(HashMap::new())),
+            overall: Arc::new(RwLock::new(LinkStats::new(time::get_time().sec))),
+            link_tx: link_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("link_handler".to_string()).spawn(move || {
+            let mut last_sent = HashMap::new();
+            //last sequence control from each transmitter, as each sensor heard it
+            let mut last_seq: HashMap<(usize, MacAddr, Option<u8>), SequenceControl> = HashMap::new();
+            loop {
+                let res = link_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let s: LinkSample = res.unwrap();
+                let now = time::get_time().sec;
+
+                let seq = s.seq.map(|sc| {
+                    let prev = last_seq.insert((s.sensor, s.ta, s.tid), sc);
+                    seq_outcome(prev, sc)
+                });
+                if s.repeat {
+                    continue
+                }
+
+                ctl.overall.write().unwrap().add(&s, seq, now);
+
+                let mut updated = Vec::new();
+                if let Some(st) = update_stats(&ctl.stations, s.ta, &s, seq, now) {
+                    updated.push((s.ta, "station", st));
+                }
+                if let Some(b) = s.bssid {
+                    if let Some(st) = update_stats(&ctl.bssids, b, &s, seq, now) {
+                        updated.push((b, "bssid", st));
+                    }
+                }
+
+                for (mac, kind, stats) in updated.into_iter() {
+                    let sent = match last_sent.entry((mac, kind)) {
+                        Entry::Occupied(e) => e.into_mut(),
+                        Entry::Vacant(e) => e.insert(0)
+                    };
+                    if now - *sent >= LINK_MSG_INTERVAL_SECS {
+                        *sent = now;
+                        ctl.mcast.send(Arc::new(LinkMsg::new(mac, kind, &stats)));
+                    }
+                }
+            }
+        }));
+
+        Ok(out)
+    }
+
+    /// Stations with at least min_frames, worst retry rate first.
+    pub fn worst_stations(&self, min_frames: u64) -> Vec<(MacAddr, LinkStats)> {
+        let stations = self.stations.read().unwrap();
+        let mut list: Vec<_> = stations.iter()
+            .filter(|&(_, st)| st.frames >= min_frames)
+            .map(|(mac, st)| (*mac, *st))
+            .collect();
+        list.sort_by(|a, b| b.1.retry_rate().partial_cmp(&a.1.retry_rate()).unwrap());
+        list
+    }
+
+    pub fn sender(&self) -> Sender<LinkSample> {
+        self.link_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<LinkMsg>>) {
+        self.mcast.register(s);
+    }
+}
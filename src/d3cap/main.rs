@@ -6,6 +6,7 @@ extern crate time;
 extern crate libc;
 
 extern crate toml;
+extern crate crypto;
 extern crate "rustc-serialize" as rustc_serialize;
 
 extern crate pcap;
@@ -20,8 +21,30 @@ extern crate bitflags;
 mod util;
 mod ip;
 mod ether;
+mod tunnel;
+mod neighbor;
+mod eapol;
+mod wpa;
+mod dissect;
+mod names;
 mod dot11;
+mod ie;
+mod ap;
+mod probe;
+mod assoc;
+mod alert;
+mod airtime;
+mod link;
+mod distance;
+mod position;
+mod signal;
+mod topology;
+mod presence;
+mod devices;
+mod rogue;
+mod ble;
 mod tap;
+mod radio_hdr;
 mod pkt_graph;
 mod d3cap;
 mod readline;
@@ -0,0 +1,102 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::old_io::net as old_net;
+use std::net;
+use std::sync::{Arc, RwLock};
+
+use rustc_serialize::Encodable;
+
+use ether::MacAddr;
+use ip::{AsStdIpAddr, IP4Addr, IP6Addr};
+use neighbor::NeighborVal;
+
+pub type MacMap = HashMap<MacAddr, String>;
+pub type IP4Map = HashMap<IP4Addr, String>;
+pub type IP6Map = HashMap<IP6Addr, String>;
+
+/// Everything we know about what to call an address.
+#[derive(Clone)]
+pub struct AddrNames {
+    pub macs: MacMap,
+    pub ip4: IP4Map,
+    pub ip6: IP6Map,
+    pub neighbors: Arc<RwLock<HashMap<MacAddr, NeighborVal>>>,
+}
+
+impl AddrNames {
+    pub fn new(macs: MacMap, neighbors: Arc<RwLock<HashMap<MacAddr, NeighborVal>>>) -> AddrNames {
+        AddrNames {
+            macs: macs,
+            ip4: HashMap::new(),
+            ip6: HashMap::new(),
+            neighbors: neighbors
+        }
+    }
+}
+
+/// An address that can be the endpoint of a route in a ProtocolGraph.  New
+/// address types only need to implement name() if there's something better
+/// to show for them than their Display.
+pub trait GraphAddr: Eq+Hash+Copy+Clone+Display+Encodable+Send+Sync+'static {
+    fn name(&self, _names: &mut AddrNames) -> String {
+        self.to_string()
+    }
+}
+
+impl GraphAddr for MacAddr {
+    /// Known macs first, then whatever the switches have told us their names are.
+    fn name(&self, names: &mut AddrNames) -> String {
+        if let Some(v) = names.macs.get(self) {
+            return v.clone();
+        }
+        match names.neighbors.read().unwrap().get(self) {
+            Some(n) => format!("{} ({})", n.nbr.name(), n.nbr.port_id),
+            None => self.to_string()
+        }
+    }
+}
+
+//TODO: waiting for https://github.com/rust-lang/rust/issues/22608
+fn new_to_old_ip(n: net::IpAddr) -> old_net::ip::IpAddr {
+    match n {
+        net::IpAddr::V4(addr) => {
+            let o = addr.octets();
+            old_net::ip::IpAddr::Ipv4Addr(o[0], o[1], o[2], o[3])
+        }
+        net::IpAddr::V6(addr) => {
+            let s = addr.segments();
+            old_net::ip::IpAddr::Ipv6Addr(s[0], s[1], s[2], s[3],
+                                          s[4], s[5], s[6], s[7])
+        }
+    }
+}
+
+fn lookup<T:AsStdIpAddr+Eq+Hash+Display+Clone>(cache: &mut HashMap<T, String>, addr: &T) -> String {
+    let k = addr.clone();
+    match cache.entry(k) {
+        Entry::Occupied(e) => e.get().clone(),
+        Entry::Vacant(e) => {
+            let a = addr.as_std_ip();
+            let n = match old_net::addrinfo::get_address_name(new_to_old_ip(a)) {
+                Ok(name) => name,
+                _ => addr.to_string()
+            };
+            let out = n.clone();
+            e.insert(n);
+            out
+        }
+    }
+}
+
+impl GraphAddr for IP4Addr {
+    fn name(&self, names: &mut AddrNames) -> String {
+        lookup(&mut names.ip4, self)
+    }
+}
+
+impl GraphAddr for IP6Addr {
+    fn name(&self, names: &mut AddrNames) -> String {
+        lookup(&mut names.ip6, self)
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::str;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use time;
+
+use multicast::Multicast;
+
+use util::{be_u16, be_u32};
+use ether::{MacAddr, ETHERTYPE_LLDP, OUI_CISCO, SNAP_PID_CDP};
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, ParseErr};
+
+// Link-layer neighbor discovery: LLDP (802.1AB) and Cisco's CDP.  Switches
+// and routers periodically announce who they are and which of their ports
+// the frame went out of, which is what lets us draw the fabric that the
+// hosts in the mac graph hang off of.
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct Neighbor {
+    pub proto: &'static str,
+    pub mac: MacAddr,
+    pub chassis_id: String,
+    pub port_id: String,
+    pub port_desc: Option<String>,
+    pub system_name: Option<String>,
+    pub capabilities: Vec<&'static str>,
+    pub mgmt_addr: Option<String>,
+}
+
+impl Neighbor {
+    fn new(proto: &'static str, mac: MacAddr) -> Neighbor {
+        Neighbor {
+            proto: proto,
+            mac: mac,
+            chassis_id: "".to_string(),
+            port_id: "".to_string(),
+            port_desc: None,
+            system_name: None,
+            capabilities: Vec::new(),
+            mgmt_addr: None
+        }
+    }
+
+    /// What to call this device: its system name if it gave us one,
+    /// otherwise its chassis id.
+    pub fn name(&self) -> &str {
+        match self.system_name {
+            Some(ref n) => n,
+            None => &self.chassis_id
+        }
+    }
+}
+
+fn lossy_str(b: &[u8]) -> String {
+    match str::from_utf8(b) {
+        Ok(s) => s.trim_matches('\0').to_string(),
+        Err(_) => b.iter().map(|x| format!("{:02x}", x)).collect::<Vec<_>>().connect(":")
+    }
+}
+
+fn mac_str(b: &[u8]) -> String {
+    if b.len() == 6 {
+        MacAddr::from_bytes(b).to_string()
+    } else {
+        lossy_str(b)
+    }
+}
+
+fn ip_str(b: &[u8]) -> Option<String> {
+    match b.len() {
+        4 => Some(format!("{}.{}.{}.{}", b[0], b[1], b[2], b[3])),
+        16 => Some(b.chunks(2).map(|c| format!("{:x}", be_u16(c)))
+                   .collect::<Vec<_>>().connect(":")),
+        _ => None
+    }
+}
+
+// 802.1AB-2009 8.5.8
+const LLDP_CAPS: [&'static str; 8] = ["other", "repeater", "bridge", "wlan-ap",
+                                      "router", "telephone", "docsis", "station"];
+
+pub fn parse_lldp(src: MacAddr, data: &[u8]) -> Option<Neighbor> {
+    let mut nbr = Neighbor::new("lldp", src);
+    let mut off = 0;
+    let mut seen_chassis = false;
+    while off + 2 <= data.len() {
+        let hdr = be_u16(&data[off..]);
+        let typ = hdr >> 9;
+        let len = (hdr & 0x01FF) as usize;
+        off += 2;
+        if off + len > data.len() {
+            break;
+        }
+        let val = &data[off..off+len];
+        off += len;
+
+        match typ {
+            0 => break, //End of LLDPDU
+            1 if len > 1 => {
+                nbr.chassis_id = match val[0] {
+                    4 => mac_str(&val[1..]),
+                    5 if len > 2 => ip_str(&val[2..]).unwrap_or_else(|| lossy_str(&val[2..])),
+                    _ => lossy_str(&val[1..])
+                };
+                seen_chassis = true;
+            }
+            2 if len > 1 => {
+                nbr.port_id = match val[0] {
+                    3 => mac_str(&val[1..]),
+                    4 if len > 2 => ip_str(&val[2..]).unwrap_or_else(|| lossy_str(&val[2..])),
+                    _ => lossy_str(&val[1..])
+                };
+            }
+            4 => nbr.port_desc = Some(lossy_str(val)),
+            5 => nbr.system_name = Some(lossy_str(val)),
+            7 if len >= 4 => {
+                let caps = be_u16(&val[0..]);
+                nbr.capabilities = LLDP_CAPS.iter().enumerate()
+                    .filter(|&(i, _)| caps & (1 << i) != 0)
+                    .map(|(_, c)| *c)
+                    .collect();
+            }
+            8 if len > 2 && nbr.mgmt_addr.is_none() => {
+                let addr_len = val[0] as usize;
+                if addr_len >= 1 && addr_len < len {
+                    //first byte of the address string is its subtype
+                    let addr = &val[2..1+addr_len];
+                    nbr.mgmt_addr = match val[1] {
+                        1 | 2 => ip_str(addr),
+                        6 => Some(mac_str(addr)),
+                        _ => None
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+    if seen_chassis { Some(nbr) } else { None }
+}
+
+// http://www.cisco.com/univercd/cc/td/doc/product/lan/trsrb/frames.htm#xtocid12
+const CDP_CAPS: [&'static str; 7] = ["router", "bridge", "source-route-bridge", "switch",
+                                     "host", "igmp", "repeater"];
+
+// CDP's addresses TLV: a count followed by (proto type, proto, address) tuples.
+fn cdp_addr(val: &[u8]) -> Option<String> {
+    if val.len() < 4 || be_u32(val) == 0 {
+        return None;
+    }
+    let mut off = 4;
+    if off + 2 > val.len() {
+        return None;
+    }
+    let proto_len = val[off+1] as usize;
+    off += 2;
+    if off + proto_len + 2 > val.len() {
+        return None;
+    }
+    let proto = &val[off..off+proto_len];
+    off += proto_len;
+    let addr_len = be_u16(&val[off..]) as usize;
+    off += 2;
+    if off + addr_len > val.len() {
+        return None;
+    }
+    let addr = &val[off..off+addr_len];
+    match proto {
+        [0xCC] => ip_str(addr), //NLPID for ip4
+        _ if proto_len == 8 => ip_str(addr), //802.2 SNAP for ip6
+        _ => None
+    }
+}
+
+pub fn parse_cdp(src: MacAddr, data: &[u8]) -> Option<Neighbor> {
+    let mut nbr = Neighbor::new("cdp", src);
+    let mut off = 4; //version, ttl, checksum
+    let mut seen_dev = false;
+    while off + 4 <= data.len() {
+        let typ = be_u16(&data[off..]);
+        let len = be_u16(&data[off+2..]) as usize;
+        if len < 4 || off + len > data.len() {
+            break;
+        }
+        let val = &data[off+4..off+len];
+        off += len;
+
+        match typ {
+            0x0001 => {
+                nbr.chassis_id = lossy_str(val);
+                nbr.system_name = Some(nbr.chassis_id.clone());
+                seen_dev = true;
+            }
+            0x0002 => if nbr.mgmt_addr.is_none() {
+                nbr.mgmt_addr = cdp_addr(val);
+            },
+            0x0003 => nbr.port_id = lossy_str(val),
+            0x0004 if val.len() >= 4 => {
+                let caps = be_u32(val);
+                nbr.capabilities = CDP_CAPS.iter().enumerate()
+                    .filter(|&(i, _)| caps & (1 << i) != 0)
+                    .map(|(_, c)| *c)
+                    .collect();
+            }
+            0x0016 => {
+                //management addresses are preferred over interface addresses
+                if let Some(a) = cdp_addr(val) {
+                    nbr.mgmt_addr = Some(a);
+                }
+            }
+            _ => {}
+        }
+    }
+    if seen_dev { Some(nbr) } else { None }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct NeighborMsg {
+    typ: &'static str,
+    neighbor: Neighbor,
+}
+
+pub struct NeighborVal {
+    pub nbr: Neighbor,
+    pub count: u32,
+    pub last_seen: time::Timespec,
+}
+
+#[derive(Clone)]
+pub struct NeighborController {
+    pub map: Arc<RwLock<HashMap<MacAddr, NeighborVal>>>,
+    nbr_tx: Sender<Neighbor>,
+    mcast: Multicast<NeighborMsg>,
+}
+
+//Browsers that connect later only hear about changes, so every so often
+//send the whole table again.
+const NEIGHBOR_RESEND_SECS: u32 = 60;
+
+impl NeighborController {
+    pub fn spawn() -> io::Result<NeighborController> {
+        let (nbr_tx, nbr_rx) = channel();
+        let (tick_tx, tick_rx) = channel();
+        let out = NeighborController {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            nbr_tx: nbr_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        try!(thread::Builder::new().name("neighbor_timer".to_string()).spawn(move || {
+            loop {
+                thread::sleep_ms(NEIGHBOR_RESEND_SECS * 1000);
+                if tick_tx.send(()).is_err() {
+                    break
+                }
+            }
+        }));
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("neighbor_handler".to_string()).spawn(move || {
+            loop {
+                select!(
+                    res = nbr_rx.recv() => {
+                        if res.is_err() {
+                            break
+                        }
+                        let nbr: Neighbor = res.unwrap();
+                        ctl.update(nbr)
+                    },
+                    _ = tick_rx.recv() => {
+                        for v in ctl.map.read().unwrap().values() {
+                            ctl.mcast.send(Arc::new(NeighborMsg { typ: "neighbor",
+                                                                  neighbor: v.nbr.clone() }));
+                        }
+                    }
+                )
+            }
+        }));
+
+        Ok(out)
+    }
+
+    fn update(&self, nbr: Neighbor) {
+        //Only tell the ui about a neighbor when something about it
+        //changes, not on every periodic announcement.
+        let changed = match self.map.write().unwrap().entry(nbr.mac) {
+            Entry::Occupied(mut e) => {
+                let v = e.get_mut();
+                let changed = v.nbr.name() != nbr.name() || v.nbr.port_id != nbr.port_id;
+                v.nbr = nbr.clone();
+                v.count += 1;
+                v.last_seen = time::get_time();
+                changed
+            }
+            Entry::Vacant(e) => {
+                e.insert(NeighborVal {
+                    nbr: nbr.clone(),
+                    count: 1,
+                    last_seen: time::get_time()
+                });
+                true
+            }
+        };
+        if changed {
+            self.mcast.send(Arc::new(NeighborMsg { typ: "neighbor", neighbor: nbr }));
+        }
+    }
+
+    pub fn sender(&self) -> Sender<Neighbor> {
+        self.nbr_tx.clone()
+    }
+
+    /// Neighbors only announce themselves every minute or so, and are only
+    /// passed on when they change, so a new listener is caught up on the
+    /// ones already seen.
+    pub fn register_listener(&self, s: Sender<Arc<NeighborMsg>>) {
+        //hold the map until registered, so nothing added meanwhile is missed
+        let map = self.map.read().unwrap();
+        for v in map.values() {
+            if s.send(Arc::new(NeighborMsg { typ: "neighbor", neighbor: v.nbr.clone() })).is_err() {
+                return
+            }
+        }
+        self.mcast.register(s);
+    }
+}
+
+pub struct NeighborDissector {
+    parse: fn(MacAddr, &[u8]) -> Option<Neighbor>,
+    nbr_tx: Sender<Neighbor>
+}
+
+impl Dissector for NeighborDissector {
+    fn dissect(&self, _: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let src = match ctx.src_mac {
+            Some(m) => m,
+            None => return Err(ParseErr::UnknownPacket)
+        };
+        match (self.parse)(src, data) {
+            Some(nbr) => Ok(try!(self.nbr_tx.send(nbr))),
+            None => Err(ParseErr::UnknownPacket)
+        }
+    }
+}
+
+pub fn register_dissectors(reg: &mut DissectorRegistry, nbr_tx: Sender<Neighbor>) {
+    reg.register(DissectKey::EtherType(ETHERTYPE_LLDP),
+                 Box::new(NeighborDissector { parse: parse_lldp, nbr_tx: nbr_tx.clone() }));
+    reg.register(DissectKey::Snap(OUI_CISCO, SNAP_PID_CDP),
+                 Box::new(NeighborDissector { parse: parse_cdp, nbr_tx: nbr_tx }));
+}
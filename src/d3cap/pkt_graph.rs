@@ -1,19 +1,30 @@
+use std::cmp;
 use std::collections::hash_map::{self, HashMap};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::hash::{Hash};
 
 use time;
 
+use tunnel::TunnelTag;
+
 #[derive(Debug)]
 pub struct PktMeta<T> {
     pub src: T,
     pub dst: T,
     pub size: u32,
-    pub tm: time::Timespec
+    pub tm: time::Timespec,
+    pub tag: Option<TunnelTag>
 }
 impl<T> PktMeta<T> {
-    pub fn new(src: T, dst: T, size: u32) -> PktMeta<T> {
-        PktMeta { src: src, dst: dst, size: size, tm: time::get_time() }
+    /// tm is when it was captured.
+    pub fn new(src: T, dst: T, size: u32, tm: time::Timespec) -> PktMeta<T> {
+        PktMeta::tagged(src, dst, size, tm, None)
+    }
+
+    /// A packet that was pulled out of a tunnel.
+    pub fn tagged(src: T, dst: T, size: u32, tm: time::Timespec,
+                  tag: Option<TunnelTag>) -> PktMeta<T> {
+        PktMeta { src: src, dst: dst, size: size, tm: tm, tag: tag }
     }
 }
 
@@ -32,22 +43,152 @@ impl PktStats {
     }
 }
 
+/// Per-second throughput over one window.
+#[derive(RustcEncodable, Copy, Clone, Debug)]
+pub struct Rate {
+    pub bytes: f64,
+    pub pkts: f64
+}
+
+#[derive(RustcEncodable, Copy, Clone, Debug)]
+pub struct Rates {
+    pub last_10s: Rate,
+    pub last_1m: Rate,
+    pub last_5m: Rate
+}
+
+impl Rate {
+    pub fn plus(&self, o: &Rate) -> Rate {
+        Rate { bytes: self.bytes + o.bytes, pkts: self.pkts + o.pkts }
+    }
+}
+
+impl Rates {
+    pub fn plus(&self, o: &Rates) -> Rates {
+        Rates {
+            last_10s: self.last_10s.plus(&o.last_10s),
+            last_1m: self.last_1m.plus(&o.last_1m),
+            last_5m: self.last_5m.plus(&o.last_5m)
+        }
+    }
+
+    /// By window name, as the cli takes them.
+    pub fn get(&self, window: &str) -> Option<Rate> {
+        match window {
+            "10s" => Some(self.last_10s),
+            "1m" => Some(self.last_1m),
+            "5m" => Some(self.last_5m),
+            _ => None
+        }
+    }
+}
+
+const WINDOW_SLICES: usize = 10;
+
+/// Traffic over a window of time, kept in slices so it slides a slice at a time
+/// without having to remember every packet.
+#[derive(Copy, Clone, Debug)]
+struct Window {
+    slice_ms: i64,
+    slices: [PktStats; WINDOW_SLICES],
+    /// Which slice, counting from the epoch, the newest traffic is in.
+    head: i64
+}
+
+impl Window {
+    fn new(secs: i64) -> Window {
+        Window { slice_ms: secs * 1000 / WINDOW_SLICES as i64,
+                 slices: [PktStats::new(); WINDOW_SLICES], head: 0 }
+    }
+
+    fn slice(&self, tm: time::Timespec) -> i64 {
+        (tm.sec * 1000 + tm.nsec as i64 / 1_000_000) / self.slice_ms
+    }
+
+    fn update(&mut self, size: u32, tm: time::Timespec) {
+        let idx = self.slice(tm);
+        let n = WINDOW_SLICES as i64;
+        if idx > self.head {
+            //clear out whatever the window slid past
+            let stale = cmp::min(idx - self.head, n);
+            for i in 0..stale {
+                self.slices[((idx - i) % n) as usize] = PktStats::new();
+            }
+            self.head = idx;
+        } else if idx <= self.head - n {
+            return;
+        }
+        self.slices[(idx % n) as usize].update(size);
+    }
+
+    fn rate(&self, now: time::Timespec) -> Rate {
+        let now_idx = self.slice(now);
+        let n = WINDOW_SLICES as i64;
+        let mut total = PktStats::new();
+        for idx in (self.head - n + 1)..(self.head + 1) {
+            if idx > now_idx - n {
+                let s = self.slices[(idx % n) as usize];
+                total.count += s.count;
+                total.size += s.size;
+            }
+        }
+        let secs = (self.slice_ms * n) as f64 / 1000.0;
+        Rate { bytes: total.size as f64 / secs, pkts: total.count as f64 / secs }
+    }
+}
+
+/// Sliding 10 second, 1 minute and 5 minute rates.
+#[derive(Copy, Clone, Debug)]
+pub struct RateWindows {
+    windows: [Window; 3]
+}
+
+impl RateWindows {
+    pub fn new() -> RateWindows {
+        RateWindows { windows: [Window::new(10), Window::new(60), Window::new(300)] }
+    }
+
+    pub fn update(&mut self, size: u32, tm: time::Timespec) {
+        for w in self.windows.iter_mut() {
+            w.update(size, tm);
+        }
+    }
+
+    pub fn rates(&self, now: time::Timespec) -> Rates {
+        Rates {
+            last_10s: self.windows[0].rate(now),
+            last_1m: self.windows[1].rate(now),
+            last_5m: self.windows[2].rate(now)
+        }
+    }
+}
+
 //TODO: derive Encodable manually
 #[derive(Clone, Debug)]
 pub struct AddrStats<T:Hash+Eq> {
     sent: PktStats,
     sent_to: HashMap<T, PktStats>,
+    sent_rates: RateWindows,
+    sent_to_rates: HashMap<T, RateWindows>,
     received: PktStats,
-    received_from: HashMap<T, PktStats>
+    received_from: HashMap<T, PktStats>,
+    received_rates: RateWindows,
 }
 impl <'a, T:Hash+Eq+Clone> AddrStats<T> {
     pub fn new() -> AddrStats<T> {
         AddrStats { sent: PktStats::new(), sent_to: HashMap::new(),
-                    received: PktStats::new(), received_from: HashMap::new() }
+                    sent_rates: RateWindows::new(), sent_to_rates: HashMap::new(),
+                    received: PktStats::new(), received_from: HashMap::new(),
+                    received_rates: RateWindows::new() }
     }
 
-    pub fn update_sent_to(&mut self, to: T, size: u32) -> PktStats {
+    pub fn update_sent_to(&mut self, to: T, size: u32, tm: time::Timespec) -> PktStats {
         self.sent.update(size);
+        self.sent_rates.update(size, tm);
+        match self.sent_to_rates.entry(to.clone()) {
+            Vacant(entry) => entry.insert(RateWindows::new()),
+            Occupied(entry) => entry.into_mut()
+        }.update(size, tm);
         AddrStats::update(&mut self.sent_to, to, size)
     }
 
@@ -59,13 +200,22 @@ impl <'a, T:Hash+Eq+Clone> AddrStats<T> {
         AddrStats::get(&self.sent_to, to)
     }
 
+    pub fn get_sent_rates(&self, now: time::Timespec) -> Rates {
+        self.sent_rates.rates(now)
+    }
+
+    pub fn get_sent_to_rates(&self, to: &T, now: time::Timespec) -> Rates {
+        self.sent_to_rates.get(to).cloned().unwrap_or_else(RateWindows::new).rates(now)
+    }
+
     pub fn sent_iter(&'a self) -> ASIter<'a, T> {
         ASIter { inner: self.sent_to.iter() }
     }
 
 
-    pub fn update_received_from(&mut self, from: T, size: u32) -> PktStats {
+    pub fn update_received_from(&mut self, from: T, size: u32, tm: time::Timespec) -> PktStats {
         self.received.update(size);
+        self.received_rates.update(size, tm);
         AddrStats::update(&mut self.received_from, from, size)
     }
 
@@ -77,6 +227,10 @@ impl <'a, T:Hash+Eq+Clone> AddrStats<T> {
         AddrStats::get(&self.received_from, from)
     }
 
+    pub fn get_received_rates(&self, now: time::Timespec) -> Rates {
+        self.received_rates.rates(now)
+    }
+
     pub fn recv_iter(&'a self) -> ASIter<'a, T> {
         ASIter { inner: self.received_from.iter() }
     }
@@ -120,69 +274,100 @@ impl<'a, T: 'a+Hash+Eq+Copy+Clone> Iterator for ASIter<'a, T> {
 #[derive(RustcEncodable, Clone)]
 pub struct SentStats<T> {
     addr: T,
-    sent: PktStats
+    sent: PktStats,
+    rates: Rates
 }
 
 #[derive(RustcEncodable, Clone)]
 pub struct RouteStats<T> {
     a: SentStats<T>,
-    b: SentStats<T>
+    b: SentStats<T>,
+    tag: Option<TunnelTag>
 }
 
+/// A host: the tunnel it was seen inside of, if any, and its address there.
+/// Tenants on different VNIs or GRE keys can reuse the same inner addresses,
+/// so each tunnel gets its own.
+pub type HostKey<T> = (Option<TunnelTag>, T);
+
 //TODO: derive Encodable manually
 #[derive(Clone, Debug)]
 pub struct ProtocolGraph<T:Hash+Eq> {
     stats: PktStats,
-    routes: HashMap<T, AddrStats<T>>,
+    /// Both ends of a route are always in the same tunnel, so each host's
+    /// AddrStats only needs the other end's address.
+    routes: HashMap<HostKey<T>, AddrStats<T>>,
+    /// The latest capture time, and the wall clock when it was seen.
+    clock: Option<(time::Timespec, time::Timespec)>,
 }
 
 impl<'a, T: Hash+Eq+Copy+Clone> ProtocolGraph<T> {
     pub fn new() -> ProtocolGraph<T> {
-        ProtocolGraph { stats: PktStats::new(), routes: HashMap::new() }
+        ProtocolGraph { stats: PktStats::new(), routes: HashMap::new(), clock: None }
     }
+
+    /// Now, in capture time: the latest packet's, run on at wall clock speed
+    /// since, so rates still fall off when traffic stops.  For a file, that
+    /// makes rates what they were at that point in the capture, rather than
+    /// how fast it's being read.
+    pub fn now(&self) -> time::Timespec {
+        match self.clock {
+            Some((latest, seen_at)) => latest + (time::get_time() - seen_at),
+            None => time::get_time()
+        }
+    }
+
     pub fn update(&mut self, pkt: &PktMeta<T>) -> RouteStats<T> {
         self.stats.update(pkt.size);
+        if self.clock.map(|(latest, _)| pkt.tm > latest).unwrap_or(true) {
+            self.clock = Some((pkt.tm, time::get_time()));
+        }
+        let now = self.now();
 
         // TODO: can we do something to avoid all these clones?
         let a_to_b;
         {
-            let a = match self.routes.entry(pkt.src) {
+            let a = match self.routes.entry((pkt.tag, pkt.src)) {
                 Vacant(entry) => entry.insert(AddrStats::new()),
                 Occupied(entry) => entry.into_mut()
             };
-            a_to_b = a.update_sent_to(pkt.dst, pkt.size);
+            a_to_b = (a.update_sent_to(pkt.dst, pkt.size, pkt.tm),
+                      a.get_sent_to_rates(&pkt.dst, now));
         }
 
         let b_to_a;
         {
-            let b = match self.routes.entry(pkt.dst) {
+            let b = match self.routes.entry((pkt.tag, pkt.dst)) {
                 Vacant(entry) => entry.insert(AddrStats::new()),
                 Occupied(entry) => entry.into_mut()
             };
-            b.update_received_from(pkt.src, pkt.size);
-            b_to_a = b.get_sent_to(&pkt.src);
+            b.update_received_from(pkt.src, pkt.size, pkt.tm);
+            b_to_a = (b.get_sent_to(&pkt.src), b.get_sent_to_rates(&pkt.src, now));
         }
 
         RouteStats {
-            a: SentStats { addr: pkt.src, sent: a_to_b },
-            b: SentStats { addr: pkt.dst, sent: b_to_a }
+            a: SentStats { addr: pkt.src, sent: a_to_b.0, rates: a_to_b.1 },
+            b: SentStats { addr: pkt.dst, sent: b_to_a.0, rates: b_to_a.1 },
+            tag: pkt.tag
         }
     }
 
-    pub fn get_route_stats(&self, a: &T, b: &T) -> Option<RouteStats<T>> {
-        let a_opt = self.routes.get(a);
-        let b_opt = self.routes.get(b);
+    pub fn get_route_stats(&self, tag: Option<TunnelTag>, a: &T, b: &T) -> Option<RouteStats<T>> {
+        let now = self.now();
+        let a_opt = self.routes.get(&(tag, *a));
+        let b_opt = self.routes.get(&(tag, *b));
         match (a_opt, b_opt) {
             (Some(a_), Some(b_)) => Some(RouteStats {
-                a: SentStats { addr: *a, sent: a_.get_sent_to(b) },
-                b: SentStats { addr: *b, sent: b_.get_sent_to(a) }
+                a: SentStats { addr: *a, sent: a_.get_sent_to(b), rates: a_.get_sent_to_rates(b, now) },
+                b: SentStats { addr: *b, sent: b_.get_sent_to(a), rates: b_.get_sent_to_rates(a, now) },
+                tag: tag
             }),
             _ => None
         }
     }
 
-    pub fn get_addr_stats(&self, addr: &T) -> Option<&AddrStats<T>> {
-        self.routes.get(addr)
+    pub fn get_addr_stats(&self, tag: Option<TunnelTag>, addr: &T) -> Option<&AddrStats<T>> {
+        self.routes.get(&(tag, *addr))
     }
 
     pub fn iter(&'a self) -> PGIter<'a, T> {
@@ -191,11 +376,11 @@ impl<'a, T: Hash+Eq+Copy+Clone> ProtocolGraph<T> {
 }
 
 struct PGIter<'a, T:'a+Hash+Eq> {
-    inner: hash_map::Iter<'a, T, AddrStats<T>>
+    inner: hash_map::Iter<'a, HostKey<T>, AddrStats<T>>
 }
 
 impl<'a, T: 'a+Hash+Eq+Copy+Clone> Iterator for PGIter<'a, T> {
-    type Item = (&'a T, &'a AddrStats<T>);
+    type Item = (&'a HostKey<T>, &'a AddrStats<T>);
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         self.inner.next()
@@ -0,0 +1,221 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::num::Float;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use toml;
+
+use multicast::Multicast;
+
+use ether::MacAddr;
+use distance::DistanceEstimate;
+
+// With radios in a few known places, each one's distance estimate puts a
+// station somewhere on a circle around it; where the circles (nearly) meet
+// is where the station is.  Distance estimates are rough, so this takes the
+// least-squares answer and reports how badly the circles disagree.
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct Sensor {
+    pub name: String,
+    pub interface: Option<String>,
+    pub file: Option<String>,
+    pub x: f32,
+    pub y: f32,
+}
+
+fn num(v: &toml::Value) -> Option<f32> {
+    v.as_float().map(|f| f as f32).or(v.as_integer().map(|i| i as f32))
+}
+
+/// Reads the [[sensors]] array, e.g.:
+///
+///     [[sensors]]
+///     name = "lobby"
+///     interface = "wlan1"
+///     x = 0.0
+///     y = 0.0
+///
+///     [[sensors]]
+///     name = "office"
+///     file = "office.pcap"
+///     x = 12.5
+///     y = 4.0
+///
+/// Positions are in meters.  Sensors without a position, or without an
+/// interface or file to capture from, are skipped.
+pub fn sensors_from_toml(conf: &toml::Table) -> Vec<Sensor> {
+    let list = match conf.get("sensors").and_then(|v| v.as_slice()) {
+        Some(l) => l,
+        None => return Vec::new()
+    };
+    list.iter().enumerate().filter_map(|(i, v)| {
+        let tbl = match v.as_table() {
+            Some(t) => t,
+            None => return None
+        };
+        let s = |key: &str| tbl.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+        let (interface, file) = (s("interface"), s("file"));
+        if interface.is_none() && file.is_none() {
+            println!("Ignoring sensor {} without an interface or file to capture from", i);
+            return None
+        }
+        match (tbl.get("x").and_then(num), tbl.get("y").and_then(num)) {
+            (Some(x), Some(y)) => Some(Sensor {
+                name: s("name").unwrap_or_else(|| format!("sensor{}", i)),
+                interface: interface,
+                file: file,
+                x: x,
+                y: y
+            }),
+            _ => {
+                println!("Ignoring sensor {} without an x and y position", i);
+                None
+            }
+        }
+    }).collect()
+}
+
+/// A sensor's latest idea of how far away a station is.
+pub struct RangeSample {
+    pub sensor: usize,
+    pub mac: MacAddr,
+    /// Capture time, so files from different sensors line up.
+    pub time: i64,
+    pub estimate: DistanceEstimate,
+}
+
+#[derive(RustcEncodable, Copy, Clone, Debug)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+    /// RMS difference, in meters, between the distance to each sensor from
+    /// here and what that sensor estimated.
+    pub error: f32,
+    pub sensors: usize,
+    pub time: i64,
+}
+
+/// Ranges older than this, relative to the newest, don't count.
+const RANGE_MAX_AGE_SECS: i64 = 10;
+
+/// Least squares over the circles (x - xi)^2 + (y - yi)^2 = di^2, made linear
+/// by subtracting the last one from the rest.  Needs three sensors that
+/// aren't all in a line.
+pub fn trilaterate(ranges: &[(f32, f32, f32)]) -> Option<(f32, f32, f32)> {
+    let n = ranges.len();
+    if n < 3 {
+        return None;
+    }
+    let (xn, yn, dn) = ranges[n-1];
+    //normal equations for A p = b, where row i of A is 2(xn-xi, yn-yi)
+    let (mut aa, mut ab, mut bb, mut ac, mut bc) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for &(xi, yi, di) in ranges[..n-1].iter() {
+        let a = 2.0 * (xn - xi);
+        let b = 2.0 * (yn - yi);
+        let c = di * di - dn * dn - xi * xi + xn * xn - yi * yi + yn * yn;
+        aa += a * a;
+        ab += a * b;
+        bb += b * b;
+        ac += a * c;
+        bc += b * c;
+    }
+    let det = aa * bb - ab * ab;
+    if det.abs() < 1e-6 {
+        return None; //collinear
+    }
+    let x = (bb * ac - ab * bc) / det;
+    let y = (aa * bc - ab * ac) / det;
+
+    let sq: f32 = ranges.iter().map(|&(xi, yi, di)| {
+        let e = ((x - xi) * (x - xi) + (y - yi) * (y - yi)).sqrt() - di;
+        e * e
+    }).fold(0.0, |s, e| s + e);
+    Some((x, y, (sq / n as f32).sqrt()))
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct PositionMsg {
+    typ: &'static str,
+    mac: MacAddr,
+    position: Position,
+    sensors: Vec<Sensor>,
+}
+
+#[derive(Clone)]
+pub struct PositionController {
+    pub sensors: Arc<Vec<Sensor>>,
+    pub positions: Arc<RwLock<HashMap<MacAddr, Position>>>,
+    range_tx: Sender<RangeSample>,
+    mcast: Multicast<PositionMsg>,
+}
+
+impl PositionController {
+    pub fn spawn(sensors: Vec<Sensor>) -> io::Result<PositionController> {
+        let (range_tx, range_rx) = channel();
+        let out = PositionController {
+            sensors: Arc::new(sensors),
+            positions: Arc::new(RwLock::new(HashMap::new())),
+            range_tx: range_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("position_handler".to_string()).spawn(move || {
+            //latest (time, meters) from each sensor, for each station
+            let mut ranges: HashMap<MacAddr, Vec<Option<(i64, f32)>>> = HashMap::new();
+            loop {
+                let res = range_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let r: RangeSample = res.unwrap();
+                let sensors = &*ctl.sensors;
+                if r.sensor >= sensors.len() {
+                    continue
+                }
+
+                let rs = match ranges.entry(r.mac) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(sensors.iter().map(|_| None).collect())
+                };
+                rs[r.sensor] = Some((r.time, r.estimate.meters));
+
+                let newest = rs.iter().filter_map(|x| x.map(|(t, _)| t)).max().unwrap_or(r.time);
+                let usable: Vec<_> = rs.iter().zip(sensors.iter())
+                    .filter_map(|(x, s)| match *x {
+                        Some((t, d)) if newest - t <= RANGE_MAX_AGE_SECS => Some((s.x, s.y, d)),
+                        _ => None
+                    })
+                    .collect();
+
+                if let Some((x, y, error)) = trilaterate(&usable) {
+                    let pos = Position { x: x, y: y, error: error, sensors: usable.len(), time: newest };
+                    //at most one update a second for the ui
+                    let prev = ctl.positions.write().unwrap().insert(r.mac, pos);
+                    if prev.map(|p| p.time == newest).unwrap_or(false) {
+                        continue
+                    }
+                    ctl.mcast.send(Arc::new(PositionMsg {
+                        typ: "position",
+                        mac: r.mac,
+                        position: pos,
+                        sensors: sensors.clone()
+                    }));
+                }
+            }
+        }));
+
+        Ok(out)
+    }
+
+    pub fn sender(&self) -> Sender<RangeSample> {
+        self.range_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<PositionMsg>>) {
+        self.mcast.register(s);
+    }
+}
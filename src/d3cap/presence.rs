@@ -0,0 +1,266 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use time;
+use toml;
+
+use multicast::Multicast;
+use fixed_ring::FixedRingBuffer;
+
+use ether::MacAddr;
+use names::MacMap;
+
+// Whether the devices we know by name are around: a station arrives the
+// first time we hear from it, and departs once we haven't for its timeout.
+// Departures have to be noticed on a timer, since nothing arrives to say
+// something's gone.
+
+#[derive(Clone, Debug)]
+pub struct PresenceConf {
+    pub timeout_secs: i64,
+    pub timeouts: HashMap<MacAddr, i64>,
+    pub log: Option<String>,
+}
+
+impl PresenceConf {
+    pub fn new() -> PresenceConf {
+        PresenceConf { timeout_secs: 300, timeouts: HashMap::new(), log: None }
+    }
+
+    /// Reads the [presence] and [presence.timeouts] tables, e.g.:
+    ///
+    ///     [presence]
+    ///     timeout-secs = 300
+    ///     log = "presence.log"
+    ///
+    ///     [presence.timeouts]
+    ///     "00:11:22:33:44:55" = 900
+    pub fn from_toml(conf: &toml::Table) -> PresenceConf {
+        let mut out = PresenceConf::new();
+        let tbl = match conf.get("presence").and_then(|t| t.as_table()) {
+            Some(t) => t,
+            None => return out
+        };
+        if let Some(t) = tbl.get("timeout-secs").and_then(|v| v.as_integer()) {
+            out.timeout_secs = t;
+        }
+        out.log = tbl.get("log").and_then(|v| v.as_str()).map(|s| s.to_string());
+        if let Some(ts) = tbl.get("timeouts").and_then(|t| t.as_table()) {
+            out.timeouts = ts.iter()
+                .filter_map(|(k, v)| match (MacAddr::from_string(&k), v.as_integer()) {
+                    (Some(mac), Some(t)) => Some((mac, t)),
+                    _ => None
+                })
+                .collect();
+        }
+        out
+    }
+
+    pub fn timeout(&self, mac: &MacAddr) -> i64 {
+        self.timeouts.get(mac).cloned().unwrap_or(self.timeout_secs)
+    }
+}
+
+/// Something we heard from a station.
+pub struct Sighting {
+    pub mac: MacAddr,
+    /// Capture time.
+    pub time: i64,
+    /// "wifi" or "ethernet"
+    pub source: &'static str,
+    pub signal: Option<i8>,
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct PresenceState {
+    pub mac: MacAddr,
+    pub name: String,
+    pub present: bool,
+    /// When it last arrived or departed.
+    pub since: i64,
+    pub last_seen: i64,
+    pub source: &'static str,
+    pub signal: Option<i8>,
+    pub arrivals: u32,
+    pub departures: u32,
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct PresenceEvent {
+    pub mac: MacAddr,
+    pub name: String,
+    /// "arrived" or "departed"
+    pub kind: &'static str,
+    pub time: i64,
+    pub source: &'static str,
+}
+
+impl PresenceEvent {
+    fn new(st: &PresenceState, kind: &'static str, time: i64) -> PresenceEvent {
+        PresenceEvent { mac: st.mac, name: st.name.clone(), kind: kind, time: time, source: st.source }
+    }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct PresenceMsg {
+    typ: &'static str,
+    event: PresenceEvent,
+}
+
+const PRESENCE_HISTORY_LEN: usize = 200;
+
+fn open_log(path: &str) -> Option<File> {
+    match OpenOptions::new().write(true).append(true).create(true).open(path) {
+        Ok(f) => Some(f),
+        Err(e) => {
+            println!("Couldn't open presence log {}: {}", path, e);
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PresenceController {
+    pub conf: Arc<PresenceConf>,
+    pub map: Arc<RwLock<HashMap<MacAddr, PresenceState>>>,
+    pub recent: Arc<RwLock<FixedRingBuffer<PresenceEvent>>>,
+    sighting_tx: Sender<Sighting>,
+    mcast: Multicast<PresenceMsg>,
+}
+
+impl PresenceController {
+    /// Only the stations in known are tracked.
+    pub fn spawn(conf: PresenceConf, known: MacMap) -> io::Result<PresenceController> {
+        let (sighting_tx, sighting_rx) = channel();
+        let (tick_tx, tick_rx) = channel();
+        let out = PresenceController {
+            conf: Arc::new(conf),
+            map: Arc::new(RwLock::new(HashMap::new())),
+            recent: Arc::new(RwLock::new(FixedRingBuffer::new(PRESENCE_HISTORY_LEN))),
+            sighting_tx: sighting_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        try!(thread::Builder::new().name("presence_timer".to_string()).spawn(move || {
+            loop {
+                thread::sleep_ms(1000);
+                if tick_tx.send(()).is_err() {
+                    break
+                }
+            }
+        }));
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("presence_handler".to_string()).spawn(move || {
+            let mut log = ctl.conf.log.as_ref().and_then(|p| open_log(p));
+            //Sightings carry capture time, which for a file is nowhere near
+            //now.  Keep the latest capture time seen from anyone, and the
+            //wall clock when we saw it, so time runs on from there between
+            //sightings and timeouts work for both.  A file can get through
+            //hours in a second, so expire on every sighting too, not just
+            //on the timer.
+            let mut clock: Option<(i64, i64)> = None;
+            loop {
+                let events = select!(
+                    s = sighting_rx.recv() => {
+                        if s.is_err() {
+                            break
+                        }
+                        let s: Sighting = s.unwrap();
+                        if clock.map(|(latest, _)| s.time > latest).unwrap_or(true) {
+                            clock = Some((s.time, time::get_time().sec));
+                        }
+                        let mut events = ctl.expire(clock.map(|(latest, _)| latest).unwrap());
+                        if let Some(name) = known.get(&s.mac) {
+                            events.extend(ctl.sighting(s, name).into_iter());
+                        }
+                        events
+                    },
+                    _ = tick_rx.recv() => {
+                        match clock {
+                            Some((latest, seen_at)) => {
+                                ctl.expire(latest + time::get_time().sec - seen_at)
+                            }
+                            None => Vec::new()
+                        }
+                    }
+                );
+
+                for ev in events.into_iter() {
+                    if let Some(ref mut f) = log {
+                        let t = time::at(time::Timespec::new(ev.time, 0));
+                        let line = writeln!(f, "{} {} {} ({}) via {}",
+                                            t.rfc3339(), ev.kind, ev.name, ev.mac, ev.source);
+                        if let Err(e) = line {
+                            println!("Couldn't write presence log: {}", e);
+                        }
+                    }
+                    ctl.recent.write().unwrap().push(ev.clone());
+                    ctl.mcast.send(Arc::new(PresenceMsg { typ: "presence", event: ev }));
+                }
+            }
+        }));
+
+        Ok(out)
+    }
+
+    fn sighting(&self, s: Sighting, name: &str) -> Vec<PresenceEvent> {
+        let mut map = self.map.write().unwrap();
+        let st = match map.entry(s.mac) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(PresenceState {
+                mac: s.mac,
+                name: name.to_string(),
+                present: false,
+                since: s.time,
+                last_seen: s.time,
+                source: s.source,
+                signal: None,
+                arrivals: 0,
+                departures: 0
+            })
+        };
+        //out of order, from a sensor that's behind
+        if s.time < st.last_seen {
+            return Vec::new();
+        }
+        st.last_seen = s.time;
+        st.source = s.source;
+        st.signal = s.signal.or(st.signal);
+        if st.present {
+            return Vec::new();
+        }
+        st.present = true;
+        st.since = s.time;
+        st.arrivals += 1;
+        vec![PresenceEvent::new(st, "arrived", s.time)]
+    }
+
+    /// Stations not heard from for their timeout as of now, which left
+    /// when the timeout ran out rather than when we noticed.
+    fn expire(&self, now: i64) -> Vec<PresenceEvent> {
+        let mut map = self.map.write().unwrap();
+        map.values_mut()
+            .filter(|st| st.present && now - st.last_seen >= self.conf.timeout(&st.mac))
+            .map(|st| {
+                let left = st.last_seen + self.conf.timeout(&st.mac);
+                st.present = false;
+                st.since = left;
+                st.departures += 1;
+                PresenceEvent::new(st, "departed", left)
+            })
+            .collect()
+    }
+
+    pub fn sender(&self) -> Sender<Sighting> {
+        self.sighting_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<PresenceMsg>>) {
+        self.mcast.register(s);
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use time;
+
+use multicast::Multicast;
+use fixed_ring::FixedRingBuffer;
+
+use util::checked_cast;
+use ether::MacAddr;
+use dot11::{self, ManagementFrameHeader};
+use ie::{self, Ies};
+use devices::{is_locally_administered, ProbeSighting};
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, ParseErr};
+
+// Stations looking for networks send probe requests, either for any network
+// (the wildcard, empty ssid) or by name for the ones they've joined before,
+// which gives away a good part of their preferred network list.
+
+pub struct ProbeReq {
+    pub mac: MacAddr,
+    pub ies: Ies,
+    pub signal: Option<i8>,
+    /// Capture time.
+    pub time: i64,
+    pub seq: u16,
+    pub fingerprint: u64,
+}
+
+pub fn parse_probe_req(frame: &[u8], signal: Option<i8>, time: i64) -> Option<ProbeReq> {
+    let hdr: &ManagementFrameHeader = match checked_cast(frame) {
+        Some(h) => h,
+        None => return None
+    };
+    let off = hdr.body_offset();
+    if frame.len() < off {
+        return None;
+    }
+    Some(ProbeReq {
+        mac: hdr.addr2,
+        ies: Ies::parse(&frame[off..]),
+        signal: signal,
+        time: time,
+        seq: hdr.seq().number(),
+        fingerprint: ie::fingerprint(&frame[off..])
+    })
+}
+
+#[derive(RustcEncodable, Copy, Clone, Debug)]
+pub struct SignalSample {
+    pub time: i64,
+    pub dbm: i8,
+}
+
+const SIGNAL_HISTORY_LEN: usize = 20;
+
+pub struct ClientInfo {
+    pub mac: MacAddr,
+    pub randomized: bool,
+    /// Directed probes, in the order we first saw them.
+    pub ssids: Vec<String>,
+    pub wildcard_probes: u32,
+    pub rates: Vec<f32>,
+    pub probes: u32,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub signals: FixedRingBuffer<SignalSample>,
+    last_sent: i64,
+}
+
+impl ClientInfo {
+    fn new(mac: MacAddr, now: i64) -> ClientInfo {
+        ClientInfo {
+            mac: mac,
+            randomized: is_locally_administered(&mac),
+            ssids: Vec::new(),
+            wildcard_probes: 0,
+            rates: Vec::new(),
+            probes: 0,
+            first_seen: now,
+            last_seen: now,
+            signals: FixedRingBuffer::new(SIGNAL_HISTORY_LEN),
+            last_sent: 0
+        }
+    }
+
+    /// Returns whether there's a new ssid.
+    fn update(&mut self, p: ProbeReq, now: i64) -> bool {
+        self.probes += 1;
+        //out of order, from a sensor that's behind
+        if now > self.last_seen {
+            self.last_seen = now;
+        }
+        if !p.ies.rates.is_empty() {
+            self.rates = p.ies.rates;
+        }
+        if let Some(dbm) = p.signal {
+            self.signals.push(SignalSample { time: now, dbm: dbm });
+        }
+        match p.ies.ssid {
+            Some(ssid) => {
+                if self.ssids.contains(&ssid) {
+                    false
+                } else {
+                    self.ssids.push(ssid);
+                    true
+                }
+            }
+            None => {
+                self.wildcard_probes += 1;
+                false
+            }
+        }
+    }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct ClientMsg {
+    typ: &'static str,
+    mac: MacAddr,
+    randomized: bool,
+    ssids: Vec<String>,
+    wildcard_probes: u32,
+    rates: Vec<f32>,
+    probes: u32,
+    first_seen: i64,
+    last_seen: i64,
+    signals: Vec<SignalSample>,
+}
+
+impl ClientMsg {
+    fn new(c: &ClientInfo) -> ClientMsg {
+        ClientMsg {
+            typ: "client",
+            mac: c.mac,
+            randomized: c.randomized,
+            ssids: c.ssids.clone(),
+            wildcard_probes: c.wildcard_probes,
+            rates: c.rates.clone(),
+            probes: c.probes,
+            first_seen: c.first_seen,
+            last_seen: c.last_seen,
+            signals: c.signals.iter().map(|s| *s).collect()
+        }
+    }
+}
+
+//Clients probe in bursts across every channel; don't update the ui for each one.
+const CLIENT_MSG_INTERVAL_SECS: i64 = 5;
+
+#[derive(Clone)]
+pub struct ProbeController {
+    pub map: Arc<RwLock<HashMap<MacAddr, ClientInfo>>>,
+    probe_tx: Sender<ProbeReq>,
+    mcast: Multicast<ClientMsg>,
+}
+
+impl ProbeController {
+    /// Each probe is passed on to devices, to work out who's behind
+    /// random addresses.
+    pub fn spawn(devices: Sender<ProbeSighting>) -> io::Result<ProbeController> {
+        let (probe_tx, probe_rx) = channel();
+        let out = ProbeController {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            probe_tx: probe_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("probe_handler".to_string()).spawn(move || {
+            loop {
+                let res = probe_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let p: ProbeReq = res.unwrap();
+                //the ui's throttled on wall time, everything else is capture time
+                let now = time::get_time().sec;
+                let seen = p.time;
+
+                let sighting = ProbeSighting {
+                    mac: p.mac,
+                    time: p.time,
+                    seq: p.seq,
+                    fingerprint: p.fingerprint,
+                    ssid: p.ies.ssid.clone()
+                };
+                if devices.send(sighting).is_err() {
+                    break
+                }
+
+                let mut map = ctl.map.write().unwrap();
+                let client = match map.entry(p.mac) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(ClientInfo::new(p.mac, seen))
+                };
+                let new_ssid = client.update(p, seen);
+                if new_ssid || now - client.last_sent >= CLIENT_MSG_INTERVAL_SECS {
+                    client.last_sent = now;
+                    ctl.mcast.send(Arc::new(ClientMsg::new(client)));
+                }
+            }
+        }));
+
+        Ok(out)
+    }
+
+    pub fn sender(&self) -> Sender<ProbeReq> {
+        self.probe_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<ClientMsg>>) {
+        self.mcast.register(s);
+    }
+}
+
+pub struct ProbeDissector {
+    probe_tx: Sender<ProbeReq>,
+}
+
+impl Dissector for ProbeDissector {
+    fn dissect(&self, _: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        let signal = ctx.radio.and_then(|r| r.signal);
+        match parse_probe_req(data, signal, ctx.time) {
+            Some(p) => Ok(try!(self.probe_tx.send(p))),
+            None => Err(ParseErr::UnknownPacket)
+        }
+    }
+}
+
+pub fn register_dissectors(reg: &mut DissectorRegistry, probe_tx: Sender<ProbeReq>) {
+    reg.register(DissectKey::Dot11Mgmt(dot11::MGMT_PROBE_REQ),
+                 Box::new(ProbeDissector { probe_tx: probe_tx }));
+}
@@ -0,0 +1,363 @@
+use util::{be_u32, checked_cast, le_u16, le_u32};
+use tap::{self, AntennaNoise, AntennaSignal, Channel, ChannelFlags, Mcs, RadioFields, Rate};
+
+// Radiotap is the usual way to get what the radio saw along with an 802.11
+// frame, but older drivers and tools wrote Prism or AVS headers instead, and
+// Kismet and AirPcap wrote PPI.  Each of these is turned into the same
+// RadioFields radiotap gives, plus how long the header was, so that from
+// the 802.11 header on it's all the same.
+
+/// What comes in front of the 802.11 frame, by link type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RadioHdr {
+    Radiotap,
+    Ppi,
+    /// Prism, or AVS saved under Prism's link type.
+    Prism,
+    Avs,
+    /// Nothing at all.
+    Raw,
+}
+
+impl RadioHdr {
+    /// The radio fields and how far in the 802.11 header starts.
+    pub fn parse(&self, data: &[u8]) -> Option<(RadioFields, usize)> {
+        match *self {
+            RadioHdr::Radiotap => tap::RadiotapIter::new(data).map(|it| {
+                let hdr: &tap::RadiotapHeader = checked_cast(data).unwrap();
+                (RadioFields::new(it), hdr.it_len as usize)
+            }),
+            RadioHdr::Ppi => parse_ppi(data),
+            RadioHdr::Prism => parse_prism(data),
+            RadioHdr::Avs => parse_avs(data),
+            RadioHdr::Raw => Some((RadioFields::empty(), 0))
+        }
+    }
+}
+
+/// Channel numbers to center frequencies, for headers that only give the
+/// number.  802.11-2012 Annex E.
+pub fn channel_mhz(ch: u32) -> Option<u16> {
+    match ch {
+        1...13 => Some(2407 + 5 * ch as u16),
+        14 => Some(2484),
+        32...177 => Some(5000 + 5 * ch as u16),
+        _ => None
+    }
+}
+
+fn channel(mhz: u16) -> Channel {
+    Channel {
+        mhz: mhz,
+        flags: if mhz < 3000 { tap::GHZ_2 } else { tap::GHZ_5 }
+    }
+}
+
+fn rate(in_500kbps: u32) -> Option<Rate> {
+    match in_500kbps {
+        1...255 => Some(Rate { in_500kbps: in_500kbps as u8 }),
+        _ => None
+    }
+}
+
+// PPI: a fixed header, then (type, length, data) fields, all little-endian.
+// "Per-Packet Information Header Specification" 1.0.10
+const PPI_HDR_LEN: usize = 8;
+const PPI_FIELD_HDR_LEN: usize = 4;
+const PPI_DLT_IEEE802_11: u32 = 105;
+const PPI_80211_COMMON: u16 = 2;
+const PPI_80211N_MAC_PHY: u16 = 4;
+const PPI_COMMON_LEN: usize = 20;
+const PPI_N_MAC_PHY_LEN: usize = 48;
+
+// 802.11-Common flags
+const PPI_FCS_PRESENT: u16 = 0x0001;
+const PPI_FCS_ERROR: u16 = 0x0004;
+const PPI_PHY_ERROR: u16 = 0x0008;
+
+// 802.11n MAC+PHY flags
+const PPI_N_HT40: u32 = 0x0002;
+const PPI_N_SHORT_GI: u32 = 0x0004;
+
+pub fn parse_ppi(data: &[u8]) -> Option<(RadioFields, usize)> {
+    if data.len() < PPI_HDR_LEN || data[0] != 0 {
+        return None;
+    }
+    let hdr_len = le_u16(&data[2..]) as usize;
+    if hdr_len < PPI_HDR_LEN || data.len() < hdr_len || le_u32(&data[4..]) != PPI_DLT_IEEE802_11 {
+        return None;
+    }
+
+    let mut r = RadioFields::empty();
+    let mut fields = &data[PPI_HDR_LEN..hdr_len];
+    while fields.len() >= PPI_FIELD_HDR_LEN {
+        let typ = le_u16(fields);
+        let len = le_u16(&fields[2..]) as usize;
+        if fields.len() < PPI_FIELD_HDR_LEN + len {
+            break;
+        }
+        let f = &fields[PPI_FIELD_HDR_LEN..PPI_FIELD_HDR_LEN + len];
+        match typ {
+            PPI_80211_COMMON if len >= PPI_COMMON_LEN => {
+                let flags = le_u16(&f[8..]);
+                let mut tf = tap::Flags::empty();
+                if flags & PPI_FCS_PRESENT != 0 { tf = tf | tap::INCLUDES_FCS; }
+                if flags & (PPI_FCS_ERROR | PPI_PHY_ERROR) != 0 { tf = tf | tap::FAILED_FCS_CHK; }
+                r.flags = Some(tf);
+                r.rate = rate(le_u16(&f[10..]) as u32);
+                let mhz = le_u16(&f[12..]);
+                if mhz != 0 {
+                    r.channel = Some(Channel {
+                        mhz: mhz,
+                        flags: ChannelFlags::from_bits_truncate(le_u16(&f[14..]))
+                    });
+                }
+                //-128 means not given
+                if f[18] as i8 != -128 { r.antenna_signal = Some(AntennaSignal { dbm: f[18] as i8 }); }
+                if f[19] as i8 != -128 { r.antenna_noise = Some(AntennaNoise { dbm: f[19] as i8 }); }
+            }
+            PPI_80211N_MAC_PHY if len >= PPI_N_MAC_PHY_LEN => {
+                let flags = le_u32(f);
+                let mut mcs_flags = 0;
+                if flags & PPI_N_HT40 != 0 { mcs_flags |= 0x01; }
+                if flags & PPI_N_SHORT_GI != 0 { mcs_flags |= 0x04; }
+                //mcs 255 means not given
+                if f[9] != 255 {
+                    r.phy_rate = Mcs { known: 0x07, flags: mcs_flags, mcs: f[9] }.phy_rate();
+                }
+            }
+            _ => {}
+        }
+        //fields are padded to 32 bits when the header says so
+        let mut next = PPI_FIELD_HDR_LEN + len;
+        if data[1] & 0x01 != 0 {
+            next = (next + 3) & !3;
+        }
+        fields = if fields.len() > next { &fields[next..] } else { &[] };
+    }
+    if r.phy_rate.is_none() {
+        r.phy_rate = r.rate.map(|x| x.phy_rate());
+    }
+    Some((r, hdr_len))
+}
+
+// Prism (wlan-ng) headers: message code and length, device name, then ten
+// (did, status, len, data) items, host byte order, in this order: hosttime,
+// mactime, channel, rssi, sq, signal, noise, rate, istx, frmlen.
+const PRISM_HDR_LEN: usize = 144;
+const PRISM_ITEMS_OFF: usize = 24;
+const PRISM_ITEM_LEN: usize = 12;
+const PRISM_CHANNEL: usize = 2;
+const PRISM_SIGNAL: usize = 5;
+const PRISM_NOISE: usize = 6;
+const PRISM_RATE: usize = 7;
+
+/// Files saved as Prism sometimes have AVS headers instead, which start with
+/// a version in this range.
+const AVS_MAGIC_MASK: u32 = 0xfffffff0;
+const AVS_MAGIC: u32 = 0x80211000;
+
+pub fn parse_prism(data: &[u8]) -> Option<(RadioFields, usize)> {
+    if data.len() >= 4 && be_u32(data) & AVS_MAGIC_MASK == AVS_MAGIC {
+        return parse_avs(data);
+    }
+    if data.len() < PRISM_HDR_LEN {
+        return None;
+    }
+    let hdr_len = le_u32(&data[4..]) as usize;
+    if hdr_len < PRISM_HDR_LEN || data.len() < hdr_len {
+        return None;
+    }
+    //status 0 means the driver filled it in
+    let item = |i: usize| {
+        let off = PRISM_ITEMS_OFF + i * PRISM_ITEM_LEN;
+        if le_u16(&data[off+4..]) == 0 { Some(le_u32(&data[off+8..])) } else { None }
+    };
+    //signal and noise are only dBm on drivers that report them negative
+    let dbm = |v: Option<u32>| v.map(|v| v as i32).and_then(|v| {
+        if v < 0 && v >= -127 { Some(v as i8) } else { None }
+    });
+
+    let mut r = RadioFields::empty();
+    r.channel = item(PRISM_CHANNEL).and_then(channel_mhz).map(channel);
+    r.antenna_signal = dbm(item(PRISM_SIGNAL)).map(|d| AntennaSignal { dbm: d });
+    r.antenna_noise = dbm(item(PRISM_NOISE)).map(|d| AntennaNoise { dbm: d });
+    r.rate = item(PRISM_RATE).and_then(rate);
+    r.phy_rate = r.rate.map(|x| x.phy_rate());
+    Some((r, hdr_len))
+}
+
+// AVS (wlan-ng capture format v1/v2): all big-endian.
+const AVS_HDR_MIN_LEN: usize = 64;
+const AVS_SSI_DBM: u32 = 2;
+
+pub fn parse_avs(data: &[u8]) -> Option<(RadioFields, usize)> {
+    if data.len() < AVS_HDR_MIN_LEN || be_u32(data) & AVS_MAGIC_MASK != AVS_MAGIC {
+        return None;
+    }
+    let hdr_len = be_u32(&data[4..]) as usize;
+    if hdr_len < AVS_HDR_MIN_LEN || data.len() < hdr_len {
+        return None;
+    }
+    let mut r = RadioFields::empty();
+    r.channel = channel_mhz(be_u32(&data[28..])).map(channel);
+    //in 100kbps
+    r.rate = rate(be_u32(&data[32..]) / 5);
+    if be_u32(&data[44..]) == AVS_SSI_DBM {
+        let dbm = |v: u32| match v as i32 {
+            v @ -127...127 => Some(v as i8),
+            _ => None
+        };
+        r.antenna_signal = dbm(be_u32(&data[48..])).map(|d| AntennaSignal { dbm: d });
+        r.antenna_noise = dbm(be_u32(&data[52..])).map(|d| AntennaNoise { dbm: d });
+    }
+    r.phy_rate = r.rate.map(|x| x.phy_rate());
+    Some((r, hdr_len))
+}
+
+// What a wlan-ng driver hands over: channel 6, rssi 40, signal -50 dBm,
+// noise -95 dBm, 11 Mbps.
+#[cfg(test)]
+fn prism_hdr() -> Vec<u8> {
+    fn le(v: u32) -> Vec<u8> {
+        vec![v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+    }
+    let mut h = Vec::new();
+    h.extend(le(0x00000044).into_iter());
+    h.extend(le(PRISM_HDR_LEN as u32).into_iter());
+    let mut dev = b"wlan0".to_vec();
+    while dev.len() < 16 {
+        dev.push(0);
+    }
+    h.extend(dev.into_iter());
+    let items = [1000, 2000, 6, 40, 0, -50i32 as u32, -95i32 as u32, 22, 0, 100];
+    for (i, &v) in items.iter().enumerate() {
+        h.extend(le(((i as u32 + 1) << 16) | 0x0044).into_iter());
+        h.extend(vec![0, 0, 4, 0].into_iter());
+        h.extend(le(v).into_iter());
+    }
+    h
+}
+
+#[test]
+fn test_parse_prism() {
+    let mut data = prism_hdr();
+    assert_eq!(data.len(), PRISM_HDR_LEN);
+    //and the start of a frame
+    data.extend(vec![0x80, 0, 0, 0].into_iter());
+
+    let (r, hdr_len) = parse_prism(&data).unwrap();
+    assert_eq!(hdr_len, PRISM_HDR_LEN);
+    assert_eq!(r.mhz(), Some(2437));
+    assert_eq!(r.signal(), Some(-50));
+    assert_eq!(r.antenna_noise.map(|n| n.dbm), Some(-95));
+    assert_eq!(r.rate.map(|x| x.in_500kbps), Some(22));
+}
+
+#[test]
+fn test_parse_prism_missing_items() {
+    let mut data = prism_hdr();
+    //the driver didn't fill in the channel
+    let status = PRISM_ITEMS_OFF + PRISM_CHANNEL * PRISM_ITEM_LEN + 4;
+    data[status] = 1;
+    let (r, _) = parse_prism(&data).unwrap();
+    assert_eq!(r.mhz(), None);
+    assert_eq!(r.signal(), Some(-50));
+}
+
+// A PPI header as Kismet writes it, 32-bit aligned: 802.11-Common (FCS
+// present, 54 Mbps, channel 6, -60/-90 dBm), a 3 byte field we don't know,
+// padded to 4, then 802.11n MAC+PHY with MCS 7, 40 MHz and short GI.
+#[cfg(test)]
+fn ppi_hdr() -> Vec<u8> {
+    fn le16(v: u16) -> Vec<u8> {
+        vec![v as u8, (v >> 8) as u8]
+    }
+    let mut common = vec![0; PPI_COMMON_LEN];
+    common[8] = PPI_FCS_PRESENT as u8;
+    common[10] = 108;
+    common[12] = (2437 & 0xff) as u8;
+    common[13] = (2437 >> 8) as u8;
+    common[14] = 0xc0; //2GHz, OFDM
+    common[18] = -60i8 as u8;
+    common[19] = -90i8 as u8;
+
+    let mut n = vec![0; PPI_N_MAC_PHY_LEN];
+    n[0] = (PPI_N_HT40 | PPI_N_SHORT_GI) as u8;
+    n[9] = 7;
+
+    let mut fields = Vec::new();
+    fields.extend(le16(PPI_80211_COMMON).into_iter());
+    fields.extend(le16(PPI_COMMON_LEN as u16).into_iter());
+    fields.extend(common.into_iter());
+    fields.extend(le16(30000).into_iter());
+    fields.extend(le16(3).into_iter());
+    fields.extend(vec![1, 2, 3, 0].into_iter());
+    fields.extend(le16(PPI_80211N_MAC_PHY).into_iter());
+    fields.extend(le16(PPI_N_MAC_PHY_LEN as u16).into_iter());
+    fields.extend(n.into_iter());
+
+    let mut h = vec![0, 0x01];
+    h.extend(le16((PPI_HDR_LEN + fields.len()) as u16).into_iter());
+    h.extend(vec![PPI_DLT_IEEE802_11 as u8, 0, 0, 0].into_iter());
+    h.extend(fields.into_iter());
+    h
+}
+
+#[test]
+fn test_parse_ppi() {
+    let mut data = ppi_hdr();
+    let len = data.len();
+    data.extend(vec![0x80, 0, 0, 0].into_iter());
+
+    let (r, hdr_len) = parse_ppi(&data).unwrap();
+    assert_eq!(hdr_len, len);
+    assert!(r.has_flag(tap::INCLUDES_FCS));
+    assert!(!r.has_flag(tap::FAILED_FCS_CHK));
+    assert_eq!(r.mhz(), Some(2437));
+    assert_eq!(r.signal(), Some(-60));
+    assert_eq!(r.antenna_noise.map(|n| n.dbm), Some(-90));
+    assert_eq!(r.rate.map(|x| x.in_500kbps), Some(108));
+    //the 11n field, past the padding, wins over the legacy rate
+    let p = r.phy_rate.unwrap();
+    assert_eq!(p.phy, "ht");
+    assert_eq!(p.mcs, Some(7));
+    assert_eq!(p.bandwidth, Some(40));
+    assert_eq!(p.guard_ns, Some(400));
+}
+
+// An AVS v2 header: channel 36, 54 Mbps, -55 dBm signal and -92 dBm noise.
+#[cfg(test)]
+fn avs_hdr() -> Vec<u8> {
+    fn be(v: u32) -> Vec<u8> {
+        vec![(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+    }
+    let mut h = Vec::new();
+    h.extend(be(0x80211002).into_iter());
+    h.extend(be(AVS_HDR_MIN_LEN as u32).into_iter());
+    //mactime, hosttime
+    h.extend(vec![0; 16].into_iter());
+    let items = [1, 36, 540, 0, 0, AVS_SSI_DBM, -55i32 as u32, -92i32 as u32, 0, 0];
+    for &v in items.iter() {
+        h.extend(be(v).into_iter());
+    }
+    h
+}
+
+#[test]
+fn test_parse_avs() {
+    let mut data = avs_hdr();
+    assert_eq!(data.len(), AVS_HDR_MIN_LEN);
+    data.extend(vec![0x80, 0, 0, 0].into_iter());
+
+    let (r, hdr_len) = parse_avs(&data).unwrap();
+    assert_eq!(hdr_len, AVS_HDR_MIN_LEN);
+    assert_eq!(r.mhz(), Some(5180));
+    assert_eq!(r.rate.map(|x| x.in_500kbps), Some(108));
+    assert_eq!(r.signal(), Some(-55));
+    assert_eq!(r.antenna_noise.map(|n| n.dbm), Some(-92));
+
+    //and when it's been saved under Prism's link type
+    let (p, _) = parse_prism(&data).unwrap();
+    assert_eq!(p.mhz(), Some(5180));
+}
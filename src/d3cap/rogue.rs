@@ -0,0 +1,136 @@
+use toml;
+
+use ether::MacAddr;
+use ap::ApInfo;
+
+// Our own networks, as they should look on the air.  Anything beaconing one
+// of their SSIDs from somewhere else, with weaker security, or on the wrong
+// channel is either misconfigured or somebody's evil twin.
+
+#[derive(Clone, Debug)]
+pub struct Network {
+    pub ssid: String,
+    /// Empty means any BSSID is fine.
+    pub bssids: Vec<MacAddr>,
+    /// Empty means any channel is fine.
+    pub channels: Vec<u8>,
+    /// As ap::security_mode names it.
+    pub security: Option<String>,
+}
+
+/// Reads the [[networks]] array, e.g.:
+///
+///     [[networks]]
+///     ssid = "corp"
+///     bssids = ["00:11:22:33:44:50", "00:11:22:33:44:51"]
+///     channels = [1, 6, 36]
+///     security = "WPA2-Enterprise"
+pub fn networks_from_toml(conf: &toml::Table) -> Vec<Network> {
+    let list = match conf.get("networks").and_then(|v| v.as_slice()) {
+        Some(l) => l,
+        None => return Vec::new()
+    };
+    list.iter().filter_map(|v| {
+        let tbl = match v.as_table() {
+            Some(t) => t,
+            None => return None
+        };
+        let ssid = match tbl.get("ssid").and_then(|v| v.as_str()) {
+            Some(s) => s.to_string(),
+            None => {
+                println!("Ignoring network without an ssid");
+                return None
+            }
+        };
+        let items = |key: &str| tbl.get(key).and_then(|v| v.as_slice()).unwrap_or(&[]);
+        let security = tbl.get("security").and_then(|v| v.as_str()).map(|s| s.to_string());
+        if let Some(ref s) = security {
+            if security_rank(s).is_none() {
+                println!("Unknown security \"{}\" for network {}", s, ssid);
+            }
+        }
+        Some(Network {
+            bssids: items("bssids").iter()
+                .filter_map(|b| b.as_str().and_then(MacAddr::from_string))
+                .collect(),
+            channels: items("channels").iter()
+                .filter_map(|c| c.as_integer().map(|c| c as u8))
+                .collect(),
+            security: security,
+            ssid: ssid
+        })
+    }).collect()
+}
+
+/// How a station gets in: no credentials, a WEP key, a shared passphrase,
+/// or its own.  An AP offering a different one than we run is wrong
+/// whichever way it goes; a PSK network turning up as Enterprise is a
+/// credential harvester.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum AuthFamily {
+    Open,
+    Wep,
+    Psk,
+    Enterprise,
+}
+
+/// The family of each of ap::security_mode's answers, and roughly how hard
+/// it is to get into within that family, so "weaker than expected" means
+/// something.
+fn security_rank(mode: &str) -> Option<(AuthFamily, u8)> {
+    Some(match mode {
+        "Open" => (AuthFamily::Open, 0),
+        "OWE" => (AuthFamily::Open, 1),
+        "WEP" => (AuthFamily::Wep, 0),
+        "WPA-PSK" => (AuthFamily::Psk, 0),
+        "WPA/WPA2-PSK" => (AuthFamily::Psk, 1),
+        "WPA2-PSK" => (AuthFamily::Psk, 2),
+        "WPA2/WPA3-SAE" => (AuthFamily::Psk, 3),
+        "WPA3-SAE" => (AuthFamily::Psk, 4),
+        "WPA-Enterprise" => (AuthFamily::Enterprise, 0),
+        "WPA/WPA2-Enterprise" => (AuthFamily::Enterprise, 1),
+        "WPA2-Enterprise" => (AuthFamily::Enterprise, 2),
+        _ => return None
+    })
+}
+
+/// What's wrong with an AP claiming one of our SSIDs, as (alert kind,
+/// message) pairs.
+pub fn check(ap: &ApInfo, networks: &[Network]) -> Vec<(&'static str, String)> {
+    let ssid = match ap.ssid {
+        Some(ref s) => s,
+        None => return Vec::new()
+    };
+    let net = match networks.iter().find(|n| n.ssid == *ssid) {
+        Some(n) => n,
+        None => return Vec::new()
+    };
+    let mut out = Vec::new();
+    if !net.bssids.is_empty() && !net.bssids.contains(&ap.bssid) {
+        out.push(("rogue-ap", format!("\"{}\" is being beaconed from unknown BSSID {}",
+                                      ssid, ap.bssid)));
+    }
+    if let Some(ref expected) = net.security {
+        match (security_rank(ap.security), security_rank(expected)) {
+            (Some((seen_fam, _)), Some((want_fam, _))) if seen_fam != want_fam => {
+                out.push(("security-mismatch", format!("\"{}\" from {} offers {}, expected {}",
+                                                       ssid, ap.bssid, ap.security, expected)));
+            }
+            (Some((_, seen)), Some((_, want))) if seen < want => {
+                out.push(("weak-security", format!("\"{}\" from {} offers {}, expected {}",
+                                                   ssid, ap.bssid, ap.security, expected)));
+            }
+            _ => {}
+        }
+    }
+    if let Some(ch) = ap.channel {
+        if !net.channels.is_empty() && !net.channels.contains(&ch) {
+            out.push(("unexpected-channel", format!("\"{}\" from {} is on channel {}, expected {}",
+                                                    ssid, ap.bssid, ch,
+                                                    net.channels.iter()
+                                                        .map(|c| c.to_string())
+                                                        .collect::<Vec<_>>().connect(", "))));
+        }
+    }
+    out
+}
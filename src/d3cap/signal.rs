@@ -0,0 +1,303 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::VecDeque;
+use std::cmp;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use time;
+use toml;
+
+use multicast::Multicast;
+
+use ether::MacAddr;
+
+// Each station's signal over time, rolled up into fixed intervals so an
+// hour of a busy station doesn't mean keeping every frame.
+
+#[derive(Copy, Clone, Debug)]
+pub struct SignalConf {
+    pub interval_secs: i64,
+    pub retention_secs: i64,
+}
+
+impl SignalConf {
+    pub fn new() -> SignalConf {
+        SignalConf { interval_secs: 10, retention_secs: 3600 }
+    }
+
+    /// Reads the [signal] table, e.g.:
+    ///
+    ///     [signal]
+    ///     interval-secs = 10
+    ///     retention-secs = 3600
+    pub fn from_toml(conf: &toml::Table) -> SignalConf {
+        let mut out = SignalConf::new();
+        if let Some(tbl) = conf.get("signal").and_then(|t| t.as_table()) {
+            let secs = |key: &str| match tbl.get(key).and_then(|v| v.as_integer()) {
+                Some(s) if s > 0 => Some(s),
+                _ => None
+            };
+            out.interval_secs = secs("interval-secs").unwrap_or(out.interval_secs);
+            out.retention_secs = secs("retention-secs").unwrap_or(out.retention_secs);
+        }
+        out
+    }
+}
+
+pub struct SignalSample {
+    pub sensor: usize,
+    /// Capture time.
+    pub time: i64,
+    pub mac: MacAddr,
+    pub dbm: i8,
+    pub noise: Option<i8>,
+}
+
+#[derive(RustcEncodable, Copy, Clone, Debug)]
+pub struct SignalBucket {
+    pub start: i64,
+    pub min: i8,
+    pub max: i8,
+    pub avg: f32,
+    pub noise: Option<f32>,
+    pub samples: u32,
+    sum: i64,
+    noise_sum: i64,
+    noise_samples: u32,
+}
+
+impl SignalBucket {
+    fn new(start: i64) -> SignalBucket {
+        SignalBucket {
+            start: start, min: 0, max: 0, avg: 0.0, noise: None, samples: 0,
+            sum: 0, noise_sum: 0, noise_samples: 0
+        }
+    }
+
+    fn add(&mut self, s: &SignalSample) {
+        if self.samples == 0 || s.dbm < self.min { self.min = s.dbm; }
+        if self.samples == 0 || s.dbm > self.max { self.max = s.dbm; }
+        self.samples += 1;
+        self.sum += s.dbm as i64;
+        self.avg = self.sum as f32 / self.samples as f32;
+        if let Some(n) = s.noise {
+            self.noise_samples += 1;
+            self.noise_sum += n as i64;
+            self.noise = Some(self.noise_sum as f32 / self.noise_samples as f32);
+        }
+    }
+
+    /// Roll several consecutive buckets into one, for showing a long history
+    /// in a little space.
+    pub fn merge(buckets: &[SignalBucket]) -> SignalBucket {
+        let mut out = SignalBucket::new(buckets.first().map(|b| b.start).unwrap_or(0));
+        for b in buckets.iter().filter(|b| b.samples > 0) {
+            if out.samples == 0 || b.min < out.min { out.min = b.min; }
+            if out.samples == 0 || b.max > out.max { out.max = b.max; }
+            out.samples += b.samples;
+            out.sum += b.sum;
+            out.noise_samples += b.noise_samples;
+            out.noise_sum += b.noise_sum;
+        }
+        if out.samples > 0 {
+            out.avg = out.sum as f32 / out.samples as f32;
+        }
+        if out.noise_samples > 0 {
+            out.noise = Some(out.noise_sum as f32 / out.noise_samples as f32);
+        }
+        out
+    }
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct SignalMsg {
+    typ: &'static str,
+    sensor: usize,
+    mac: MacAddr,
+    interval_secs: i64,
+    bucket: SignalBucket,
+}
+
+#[derive(Clone)]
+pub struct SignalController {
+    pub conf: SignalConf,
+    /// Oldest first, keyed by sensor and transmitter.
+    pub map: Arc<RwLock<HashMap<(usize, MacAddr), VecDeque<SignalBucket>>>>,
+    signal_tx: Sender<SignalSample>,
+    mcast: Multicast<SignalMsg>,
+}
+
+impl SignalController {
+    pub fn spawn(conf: SignalConf) -> io::Result<SignalController> {
+        let (signal_tx, signal_rx) = channel();
+        let (tick_tx, tick_rx) = channel();
+        let out = SignalController {
+            conf: conf,
+            map: Arc::new(RwLock::new(HashMap::new())),
+            signal_tx: signal_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        let interval_ms = conf.interval_secs as u32 * 1000;
+        try!(thread::Builder::new().name("signal_timer".to_string()).spawn(move || {
+            loop {
+                thread::sleep_ms(interval_ms);
+                if tick_tx.send(()).is_err() {
+                    break
+                }
+            }
+        }));
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("signal_handler".to_string()).spawn(move || {
+            //Capture time, run on at wall speed between samples, as in presence.
+            let mut clock: Option<(i64, i64)> = None;
+            loop {
+                select!(
+                    s = signal_rx.recv() => {
+                        if s.is_err() {
+                            break
+                        }
+                        let s: SignalSample = s.unwrap();
+                        if clock.map(|(latest, _)| s.time > latest).unwrap_or(true) {
+                            clock = Some((s.time, time::get_time().sec));
+                        }
+                        ctl.sample(s)
+                    },
+                    _ = tick_rx.recv() => {
+                        if let Some((latest, seen_at)) = clock {
+                            ctl.prune(latest + time::get_time().sec - seen_at)
+                        }
+                    }
+                )
+            }
+        }));
+
+        Ok(out)
+    }
+
+    fn sample(&self, s: SignalSample) {
+        let interval = self.conf.interval_secs;
+        let start = s.time - s.time % interval;
+
+        let mut map = self.map.write().unwrap();
+        let buckets = match map.entry((s.sensor, s.mac)) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(VecDeque::new())
+        };
+
+        let latest = buckets.back().map(|b| b.start);
+        match latest {
+            Some(l) if start < l => {
+                //late, from a sensor that's behind; fill in if we still have it
+                if let Some(b) = buckets.iter_mut().find(|b| b.start == start) {
+                    b.add(&s);
+                }
+                return
+            }
+            Some(l) if start == l => {}
+            _ => {
+                //the last one's done, pass it on
+                if let Some(done) = buckets.back() {
+                    self.mcast.send(Arc::new(SignalMsg {
+                        typ: "signal",
+                        sensor: s.sensor,
+                        mac: s.mac,
+                        interval_secs: interval,
+                        bucket: *done
+                    }));
+                }
+                //empty ones for the intervals it wasn't heard in, so gaps
+                //show as gaps; no more than we'd keep
+                if let Some(l) = latest {
+                    let first = cmp::max(l + interval, start - self.conf.retention_secs + interval);
+                    let mut gap = first;
+                    while gap < start {
+                        buckets.push_back(SignalBucket::new(gap));
+                        gap += interval;
+                    }
+                }
+                buckets.push_back(SignalBucket::new(start));
+            }
+        }
+        buckets.back_mut().unwrap().add(&s);
+
+        while buckets.front().map(|b| start - b.start >= self.conf.retention_secs).unwrap_or(false) {
+            buckets.pop_front();
+        }
+    }
+
+    /// Drop buckets past retention as of now, and stations with none left.
+    fn prune(&self, now: i64) {
+        let mut map = self.map.write().unwrap();
+        let mut gone = Vec::new();
+        for (key, buckets) in map.iter_mut() {
+            while buckets.front().map(|b| now - b.start >= self.conf.retention_secs).unwrap_or(false) {
+                buckets.pop_front();
+            }
+            if buckets.is_empty() {
+                gone.push(*key);
+            }
+        }
+        for key in gone.iter() {
+            map.remove(key);
+        }
+    }
+
+    /// The last secs worth of buckets for a station, from each sensor that
+    /// heard it, counting back from the latest one.
+    pub fn history(&self, mac: &MacAddr, secs: i64) -> Vec<(usize, Vec<SignalBucket>)> {
+        let map = self.map.read().unwrap();
+        let mut out: Vec<_> = map.iter()
+            .filter(|&(&(_, m), _)| m == *mac)
+            .map(|(&(sensor, _), buckets)| {
+                let latest = buckets.back().map(|b| b.start).unwrap_or(0);
+                (sensor, buckets.iter().filter(|b| latest - b.start < secs).cloned().collect())
+            })
+            .collect();
+        out.sort_by(|a, b| a.0.cmp(&b.0));
+        out
+    }
+
+    pub fn sender(&self) -> Sender<SignalSample> {
+        self.signal_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<SignalMsg>>) {
+        self.mcast.register(s);
+    }
+}
+
+const SPARK_LEVELS: &'static [u8] = b"_.-~=+*#";
+
+/// One character per bucket, low signal to high, scaled to the range shown.
+pub fn sparkline(buckets: &[SignalBucket]) -> String {
+    let (mut lo, mut hi) = (0.0, 0.0);
+    for (i, b) in buckets.iter().filter(|b| b.samples > 0).enumerate() {
+        if i == 0 || b.avg < lo { lo = b.avg; }
+        if i == 0 || b.avg > hi { hi = b.avg; }
+    }
+    let top = (SPARK_LEVELS.len() - 1) as f32;
+    buckets.iter().map(|b| {
+        if b.samples == 0 {
+            ' '
+        } else if hi - lo < 1.0 {
+            SPARK_LEVELS[SPARK_LEVELS.len() / 2] as char
+        } else {
+            SPARK_LEVELS[((b.avg - lo) / (hi - lo) * top).round() as usize] as char
+        }
+    }).collect()
+}
+
+/// "90", "90s", "30m", "2h" to seconds.
+pub fn parse_duration(s: &str) -> Option<i64> {
+    let (num, mult) = match s.chars().last() {
+        Some('s') => (&s[..s.len()-1], 1),
+        Some('m') => (&s[..s.len()-1], 60),
+        Some('h') => (&s[..s.len()-1], 3600),
+        _ => (s, 1)
+    };
+    num.parse::<i64>().ok().map(|n| n * mult)
+}
@@ -3,7 +3,7 @@
 use std::mem::size_of;
 use std::fmt;
 
-use util::skip_cast;
+use util::{checked_cast, le_u16, le_u32, le_u64};
 
 //For possible reference:
 //https://github.com/simsong/tcpflow/blob/master/src/wifipcap/ieee802_11_radio.h
@@ -40,23 +40,12 @@ bitflags!(flags ItPresent: u32 {
     const MCS               = 1 << 19,
     const A_MPDU_STATUS     = 1 << 20,
     const VHT               = 1 << 21,
-    const MORE_IT_PRESENT   = 1 << 31,
-
-    const COMMON_A          = TSFT.bits
-                            | FLAGS.bits
-                            | RATE.bits
-                            | CHANNEL.bits
-                            | ANTENNA_SIGNAL.bits
-                            | ANTENNA_NOISE.bits
-                            | ANTENNA.bits,
-
-    const COMMON_B          = TSFT.bits
-                            | FLAGS.bits
-                            | CHANNEL.bits
-                            | ANTENNA_SIGNAL.bits
-                            | ANTENNA_NOISE.bits
-                            | ANTENNA.bits
-                            | MCS.bits
+    const TIMESTAMP         = 1 << 22,
+    const HE                = 1 << 23,
+    const HE_MU             = 1 << 24,
+    const RADIOTAP_NS       = 1 << 29,
+    const VENDOR_NS         = 1 << 30,
+    const MORE_IT_PRESENT   = 1 << 31
 });
 
 impl fmt::Debug for ItPresent {
@@ -82,6 +71,12 @@ bitflags!(flags Flags: u8 {
     const SHORT_GUARD    = 0x80
 });
 
+impl fmt::Debug for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}", self.bits)
+    }
+}
+
 #[derive(Copy, Debug)]
 #[repr(packed)]
 pub struct Rate {
@@ -138,60 +133,488 @@ pub struct Mcs {
     pub mcs: u8
 }
 
-// For now just predefining a few types of packets I actually see with my setup,
-// rather than defining a general parser.
-#[derive(Copy)]
-#[repr(packed)]
-pub struct CommonA {
-    pub tsft: Tsft,  // 8
-    pub flags: Flags, // 1
-    pub rate: Rate, // 1
-    pub channel: Channel, // 2 + 2 = 4
-    pub antenna_signal: AntennaSignal, // 1
-    pub antenna_noise: AntennaNoise, // 1
-    pub antenna: Antenna // 1
-}
-// sizeof should be 17.
-
-impl CommonA {
-    pub fn parse(hdr: &RadiotapHeader) -> Option<&CommonA> {
-        let sz = size_of::<RadiotapHeader>() + size_of::<CommonA>();
-        if hdr.it_present == COMMON_A
-        && hdr.it_len as usize >= sz {
-            let out: &CommonA = unsafe { skip_cast(hdr) };
-            Some(out)
-        } else {
-            None
+#[derive(Copy, Debug)]
+pub struct Vht {
+    pub known: u16,
+    pub flags: u8,
+    pub bandwidth: u8,
+    pub mcs_nss: [u8; 4],
+    pub coding: u8,
+    pub group_id: u8,
+    pub partial_aid: u16
+}
+
+#[derive(Copy, Debug)]
+pub struct He {
+    pub data: [u16; 6]
+}
+
+/// What a frame was actually sent at, worked out from whichever of the
+/// legacy rate, MCS, VHT or HE fields the driver gave us.
+#[derive(RustcEncodable, Copy, Clone, PartialEq, Debug)]
+pub struct PhyRate {
+    pub phy: &'static str,
+    pub mcs: Option<u8>,
+    pub nss: u8,
+    pub bandwidth: Option<u16>, //MHz; None for HE resource units smaller than a channel
+    pub guard_ns: Option<u16>,
+    pub mbps: f32,
+}
+
+impl PhyRate {
+    /// Same modulation and coding, regardless of the resulting rate.
+    pub fn same_mode(&self, o: &PhyRate) -> bool {
+        self.phy == o.phy && self.mcs == o.mcs && self.nss == o.nss
+            && self.bandwidth == o.bandwidth && self.guard_ns == o.guard_ns
+            && (self.mcs.is_some() || self.mbps == o.mbps)
+    }
+}
+
+impl fmt::Display for PhyRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:.1} Mbps", self.mbps));
+        if let Some(mcs) = self.mcs {
+            try!(write!(f, " ({} mcs{} {}ss", self.phy, mcs, self.nss));
+            if let Some(bw) = self.bandwidth {
+                try!(write!(f, " {}MHz", bw));
+            }
+            if let Some(gi) = self.guard_ns {
+                try!(write!(f, " {}ns", gi));
+            }
+            try!(write!(f, ")"));
         }
+        Ok(())
     }
 }
 
-#[repr(packed)]
-pub struct CommonB {
-    pub tsft: Tsft,
-    pub flags: Flags,
-    pub channel: Channel,
-    pub antenna_signal: AntennaSignal,
-    pub antenna_noise: AntennaNoise,
-    pub antenna: Antenna,
-    pub mcs: Mcs
-}
-
-impl CommonB {
-    pub fn parse(hdr: &RadiotapHeader) -> Option<&CommonB> {
-        let sz = size_of::<RadiotapHeader>() + size_of::<CommonB>();
-        if hdr.it_present == COMMON_B
-        && hdr.it_len as usize >= sz {
-            let out: &CommonB = unsafe { skip_cast(hdr) };
-            Some(out)
+// (coded bits per subcarrier, coding rate) for each MCS, which is the same
+// table for HT (per stream), VHT and HE.
+const MCS_MODULATION: [(f32, f32); 12] = [
+    (1.0, 1.0/2.0), (2.0, 1.0/2.0), (2.0, 3.0/4.0), (4.0, 1.0/2.0),
+    (4.0, 3.0/4.0), (6.0, 2.0/3.0), (6.0, 3.0/4.0), (6.0, 5.0/6.0),
+    (8.0, 3.0/4.0), (8.0, 5.0/6.0), (10.0, 3.0/4.0), (10.0, 5.0/6.0)
+];
+
+// data subcarriers * bits per subcarrier * coding rate * streams / symbol time
+fn ofdm_mbps(mcs: u8, nss: u8, data_subcarriers: u16, symbol_us: f32) -> Option<f32> {
+    MCS_MODULATION.get(mcs as usize).map(|&(bits, rate)| {
+        data_subcarriers as f32 * bits * rate * nss as f32 / symbol_us
+    })
+}
+
+// HT and VHT data subcarriers by bandwidth
+fn vht_subcarriers(bw: u16) -> Option<u16> {
+    match bw {
+        20 => Some(52),
+        40 => Some(108),
+        80 => Some(234),
+        160 => Some(468),
+        _ => None
+    }
+}
+
+impl Rate {
+    pub fn phy_rate(&self) -> PhyRate {
+        PhyRate {
+            phy: "legacy",
+            mcs: None,
+            nss: 1,
+            bandwidth: None,
+            guard_ns: None,
+            mbps: self.in_500kbps as f32 / 2.0
+        }
+    }
+}
+
+impl Mcs {
+    pub fn phy_rate(&self) -> Option<PhyRate> {
+        if self.known & 0x02 == 0 || self.mcs > 31 {
+            return None;
+        }
+        //without the bandwidth and guard interval, assume the least
+        let bw = if self.known & 0x01 != 0 && self.flags & 0x03 == 1 { 40 } else { 20 };
+        let short_gi = self.known & 0x04 != 0 && self.flags & 0x04 != 0;
+        let nss = self.mcs / 8 + 1;
+        let idx = self.mcs % 8;
+        ofdm_mbps(idx, nss, vht_subcarriers(bw).unwrap(), if short_gi { 3.6 } else { 4.0 })
+            .map(|mbps| PhyRate {
+                phy: "ht",
+                mcs: Some(idx),
+                nss: nss,
+                bandwidth: Some(bw),
+                guard_ns: Some(if short_gi { 400 } else { 800 }),
+                mbps: mbps
+            })
+    }
+}
+
+impl Vht {
+    pub fn phy_rate(&self) -> Option<PhyRate> {
+        //only look at the first user; the others in an MU frame aren't us
+        let mcs = self.mcs_nss[0] >> 4;
+        let nss = self.mcs_nss[0] & 0x0f;
+        if nss == 0 {
+            return None;
+        }
+        let bw = if self.known & 0x0040 == 0 {
+            20
         } else {
-            None
+            match self.bandwidth & 0x1f {
+                0 => 20,
+                1...3 => 40,
+                4...10 => 80,
+                11...25 => 160,
+                _ => return None
+            }
+        };
+        let short_gi = self.known & 0x0004 != 0 && self.flags & 0x04 != 0;
+        ofdm_mbps(mcs, nss, vht_subcarriers(bw).unwrap(), if short_gi { 3.6 } else { 4.0 })
+            .map(|mbps| PhyRate {
+                phy: "vht",
+                mcs: Some(mcs),
+                nss: nss,
+                bandwidth: Some(bw),
+                guard_ns: Some(if short_gi { 400 } else { 800 }),
+                mbps: mbps
+            })
+    }
+}
+
+impl He {
+    pub fn phy_rate(&self) -> Option<PhyRate> {
+        let d = &self.data;
+        if d[0] & 0x0020 == 0 {
+            return None; //data MCS not known
         }
+        let mcs = ((d[2] >> 8) & 0x0f) as u8;
+        let nss = match (d[5] & 0x0f) as u8 { 0 => 1, n => n };
+        //bandwidth, or the resource unit for OFDMA, and its data subcarriers
+        let (bw, tones) = if d[0] & 0x4000 == 0 {
+            (Some(20), 234)
+        } else {
+            match d[4] & 0x0f {
+                0 => (Some(20), 234),
+                1 => (Some(40), 468),
+                2 => (Some(80), 980),
+                3 => (Some(160), 1960),
+                4 => (None, 24),
+                5 => (None, 48),
+                6 => (None, 102),
+                7 => (None, 234),
+                8 => (None, 468),
+                9 => (None, 980),
+                10 => (None, 1960),
+                _ => return None
+            }
+        };
+        let gi = if d[1] & 0x0002 == 0 {
+            800
+        } else {
+            match (d[4] >> 4) & 0x03 {
+                0 => 800,
+                1 => 1600,
+                2 => 3200,
+                _ => return None
+            }
+        };
+        ofdm_mbps(mcs, nss, tones, 12.8 + gi as f32 / 1000.0)
+            .map(|mbps| PhyRate {
+                phy: "he",
+                mcs: Some(mcs),
+                nss: nss,
+                bandwidth: bw,
+                guard_ns: Some(gi),
+                mbps: mbps
+            })
     }
 }
 
+/// One field out of a radiotap header.  Fields we have no use for yet come
+/// back as Other with their index in the radiotap namespace.
+#[derive(Debug)]
+pub enum Field<'a> {
+    Tsft(Tsft),
+    Flags(Flags),
+    Rate(Rate),
+    Channel(Channel),
+    AntennaSignal(AntennaSignal),
+    AntennaNoise(AntennaNoise),
+    Antenna(Antenna),
+    Mcs(Mcs),
+    Vht(Vht),
+    He(He),
+    Vendor { oui: [u8; 3], sub_ns: u8, data: &'a [u8] },
+    Other(u32, &'a [u8]),
+}
 
+// (alignment, size) of each field in the radiotap namespace, from
+// http://www.radiotap.org/fields/defined
+fn field_layout(idx: u32) -> Option<(usize, usize)> {
+    Some(match idx {
+        0 => (8, 8),   //TSFT
+        1 => (1, 1),   //Flags
+        2 => (1, 1),   //Rate
+        3 => (2, 4),   //Channel
+        4 => (1, 2),   //FHSS
+        5 => (1, 1),   //dBm antenna signal
+        6 => (1, 1),   //dBm antenna noise
+        7 => (2, 2),   //Lock quality
+        8 => (2, 2),   //TX attenuation
+        9 => (2, 2),   //dB TX attenuation
+        10 => (1, 1),  //dBm TX power
+        11 => (1, 1),  //Antenna
+        12 => (1, 1),  //dB antenna signal
+        13 => (1, 1),  //dB antenna noise
+        14 => (2, 2),  //RX flags
+        15 => (2, 2),  //TX flags
+        16 => (1, 1),  //RTS retries
+        17 => (1, 1),  //Data retries
+        18 => (4, 8),  //XChannel
+        19 => (1, 3),  //MCS
+        20 => (4, 8),  //A-MPDU status
+        21 => (2, 12), //VHT
+        22 => (8, 12), //Timestamp
+        23 => (2, 12), //HE
+        24 => (2, 12), //HE-MU
+        25 => (2, 6),  //HE-MU-other-user
+        26 => (1, 1),  //0-length-PSDU
+        27 => (2, 4),  //L-SIG
+        _ => return None
+    })
+}
+
+impl<'a> Field<'a> {
+    fn parse(idx: u32, f: &'a [u8]) -> Field<'a> {
+        match idx {
+            0 => Field::Tsft(Tsft { timer_micros: le_u64(f) }),
+            1 => Field::Flags(Flags::from_bits_truncate(f[0])),
+            2 => Field::Rate(Rate { in_500kbps: f[0] }),
+            3 => Field::Channel(Channel {
+                mhz: le_u16(f),
+                flags: ChannelFlags::from_bits_truncate(le_u16(&f[2..]))
+            }),
+            5 => Field::AntennaSignal(AntennaSignal { dbm: f[0] as i8 }),
+            6 => Field::AntennaNoise(AntennaNoise { dbm: f[0] as i8 }),
+            11 => Field::Antenna(Antenna { idx: f[0] }),
+            19 => Field::Mcs(Mcs { known: f[0], flags: f[1], mcs: f[2] }),
+            21 => Field::Vht(Vht {
+                known: le_u16(f),
+                flags: f[2],
+                bandwidth: f[3],
+                mcs_nss: [f[4], f[5], f[6], f[7]],
+                coding: f[8],
+                group_id: f[9],
+                partial_aid: le_u16(&f[10..])
+            }),
+            23 => {
+                let mut data = [0u16; 6];
+                for (i, d) in data.iter_mut().enumerate() {
+                    *d = le_u16(&f[i*2..]);
+                }
+                Field::He(He { data: data })
+            }
+            _ => Field::Other(idx, f)
+        }
+    }
+}
+
+/// Walks the fields of a radiotap header in order, following the chain of
+/// presence bitmaps, namespace switches and each field's natural alignment
+/// (which is relative to the start of the header).  Iteration stops at the
+/// first field we don't know the size of, since nothing after it can be
+/// found either.
+pub struct RadiotapIter<'a> {
+    buf: &'a [u8],
+    word_off: usize,
+    word: u32,
+    bit: u32,
+    ns_word: u32,
+    ns_reset: bool,
+    vendor: bool,
+    off: usize,
+}
+
+impl<'a> RadiotapIter<'a> {
+    /// data starts at the radiotap header; it can run on into the frame.
+    pub fn new(data: &'a [u8]) -> Option<RadiotapIter<'a>> {
+        let hdr: &RadiotapHeader = match checked_cast(data) {
+            Some(h) => h,
+            None => return None
+        };
+        let len = hdr.it_len as usize;
+        if hdr.it_version != 0 || len < size_of::<RadiotapHeader>() || len > data.len() {
+            return None;
+        }
+        let buf = &data[..len];
+
+        //field data starts after the last presence bitmap
+        let mut off = 4;
+        loop {
+            if off + 4 > len {
+                return None;
+            }
+            let w = le_u32(&buf[off..]);
+            off += 4;
+            if w & MORE_IT_PRESENT.bits == 0 {
+                break;
+            }
+        }
+
+        Some(RadiotapIter {
+            buf: buf,
+            word_off: 4,
+            word: le_u32(&buf[4..]),
+            bit: 0,
+            ns_word: 0,
+            ns_reset: false,
+            vendor: false,
+            off: off
+        })
+    }
+
+    fn align(&mut self, align: usize) {
+        self.off = (self.off + align - 1) & !(align - 1);
+    }
+
+    fn stop(&mut self) -> Option<Field<'a>> {
+        self.word = 0;
+        self.bit = 32;
+        None
+    }
+}
+
+impl<'a> Iterator for RadiotapIter<'a> {
+    type Item = Field<'a>;
+
+    fn next(&mut self) -> Option<Field<'a>> {
+        loop {
+            if self.bit == 32 {
+                if self.word & MORE_IT_PRESENT.bits == 0 {
+                    return None;
+                }
+                self.word_off += 4;
+                self.word = le_u32(&self.buf[self.word_off..]);
+                self.bit = 0;
+                self.ns_word = if self.ns_reset { 0 } else { self.ns_word + 1 };
+                self.ns_reset = false;
+                continue;
+            }
 
+            let b = self.bit;
+            self.bit += 1;
+            if self.word & (1 << b) == 0 {
+                continue;
+            }
+
+            match b {
+                29 => {
+                    //back to radiotap's own fields, numbered from 0 again
+                    self.vendor = false;
+                    self.ns_reset = true;
+                }
+                30 => {
+                    //vendor namespaces tell us how much data to skip
+                    self.align(2);
+                    if self.off + 6 > self.buf.len() {
+                        return self.stop();
+                    }
+                    let h = &self.buf[self.off..self.off+6];
+                    let start = self.off + 6;
+                    let end = start + le_u16(&h[4..]) as usize;
+                    if end > self.buf.len() {
+                        return self.stop();
+                    }
+                    self.off = end;
+                    self.vendor = true;
+                    self.ns_reset = true;
+                    return Some(Field::Vendor {
+                        oui: [h[0], h[1], h[2]],
+                        sub_ns: h[3],
+                        data: &self.buf[start..end]
+                    });
+                }
+                31 => {}
+                _ if self.vendor => {}
+                _ => {
+                    let idx = self.ns_word * 32 + b;
+                    let (align, size) = match field_layout(idx) {
+                        Some(l) => l,
+                        None => return self.stop()
+                    };
+                    self.align(align);
+                    if self.off + size > self.buf.len() {
+                        return self.stop();
+                    }
+                    let f = &self.buf[self.off..self.off+size];
+                    self.off += size;
+                    return Some(Field::parse(idx, f));
+                }
+            }
+        }
+    }
+}
+
+/// The fields we actually use, pulled out of a radiotap header.  Drivers
+/// that report per-chain values do so after the combined ones, so the first
+/// of each field wins.
+#[derive(Copy, Clone, Debug)]
+pub struct RadioFields {
+    pub flags: Option<Flags>,
+    pub rate: Option<Rate>,
+    pub channel: Option<Channel>,
+    pub antenna_signal: Option<AntennaSignal>,
+    pub antenna_noise: Option<AntennaNoise>,
+    pub antenna: Option<Antenna>,
+    pub phy_rate: Option<PhyRate>,
+}
+
+impl RadioFields {
+    pub fn empty() -> RadioFields {
+        RadioFields {
+            flags: None,
+            rate: None,
+            channel: None,
+            antenna_signal: None,
+            antenna_noise: None,
+            antenna: None,
+            phy_rate: None
+        }
+    }
+
+    pub fn new(fields: RadiotapIter) -> RadioFields {
+        let mut r = RadioFields::empty();
+        for f in fields {
+            match f {
+                Field::Flags(f) if r.flags.is_none() => r.flags = Some(f),
+                Field::Rate(x) if r.rate.is_none() => r.rate = Some(x),
+                Field::Channel(c) if r.channel.is_none() => r.channel = Some(c),
+                Field::AntennaSignal(s) if r.antenna_signal.is_none() => r.antenna_signal = Some(s),
+                Field::AntennaNoise(n) if r.antenna_noise.is_none() => r.antenna_noise = Some(n),
+                Field::Antenna(a) if r.antenna.is_none() => r.antenna = Some(a),
+                Field::Mcs(m) if r.phy_rate.is_none() => r.phy_rate = m.phy_rate(),
+                Field::Vht(v) if r.phy_rate.is_none() => r.phy_rate = v.phy_rate(),
+                Field::He(h) if r.phy_rate.is_none() => r.phy_rate = h.phy_rate(),
+                _ => {}
+            }
+        }
+        if r.phy_rate.is_none() {
+            r.phy_rate = r.rate.map(|x| x.phy_rate());
+        }
+        r
+    }
+
+    pub fn has_flag(&self, flag: Flags) -> bool {
+        self.flags.map(|f| f.contains(flag)).unwrap_or(false)
+    }
+
+    pub fn mhz(&self) -> Option<u16> {
+        self.channel.map(|c| c.mhz)
+    }
+
+    pub fn signal(&self) -> Option<i8> {
+        self.antenna_signal.map(|s| s.dbm)
+    }
+}
 
 impl RadiotapHeader {
     pub fn has_field(&self, fld: ItPresent) -> bool {
@@ -0,0 +1,253 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+
+use time;
+
+use multicast::Multicast;
+
+use ether::MacAddr;
+use ap::ApInfo;
+use probe::ClientInfo;
+use assoc::{AssocState, AssocStatus};
+
+// Who's talking to whom on Wi-Fi, by role instead of by frame: access points
+// with the BSSes they run, the clients in each BSS, and the devices that only
+// ever probe.  BSS details come from beacons (ApController), probing from
+// ProbeController, and membership from associations and data frames.
+
+/// A station sending to or receiving from a BSS in a data frame.
+pub struct MemberSample {
+    pub station: MacAddr,
+    pub bssid: MacAddr,
+    /// Capture time.
+    pub time: i64,
+}
+
+/// Data frames stop; after this long without any, a station is no longer
+/// counted in a BSS unless it's still associated.
+const MEMBER_TIMEOUT_SECS: i64 = 300;
+
+/// APs running several BSSes usually derive the BSSIDs from one base address,
+/// varying the low bits and sometimes setting the locally administered bit.
+/// Clearing those gives a guess at which radio a BSSID belongs to.
+pub fn ap_addr(bssid: &MacAddr) -> MacAddr {
+    let b = bssid.as_bytes();
+    MacAddr::from_bytes(&[b[0] & !0x02, b[1], b[2], b[3], b[4], b[5] & 0xf0])
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct TopoNode {
+    pub addr: MacAddr,
+    /// "ap", "client" or "probing"
+    pub kind: &'static str,
+    /// For APs, the radio it seems to share with other BSSIDs; for clients,
+    /// the BSS they're in.
+    pub parent: Option<MacAddr>,
+    pub ssid: Option<String>,
+    pub channel: Option<u8>,
+    pub security: Option<&'static str>,
+    pub last_seen: i64,
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct TopoLink {
+    pub source: MacAddr,
+    pub target: MacAddr,
+    /// "member" for a client in a BSS, "probe" for a device asking for the
+    /// BSS's ssid by name, "radio" between BSSes of one AP.
+    pub kind: &'static str,
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct TopologyMsg {
+    typ: &'static str,
+    nodes: Vec<TopoNode>,
+    links: Vec<TopoLink>,
+}
+
+const TOPOLOGY_MSG_INTERVAL_SECS: u32 = 5;
+
+#[derive(Clone)]
+pub struct TopologyController {
+    /// Last data frame between each (station, bssid).
+    pub members: Arc<RwLock<HashMap<(MacAddr, MacAddr), i64>>>,
+    aps: Arc<RwLock<HashMap<MacAddr, ApInfo>>>,
+    clients: Arc<RwLock<HashMap<MacAddr, ClientInfo>>>,
+    assocs: Arc<RwLock<HashMap<(MacAddr, MacAddr), AssocState>>>,
+    member_tx: Sender<MemberSample>,
+    mcast: Multicast<TopologyMsg>,
+}
+
+impl TopologyController {
+    pub fn spawn(aps: Arc<RwLock<HashMap<MacAddr, ApInfo>>>,
+                 clients: Arc<RwLock<HashMap<MacAddr, ClientInfo>>>,
+                 assocs: Arc<RwLock<HashMap<(MacAddr, MacAddr), AssocState>>>)
+                 -> io::Result<TopologyController> {
+        let (member_tx, member_rx) = channel();
+        let (tick_tx, tick_rx) = channel();
+        let out = TopologyController {
+            members: Arc::new(RwLock::new(HashMap::new())),
+            aps: aps,
+            clients: clients,
+            assocs: assocs,
+            member_tx: member_tx,
+            mcast: try!(Multicast::spawn())
+        };
+
+        try!(thread::Builder::new().name("topology_timer".to_string()).spawn(move || {
+            loop {
+                thread::sleep_ms(TOPOLOGY_MSG_INTERVAL_SECS * 1000);
+                if tick_tx.send(()).is_err() {
+                    break
+                }
+            }
+        }));
+
+        let ctl = out.clone();
+        try!(thread::Builder::new().name("topology_handler".to_string()).spawn(move || {
+            //Capture time, run on at wall speed between samples, as in
+            //presence, so the timeout means the same for files.
+            let mut clock: Option<(i64, i64)> = None;
+            loop {
+                select!(
+                    m = member_rx.recv() => {
+                        if m.is_err() {
+                            break
+                        }
+                        let m: MemberSample = m.unwrap();
+                        if clock.map(|(latest, _)| m.time > latest).unwrap_or(true) {
+                            clock = Some((m.time, time::get_time().sec));
+                        }
+                        match ctl.members.write().unwrap().entry((m.station, m.bssid)) {
+                            Entry::Occupied(mut e) => {
+                                //out of order, from a sensor that's behind
+                                if m.time > *e.get() {
+                                    *e.get_mut() = m.time;
+                                }
+                            }
+                            Entry::Vacant(e) => { e.insert(m.time); }
+                        }
+                    },
+                    _ = tick_rx.recv() => {
+                        if let Some((latest, seen_at)) = clock {
+                            let now = latest + time::get_time().sec - seen_at;
+                            let mut members = ctl.members.write().unwrap();
+                            let expired: Vec<_> = members.iter()
+                                .filter(|&(_, &last)| now - last >= MEMBER_TIMEOUT_SECS)
+                                .map(|(k, _)| *k)
+                                .collect();
+                            for k in expired.iter() {
+                                members.remove(k);
+                            }
+                        }
+                        let (nodes, links) = ctl.topology();
+                        if !nodes.is_empty() {
+                            ctl.mcast.send(Arc::new(TopologyMsg {
+                                typ: "topology",
+                                nodes: nodes,
+                                links: links
+                            }));
+                        }
+                    }
+                )
+            }
+        }));
+
+        Ok(out)
+    }
+
+    /// The current graph.  APs come first, then clients, then probing-only
+    /// devices.
+    pub fn topology(&self) -> (Vec<TopoNode>, Vec<TopoLink>) {
+        let aps = self.aps.read().unwrap();
+        let clients = self.clients.read().unwrap();
+        let mut nodes = Vec::new();
+        let mut links = Vec::new();
+
+        //first BSSID seen for each radio, to tie the others to
+        let mut radios: HashMap<MacAddr, MacAddr> = HashMap::new();
+        for ap in aps.values() {
+            let radio = ap_addr(&ap.bssid);
+            match radios.entry(radio) {
+                Entry::Occupied(e) => links.push(TopoLink {
+                    source: *e.get(), target: ap.bssid, kind: "radio"
+                }),
+                Entry::Vacant(e) => { e.insert(ap.bssid); }
+            }
+            nodes.push(TopoNode {
+                addr: ap.bssid,
+                kind: "ap",
+                parent: Some(radio),
+                ssid: ap.ssid.clone(),
+                channel: ap.channel,
+                security: Some(ap.security),
+                last_seen: ap.last_seen
+            });
+        }
+
+        //a station can be in one BSS at a time; take the latest
+        let mut bss_of: HashMap<MacAddr, (MacAddr, i64)> = HashMap::new();
+        {
+            let mut join = |station: MacAddr, bssid: MacAddr, when: i64| {
+                if station == bssid || station.is_multicast() || aps.contains_key(&station) {
+                    return;
+                }
+                match bss_of.entry(station) {
+                    Entry::Occupied(mut e) => if e.get().1 < when { *e.get_mut() = (bssid, when); },
+                    Entry::Vacant(e) => { e.insert((bssid, when)); }
+                }
+            };
+            for (&(station, bssid), &last) in self.members.read().unwrap().iter() {
+                join(station, bssid, last);
+            }
+            for st in self.assocs.read().unwrap().values() {
+                if st.status == AssocStatus::Associated {
+                    join(st.station, st.bssid, st.last_update);
+                }
+            }
+        }
+        for (&station, &(bssid, last)) in bss_of.iter() {
+            let ap = aps.get(&bssid);
+            nodes.push(TopoNode {
+                addr: station,
+                kind: "client",
+                parent: Some(bssid),
+                ssid: ap.and_then(|a| a.ssid.clone()),
+                channel: ap.and_then(|a| a.channel),
+                security: None,
+                last_seen: last
+            });
+            links.push(TopoLink { source: station, target: bssid, kind: "member" });
+        }
+
+        for c in clients.values().filter(|c| !bss_of.contains_key(&c.mac)) {
+            nodes.push(TopoNode {
+                addr: c.mac,
+                kind: "probing",
+                parent: None,
+                ssid: None,
+                channel: None,
+                security: None,
+                last_seen: c.last_seen
+            });
+            for ap in aps.values() {
+                if ap.ssid.as_ref().map(|s| c.ssids.contains(s)).unwrap_or(false) {
+                    links.push(TopoLink { source: c.mac, target: ap.bssid, kind: "probe" });
+                }
+            }
+        }
+
+        (nodes, links)
+    }
+
+    pub fn sender(&self) -> Sender<MemberSample> {
+        self.member_tx.clone()
+    }
+
+    pub fn register_listener(&self, s: Sender<Arc<TopologyMsg>>) {
+        self.mcast.register(s);
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt::{Display, Error, Formatter};
+use std::mem::size_of;
+
+use rustc_serialize::{Encodable, Encoder};
+use toml;
+
+use util::{be_u16, be_u24, be_u32, checked_cast};
+use ether::{ETHERTYPE_TEB, ETHERTYPE_IP4, ETHERTYPE_IP6};
+use ip::{IPPROTO_GRE, IPPROTO_IPIP, IPPROTO_IPV6};
+use dissect::{DissectCtx, DissectKey, Dissector, DissectorRegistry, ParseErr};
+
+pub const VXLAN_PORT: u16 = 4789;
+pub const GENEVE_PORT: u16 = 6081;
+
+/// Identifies which tunnel (and which virtual network inside of it) an inner
+/// packet was carried in.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum TunnelTag {
+    Gre(Option<u32>), //key, if present
+    Vxlan(u32), //vni
+    Geneve(u32), //vni
+    IpIp
+}
+
+impl Display for TunnelTag {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match *self {
+            TunnelTag::Gre(Some(key)) => f.write_str(&format!("gre:{}", key)),
+            TunnelTag::Gre(None) => f.write_str("gre"),
+            TunnelTag::Vxlan(vni) => f.write_str(&format!("vxlan:{}", vni)),
+            TunnelTag::Geneve(vni) => f.write_str(&format!("geneve:{}", vni)),
+            TunnelTag::IpIp => f.write_str("ipip")
+        }
+    }
+}
+
+impl Encodable for TunnelTag {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+/// A decapsulated payload: what tunnel it came out of, what kind of packet
+/// it is (as an ethertype, in the same byte order as EthernetHeader.typ),
+/// and the bytes themselves.
+pub struct Decap<'a> {
+    pub tag: TunnelTag,
+    pub typ: u16,
+    pub payload: &'a [u8]
+}
+
+// RFC 2784 / RFC 2890
+#[repr(packed)]
+pub struct GreHeader {
+    pub flags_ver: u16,
+    pub proto: u16
+}
+
+bitflags!(flags GreFlags: u16 {
+    const GRE_CHECKSUM = 0x8000,
+    const GRE_KEY      = 0x2000,
+    const GRE_SEQ      = 0x1000,
+    const GRE_VERSION  = 0x0007
+});
+
+pub fn parse_gre(data: &[u8]) -> Option<Decap> {
+    let hdr: &GreHeader = match checked_cast(data) {
+        Some(h) => h,
+        None => return None
+    };
+    let flags = GreFlags::from_bits_truncate(be_u16(data));
+    if flags.intersects(GRE_VERSION) {
+        //version 1 is PPTP's enhanced GRE, which we don't care about.
+        return None;
+    }
+
+    let mut off = size_of::<GreHeader>();
+    if flags.contains(GRE_CHECKSUM) {
+        off += 4; //checksum + reserved1
+    }
+    let key = if flags.contains(GRE_KEY) {
+        if data.len() < off + 4 {
+            return None;
+        }
+        let k = be_u32(&data[off..]);
+        off += 4;
+        Some(k)
+    } else {
+        None
+    };
+    if flags.contains(GRE_SEQ) {
+        off += 4;
+    }
+    if data.len() < off {
+        return None;
+    }
+
+    Some(Decap { tag: TunnelTag::Gre(key), typ: hdr.proto, payload: &data[off..] })
+}
+
+// RFC 7348
+#[repr(packed)]
+pub struct VxlanHeader {
+    pub flags: u8,
+    pub reserved1: [u8; 3],
+    pub vni: [u8; 3],
+    pub reserved2: u8
+}
+
+const VXLAN_VALID_VNI: u8 = 0x08;
+
+pub fn parse_vxlan(data: &[u8]) -> Option<Decap> {
+    let hdr: &VxlanHeader = match checked_cast(data) {
+        Some(h) => h,
+        None => return None
+    };
+    if hdr.flags & VXLAN_VALID_VNI == 0 {
+        return None;
+    }
+    Some(Decap {
+        tag: TunnelTag::Vxlan(be_u24(&hdr.vni)),
+        typ: ETHERTYPE_TEB,
+        payload: &data[size_of::<VxlanHeader>()..]
+    })
+}
+
+// https://tools.ietf.org/html/draft-ietf-nvo3-geneve
+#[repr(packed)]
+pub struct GeneveHeader {
+    pub ver_optlen: u8,
+    pub flags: u8,
+    pub proto: u16,
+    pub vni: [u8; 3],
+    pub reserved: u8
+}
+
+pub fn parse_geneve(data: &[u8]) -> Option<Decap> {
+    let hdr: &GeneveHeader = match checked_cast(data) {
+        Some(h) => h,
+        None => return None
+    };
+    if hdr.ver_optlen >> 6 != 0 {
+        return None;
+    }
+    let off = size_of::<GeneveHeader>() + ((hdr.ver_optlen & 0x3F) as usize) * 4;
+    if data.len() < off {
+        return None;
+    }
+    Some(Decap {
+        tag: TunnelTag::Geneve(be_u24(&hdr.vni)),
+        typ: hdr.proto,
+        payload: &data[off..]
+    })
+}
+
+pub fn ip_in_ip(typ: u16, data: &[u8]) -> Decap {
+    Decap { tag: TunnelTag::IpIp, typ: typ, payload: data }
+}
+
+/// Which hosts should show up in the graphs for a given kind of tunnel.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GraphMode {
+    Outer,
+    Inner,
+    Both
+}
+
+impl GraphMode {
+    fn from_str(s: &str) -> Option<GraphMode> {
+        match s {
+            "outer" => Some(GraphMode::Outer),
+            "inner" => Some(GraphMode::Inner),
+            "both" => Some(GraphMode::Both),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TunnelConf {
+    pub gre: GraphMode,
+    pub vxlan: GraphMode,
+    pub geneve: GraphMode,
+    pub ipip: GraphMode,
+    pub vxlan_port: u16,
+    pub geneve_port: u16
+}
+
+impl TunnelConf {
+    pub fn new() -> TunnelConf {
+        TunnelConf {
+            gre: GraphMode::Both,
+            vxlan: GraphMode::Both,
+            geneve: GraphMode::Both,
+            ipip: GraphMode::Both,
+            vxlan_port: VXLAN_PORT,
+            geneve_port: GENEVE_PORT
+        }
+    }
+
+    /// Reads the [tunnels] table, e.g.:
+    ///
+    ///     [tunnels]
+    ///     vxlan = "inner"
+    ///     vxlan-port = 8472
+    ///     gre = "both"
+    ///
+    /// Anything missing or unparseable keeps its default.
+    pub fn from_toml(conf: &toml::Table) -> TunnelConf {
+        let mut out = TunnelConf::new();
+        let tbl = match conf.get("tunnels").and_then(|t| t.as_table()) {
+            Some(t) => t,
+            None => return out
+        };
+
+        let mode = |key: &str, dflt: GraphMode| {
+            tbl.get(key).and_then(|v| v.as_str()).and_then(GraphMode::from_str).unwrap_or(dflt)
+        };
+        out.gre = mode("gre", out.gre);
+        out.vxlan = mode("vxlan", out.vxlan);
+        out.geneve = mode("geneve", out.geneve);
+        out.ipip = mode("ipip", out.ipip);
+
+        let port = |key: &str, dflt: u16| match tbl.get(key).and_then(|v| v.as_integer()) {
+            Some(p) if p >= 1 && p <= 65535 => p as u16,
+            Some(p) => {
+                println!("{} {} isn't a port, using {}", key, p, dflt);
+                dflt
+            }
+            None => dflt
+        };
+        out.vxlan_port = port("vxlan-port", out.vxlan_port);
+        out.geneve_port = port("geneve-port", out.geneve_port);
+
+        out
+    }
+}
+
+/// One dissector for every kind of tunnel: they only differ in how the
+/// tunnel header is parsed.
+pub struct TunnelDissector {
+    parse: fn(&[u8]) -> Option<Decap>,
+    mode: GraphMode
+}
+
+impl Dissector for TunnelDissector {
+    fn dissect(&self, reg: &DissectorRegistry, ctx: &mut DissectCtx, data: &[u8])
+               -> Result<(), ParseErr> {
+        match (self.parse)(data) {
+            Some(d) => ctx.decap(reg, d, self.mode),
+            None => Err(ParseErr::UnknownPacket)
+        }
+    }
+}
+
+fn ip4_in_ip(data: &[u8]) -> Option<Decap> {
+    Some(ip_in_ip(ETHERTYPE_IP4, data))
+}
+
+fn ip6_in_ip(data: &[u8]) -> Option<Decap> {
+    Some(ip_in_ip(ETHERTYPE_IP6, data))
+}
+
+pub fn register_dissectors(reg: &mut DissectorRegistry, conf: &TunnelConf) {
+    reg.register(DissectKey::IpProto(IPPROTO_GRE),
+                 Box::new(TunnelDissector { parse: parse_gre, mode: conf.gre }));
+    reg.register(DissectKey::IpProto(IPPROTO_IPIP),
+                 Box::new(TunnelDissector { parse: ip4_in_ip, mode: conf.ipip }));
+    reg.register(DissectKey::IpProto(IPPROTO_IPV6),
+                 Box::new(TunnelDissector { parse: ip6_in_ip, mode: conf.ipip }));
+    reg.register(DissectKey::UdpPort(conf.vxlan_port),
+                 Box::new(TunnelDissector { parse: parse_vxlan, mode: conf.vxlan }));
+    reg.register(DissectKey::UdpPort(conf.geneve_port),
+                 Box::new(TunnelDissector { parse: parse_geneve, mode: conf.geneve }));
+}
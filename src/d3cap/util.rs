@@ -1,9 +1,34 @@
+use std::mem::size_of;
 
 //TODO: this is dumb and just assumes we're on a little-endian system.
 pub fn ntohs(n: u16) -> u16 {
     (n>>8) | (n<<8)
 }
 
+pub fn be_u16(b: &[u8]) -> u16 {
+    ((b[0] as u16) << 8) | (b[1] as u16)
+}
+
+pub fn be_u24(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | (b[2] as u32)
+}
+
+pub fn be_u32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | (b[3] as u32)
+}
+
+pub fn le_u16(b: &[u8]) -> u16 {
+    (b[0] as u16) | ((b[1] as u16) << 8)
+}
+
+pub fn le_u32(b: &[u8]) -> u32 {
+    (le_u16(b) as u32) | ((le_u16(&b[2..]) as u32) << 16)
+}
+
+pub fn le_u64(b: &[u8]) -> u64 {
+    (le_u32(b) as u64) | ((le_u32(&b[4..]) as u64) << 32)
+}
+
 pub unsafe fn skip_cast<T, U>(t: &T) -> &U {
     &*((t as *const T).offset(1) as *const U)
 }
@@ -11,3 +36,13 @@ pub unsafe fn skip_cast<T, U>(t: &T) -> &U {
 pub unsafe fn skip_bytes_cast<T,U>(t: &T, bytes: isize) -> &U {
     &*((t as *const T as *const u8).offset(bytes) as *const U)
 }
+
+/// Like skip_cast, but for slices: only hands back a header if the slice is
+/// actually long enough to hold one.
+pub fn checked_cast<T>(data: &[u8]) -> Option<&T> {
+    if data.len() >= size_of::<T>() {
+        Some(unsafe { &*(data.as_ptr() as *const T) })
+    } else {
+        None
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::{Arc, RwLock};
+
+use crypto::aessafe::{AesSafe128Decryptor, AesSafe128Encryptor};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha1::Sha1;
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
+use crypto::util::fixed_time_eq;
+
+use toml;
+
+use ether::MacAddr;
+use dot11::DataFrameHeader;
+use ie::{self, IE_VENDOR, OUI_IEEE};
+use eapol::{EapolKey, EAPOL_KEY_MIC_OFFSET, KEY_ACK, KEY_ENCRYPTED, KEY_PAIRWISE};
+
+// WPA2-Personal with CCMP, for networks we have the passphrase to.  The PMK
+// comes straight from the passphrase and ssid, each station's pairwise keys
+// from the PMK and the nonces in its 4-way handshake, and the group key
+// arrives wrapped in message 3.  Miss the handshake and there's nothing to
+// decrypt with until the station joins again.  802.11-2012 11.4.3, 11.6
+
+fn copy(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src.iter()) {
+        *d = *s;
+    }
+}
+
+fn hmac_sha1(key: &[u8], parts: &[&[u8]]) -> [u8; 20] {
+    let mut mac = Hmac::new(Sha1::new(), key);
+    for p in parts.iter() {
+        mac.input(*p);
+    }
+    let mut out = [0u8; 20];
+    mac.raw_result(&mut out);
+    out
+}
+
+/// M.4.1: PBKDF2-SHA1 over the passphrase, salted with the ssid.
+pub fn psk_to_pmk(passphrase: &str, ssid: &str) -> [u8; 32] {
+    let mut mac = Hmac::new(Sha1::new(), passphrase.as_bytes());
+    let mut pmk = [0u8; 32];
+    pbkdf2(&mut mac, ssid.as_bytes(), 4096, &mut pmk);
+    pmk
+}
+
+/// 11.6.1.2 PRF: HMAC-SHA1 over the label, a zero, the data and a counter,
+/// as many times as it takes to fill out.
+pub fn prf(key: &[u8], label: &str, data: &[u8], out: &mut [u8]) {
+    let zero = [0u8];
+    let mut ctr = [0u8];
+    let mut off = 0;
+    while off < out.len() {
+        let h = hmac_sha1(key, &[label.as_bytes(), &zero[..], data, &ctr[..]]);
+        copy(&mut out[off..], &h);
+        off += h.len();
+        ctr[0] += 1;
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Ptk {
+    pub kck: [u8; 16],
+    pub kek: [u8; 16],
+    pub tk: [u8; 16],
+}
+
+/// 11.6.1.3: PRF-384 of the PMK over both addresses and both nonces, lowest
+/// first so both ends get the same answer.
+pub fn derive_ptk(pmk: &[u8], aa: MacAddr, spa: MacAddr, anonce: &[u8], snonce: &[u8]) -> Ptk {
+    let (aa, spa) = (aa.as_bytes(), spa.as_bytes());
+    let (lo_addr, hi_addr) = if aa < spa { (aa, spa) } else { (spa, aa) };
+    let (lo_nonce, hi_nonce) = if anonce < snonce { (anonce, snonce) } else { (snonce, anonce) };
+    let mut data = Vec::with_capacity(76);
+    for part in [lo_addr, hi_addr, lo_nonce, hi_nonce].iter() {
+        data.extend(part.iter().cloned());
+    }
+
+    let mut out = [0u8; 48];
+    prf(pmk, "Pairwise key expansion", &data, &mut out);
+    let mut ptk = Ptk { kck: [0; 16], kek: [0; 16], tk: [0; 16] };
+    copy(&mut ptk.kck, &out[0..16]);
+    copy(&mut ptk.kek, &out[16..32]);
+    copy(&mut ptk.tk, &out[32..48]);
+    ptk
+}
+
+/// Key descriptor version 2: HMAC-SHA1-128 over the whole EAPOL frame with
+/// the MIC field zeroed.
+pub fn verify_mic(kck: &[u8], key: &EapolKey) -> bool {
+    let mut raw = key.raw.to_vec();
+    for b in raw[EAPOL_KEY_MIC_OFFSET..EAPOL_KEY_MIC_OFFSET+16].iter_mut() {
+        *b = 0;
+    }
+    let h = hmac_sha1(kck, &[&raw[..]]);
+    fixed_time_eq(&h[..16], key.mic)
+}
+
+/// RFC 3394 AES key unwrap, which is how descriptor version 2 protects the
+/// key data.
+pub fn aes_unwrap(kek: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 24 || data.len() % 8 != 0 {
+        return None;
+    }
+    let n = data.len() / 8 - 1;
+    let aes = AesSafe128Decryptor::new(kek);
+    let mut a = [0u8; 8];
+    copy(&mut a, &data[..8]);
+    let mut r = data[8..].to_vec();
+    let mut b = [0u8; 16];
+    let mut out = [0u8; 16];
+    for j in (0..6).rev() {
+        for i in (0..n).rev() {
+            let t = (n * j + i + 1) as u64;
+            copy(&mut b[..8], &a);
+            for k in 0..8 {
+                b[k] ^= (t >> (8 * (7 - k))) as u8;
+            }
+            copy(&mut b[8..], &r[i*8..i*8+8]);
+            aes.decrypt_block(&b, &mut out);
+            copy(&mut a, &out[..8]);
+            copy(&mut r[i*8..i*8+8], &out[8..]);
+        }
+    }
+    if a == [0xA6; 8] { Some(r) } else { None }
+}
+
+const KDE_GTK: u8 = 1;
+
+/// Pull the GTK KDE out of unwrapped key data: key id, a reserved byte,
+/// then the key.
+fn find_gtk(key_data: &[u8]) -> Option<(u8, [u8; 16])> {
+    for (id, val) in ie::iter(key_data) {
+        if id == IE_VENDOR && val.len() >= 6 + 16 && &val[..3] == OUI_IEEE && val[3] == KDE_GTK {
+            let mut gtk = [0u8; 16];
+            copy(&mut gtk, &val[6..22]);
+            return Some((val[4] & 0x03, gtk));
+        }
+    }
+    None
+}
+
+pub const CCMP_HDR_LEN: usize = 8;
+pub const CCMP_MIC_LEN: usize = 8;
+const CCMP_EXT_IV: u8 = 0x20;
+
+fn cbc_mac(aes: &AesSafe128Encryptor, x: &mut [u8; 16], data: &[u8]) {
+    for chunk in data.chunks(16) {
+        for (xb, db) in x.iter_mut().zip(chunk.iter()) {
+            *xb ^= *db;
+        }
+        let last = *x;
+        aes.encrypt_block(&last, x);
+    }
+}
+
+/// CCM with an 8-byte MIC and 2-byte length, as CCMP uses it (RFC 3610).
+fn ccm_decrypt(key: &[u8], nonce: &[u8], aad: &[u8], data: &[u8], mic: &[u8]) -> Option<Vec<u8>> {
+    let aes = AesSafe128Encryptor::new(key);
+    let mut ctr = [0u8; 16];
+    ctr[0] = 0x01;
+    copy(&mut ctr[1..14], nonce);
+    let mut ks = [0u8; 16];
+
+    let mut plain = data.to_vec();
+    for (i, chunk) in plain.chunks_mut(16).enumerate() {
+        ctr[14] = ((i + 1) >> 8) as u8;
+        ctr[15] = (i + 1) as u8;
+        aes.encrypt_block(&ctr, &mut ks);
+        for (p, k) in chunk.iter_mut().zip(ks.iter()) {
+            *p ^= *k;
+        }
+    }
+
+    let mut b0 = [0u8; 16];
+    b0[0] = 0x59; //Adata, M = 8, L = 2
+    copy(&mut b0[1..14], nonce);
+    b0[14] = (plain.len() >> 8) as u8;
+    b0[15] = plain.len() as u8;
+    let mut x = [0u8; 16];
+    aes.encrypt_block(&b0, &mut x);
+    let mut a = vec![(aad.len() >> 8) as u8, aad.len() as u8];
+    a.extend(aad.iter().cloned());
+    cbc_mac(&aes, &mut x, &a);
+    cbc_mac(&aes, &mut x, &plain);
+
+    ctr[14] = 0;
+    ctr[15] = 0;
+    aes.encrypt_block(&ctr, &mut ks);
+    for (t, s) in x.iter_mut().zip(ks.iter()) {
+        *t ^= *s;
+    }
+    if fixed_time_eq(&x[..CCMP_MIC_LEN], mic) { Some(plain) } else { None }
+}
+
+/// 11.4.3.3: the AAD is the MAC header with whatever can change on a
+/// retransmission masked off, and the nonce is the priority, A2 and packet
+/// number.  Returns the plaintext body, or None if the MIC doesn't check out.
+pub fn ccmp_decrypt(tk: &[u8], hdr: &DataFrameHeader, frame: &[u8]) -> Option<Vec<u8>> {
+    let off = hdr.body_offset();
+    if frame.len() < off + CCMP_HDR_LEN + CCMP_MIC_LEN {
+        return None;
+    }
+    let ccmp = &frame[off..off+CCMP_HDR_LEN];
+    if ccmp[3] & CCMP_EXT_IV == 0 {
+        return None;
+    }
+    let qos = hdr.qos_ctl(frame);
+
+    let mut aad = Vec::with_capacity(30);
+    aad.push(frame[0] & 0x8F); //subtype bits 4-6
+    aad.push(frame[1] & if qos.is_some() { 0x47 } else { 0xC7 }); //retry, pwr mgt, more data, order
+    aad.extend(frame[4..22].iter().cloned());
+    aad.push(frame[22] & 0x0F); //just the fragment number
+    aad.push(0);
+    if hdr.addr4(frame).is_some() {
+        aad.extend(frame[24..30].iter().cloned());
+    }
+    if let Some(q) = qos {
+        aad.push(q.tid());
+        aad.push(0);
+    }
+
+    let mut nonce = [0u8; 13];
+    nonce[0] = qos.map(|q| q.tid()).unwrap_or(0);
+    copy(&mut nonce[1..7], hdr.addr2.as_bytes());
+    copy(&mut nonce[7..13], &[ccmp[7], ccmp[6], ccmp[5], ccmp[4], ccmp[1], ccmp[0]]);
+
+    let mic_off = frame.len() - CCMP_MIC_LEN;
+    ccm_decrypt(tk, &nonce, &aad, &frame[off+CCMP_HDR_LEN..mic_off], &frame[mic_off..])
+}
+
+fn find_ptk(pmks: &[(String, [u8; 32])], aa: MacAddr, spa: MacAddr,
+            anonce: &[u8], snonce: &[u8], key: &EapolKey) -> Option<Ptk> {
+    for &(_, ref pmk) in pmks.iter() {
+        let ptk = derive_ptk(pmk, aa, spa, anonce, snonce);
+        if verify_mic(&ptk.kck, key) {
+            return Some(ptk);
+        }
+    }
+    None
+}
+
+struct Handshake {
+    anonce: Option<Vec<u8>>,
+    snonce: Option<Vec<u8>>,
+    ptk: Option<Ptk>,
+}
+
+/// Everything needed to decrypt: the PMKs for the configured networks, the
+/// PTK for each (authenticator, supplicant) pair whose handshake we saw, and
+/// the group keys handed out by each authenticator.
+pub struct Keyring {
+    pmks: Vec<(String, [u8; 32])>,
+    handshakes: HashMap<(MacAddr, MacAddr), Handshake>,
+    gtks: HashMap<(MacAddr, u8), [u8; 16]>,
+}
+
+pub type KeyringRef = Arc<RwLock<Keyring>>;
+
+impl Keyring {
+    pub fn new() -> Keyring {
+        Keyring {
+            pmks: Vec::new(),
+            handshakes: HashMap::new(),
+            gtks: HashMap::new()
+        }
+    }
+
+    /// Reads the [wpa-psk] table of ssids to passphrases, e.g.:
+    ///
+    ///     [wpa-psk]
+    ///     "lab-net" = "correct horse battery staple"
+    pub fn from_toml(conf: &toml::Table) -> Keyring {
+        let mut out = Keyring::new();
+        if let Some(tbl) = conf.get("wpa-psk").and_then(|t| t.as_table()) {
+            for (ssid, pass) in tbl.iter() {
+                match pass.as_str() {
+                    Some(p) if p.len() >= 8 && p.len() <= 63 => out.add_network(ssid, p),
+                    _ => println!("Ignoring wpa-psk for {}: passphrase must be 8 to 63 characters", ssid)
+                }
+            }
+        }
+        out
+    }
+
+    pub fn add_network(&mut self, ssid: &str, passphrase: &str) {
+        self.pmks.push((ssid.to_string(), psk_to_pmk(passphrase, ssid)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pmks.is_empty()
+    }
+
+    /// Follow a 4-way or group key handshake between authenticator aa and
+    /// supplicant spa.  The PMK is whichever one makes the MIC check out.
+    pub fn handle_key(&mut self, aa: MacAddr, spa: MacAddr, key: &EapolKey) {
+        if self.pmks.is_empty() || key.descriptor_version() != 2 {
+            return;
+        }
+        let hs = match self.handshakes.entry((aa, spa)) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(Handshake { anonce: None, snonce: None, ptk: None })
+        };
+
+        //M4's nonce is all zeros, and a group key handshake's isn't either of
+        //these, so only keep real ones from the pairwise messages
+        let from_aa = key.info.contains(KEY_ACK);
+        if key.info.contains(KEY_PAIRWISE) && key.nonce.iter().any(|&b| b != 0) {
+            if from_aa {
+                hs.anonce = Some(key.nonce.to_vec());
+            } else {
+                hs.snonce = Some(key.nonce.to_vec());
+            }
+        }
+
+        //M2 and M3 both carry a MIC, so either will do to find the PMK.  A
+        //PTK that doesn't verify is left over from an earlier handshake.
+        let msg = key.handshake_msg();
+        let stale = hs.ptk.map(|p| !verify_mic(&p.kck, key)).unwrap_or(true);
+        if (msg == Some(2) || msg == Some(3)) && stale {
+            if let (Some(an), Some(sn)) = (hs.anonce.as_ref(), hs.snonce.as_ref()) {
+                if let Some(p) = find_ptk(&self.pmks, aa, spa, an, sn, key) {
+                    hs.ptk = Some(p);
+                }
+            }
+        }
+
+        //M3, or message 1 of a group key handshake
+        let ptk = match hs.ptk {
+            Some(p) => p,
+            None => return
+        };
+        if from_aa && key.info.contains(KEY_ENCRYPTED) && verify_mic(&ptk.kck, key) {
+            if let Some((id, gtk)) = aes_unwrap(&ptk.kek, key.key_data).and_then(|d| find_gtk(&d)) {
+                self.gtks.insert((aa, id), gtk);
+            }
+        }
+    }
+
+    fn ptk(&self, a: MacAddr, b: MacAddr) -> Option<&Ptk> {
+        self.handshakes.get(&(a, b))
+            .or_else(|| self.handshakes.get(&(b, a)))
+            .and_then(|hs| hs.ptk.as_ref())
+    }
+
+    /// Decrypt a protected data frame's body, if we have the key for it.
+    pub fn decrypt(&self, hdr: &DataFrameHeader, frame: &[u8]) -> Option<Vec<u8>> {
+        let off = hdr.body_offset();
+        if frame.len() < off + CCMP_HDR_LEN {
+            return None;
+        }
+        let key_id = frame[off + 3] >> 6;
+        let tk = if hdr.addr1.is_multicast() {
+            let bssid = match hdr.addrs(frame).and_then(|a| a.bssid) {
+                Some(b) => b,
+                None => return None
+            };
+            self.gtks.get(&(bssid, key_id))
+        } else {
+            self.ptk(hdr.addr1, hdr.addr2).map(|p| &p.tk)
+        };
+        tk.and_then(|tk| ccmp_decrypt(tk, hdr, frame))
+    }
+}
+
+#[cfg(test)]
+fn hex(s: &str) -> Vec<u8> {
+    use rustc_serialize::hex::FromHex;
+    s.replace(" ", "").from_hex().unwrap()
+}
+
+// M.4.2
+#[test]
+fn test_psk_to_pmk() {
+    assert_eq!(psk_to_pmk("password", "IEEE").to_vec(),
+               hex("f42c6fc52df0ebef9ebb4b90b38a5f902e83fe1b135a70e23aed762e9710a12e"));
+    assert_eq!(psk_to_pmk("ThisIsAPassword", "ThisIsASSID").to_vec(),
+               hex("0dc0d6eb90555ed6419756b9a15ec3e3209b63df707dd508d14581f8982721af"));
+}
+
+// M.3
+#[test]
+fn test_prf() {
+    let mut out = [0u8; 64];
+    prf(&[0x0b; 20], "prefix", b"Hi There", &mut out);
+    assert_eq!(out.to_vec(),
+               hex("bcd4c650b30b9684951829e0d75f9d54b862175ed9f00606e17d8da35402ffee\
+                    75df78c3d31e0f889f012120c0862beb67753e7439ae242edb8373698356cf5a"));
+}
+
+// RFC 3394 4.1
+#[test]
+fn test_aes_unwrap() {
+    let kek = hex("000102030405060708090a0b0c0d0e0f");
+    let wrapped = hex("1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5");
+    assert_eq!(aes_unwrap(&kek, &wrapped), Some(hex("00112233445566778899aabbccddeeff")));
+
+    let mut bad = wrapped.clone();
+    bad[0] ^= 1;
+    assert_eq!(aes_unwrap(&kek, &bad), None);
+}
+
+// M.6.4
+#[test]
+fn test_ccmp_decrypt() {
+    use util::checked_cast;
+
+    let tk = hex("c97c1f67ce371185514a8a19f2bdd52f");
+    let mut frame = hex("08 48 c3 2c 0f d2 e1 28 a5 7c 50 30 f1 84 44 08 ab ae a5 b8 fc ba 80 33");
+    frame.extend(hex("0c e7 00 20 76 97 03 b5").into_iter());
+    frame.extend(hex("f3 d0 a2 fe 9a 3d bf 23 42 a6 43 e4 32 46 e8 0c 3c 04 d0 19").into_iter());
+    frame.extend(hex("78 45 ce 0b 16 f9 76 23").into_iter());
+    let plain = hex("f8 ba 1a 55 d0 2f 85 ae 96 7b b6 2f b6 cd a8 eb 7e 78 a0 50");
+
+    let hdr: &DataFrameHeader = checked_cast(&frame).unwrap();
+    assert_eq!(ccmp_decrypt(&tk, hdr, &frame), Some(plain));
+
+    let last = frame.len() - 1;
+    frame[last] ^= 1;
+    let hdr: &DataFrameHeader = checked_cast(&frame).unwrap();
+    assert_eq!(ccmp_decrypt(&tk, hdr, &frame), None);
+}
@@ -1,5 +1,6 @@
 use libc::{c_char,c_int};
 use std::ptr;
+use std::slice;
 use std::ffi::CString;
 
 mod pcap {
@@ -14,7 +15,13 @@ mod pcap {
 pub type DataLinkType = c_int;
 pub const DLT_NULL: DataLinkType = 0;
 pub const DLT_ETHERNET: DataLinkType = 1;
+pub const DLT_IEEE802_11: DataLinkType = 105;
+pub const DLT_PRISM_HEADER: DataLinkType = 119;
 pub const DLT_IEEE802_11_RADIO: DataLinkType = 127;
+pub const DLT_IEEE802_11_RADIO_AVS: DataLinkType = 163;
+pub const DLT_PPI: DataLinkType = 192;
+pub const DLT_BLUETOOTH_LE_LL: DataLinkType = 251;
+pub const DLT_BLUETOOTH_LE_LL_WITH_PHDR: DataLinkType = 256;
 
 #[derive(Copy)]
 pub struct PcapSessionBuilder {
@@ -167,6 +174,12 @@ impl PcapData {
     pub fn pkt_ptr(&self) -> *const u8 {
         self.dat
     }
+
+    /// The captured bytes of the packet, which may be fewer than len() if
+    /// the capture was truncated to a snaplen.
+    pub fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.dat, self.caplen() as usize) }
+    }
 }
 
 pub struct PcapDumper {